@@ -6,62 +6,57 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-#![feature(test)]
-
-extern crate futures;
-extern crate futures_cpupool;
-extern crate http;
-extern crate http_serve;
-extern crate hyper;
-#[macro_use]
-extern crate lazy_static;
-extern crate mime;
-extern crate reqwest;
-extern crate tempdir;
-extern crate test;
-extern crate tokio;
-
-use futures::Future;
-use futures_cpupool::{CpuFuture, CpuPool};
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
 use http::{Request, Response};
-use hyper::Body;
+use http_serve::Body;
+use hyper::body::Incoming;
+use hyper_util::rt::TokioIo;
+use lazy_static::lazy_static;
 use std::ffi::OsString;
 use std::fs::File;
 use std::io::{Read, Write};
 use std::sync::Mutex;
 use tempdir::TempDir;
 
-fn serve(req: Request<Body>) -> CpuFuture<Response<Body>, ::std::io::Error> {
-    POOL.spawn_fn(move || {
+async fn serve(req: Request<Incoming>) -> Result<Response<Body>, ::std::io::Error> {
+    tokio::task::spawn_blocking(move || {
         let f = File::open(&*PATH.lock().unwrap())?;
         let headers = http::header::HeaderMap::new();
-        let f = http_serve::ChunkedReadFile::new(f, Some(POOL.clone()), headers)?;
+        let f = http_serve::ChunkedReadFile::new(f, headers)?;
         Ok(http_serve::serve(f, &req))
     })
+    .await
+    .unwrap()
 }
 
 /// Returns the hostport of a newly created, never-destructed server.
 fn new_server() -> String {
     let (tx, rx) = ::std::sync::mpsc::channel();
     ::std::thread::spawn(move || {
-        let addr = "127.0.0.1:0".parse().unwrap();
-        let server = hyper::server::Server::bind(&addr).serve(|| hyper::service::service_fn(serve));
-        let addr = server.local_addr();
-        tx.send(addr).unwrap();
-        tokio::run(server.map_err(|e| panic!(e)))
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            tx.send(listener.local_addr().unwrap()).unwrap();
+            loop {
+                let (tcp, _) = listener.accept().await.unwrap();
+                tokio::spawn(
+                    hyper::server::conn::http1::Builder::new()
+                        .serve_connection(TokioIo::new(tcp), hyper::service::service_fn(serve)),
+                );
+            }
+        })
     });
     let addr = rx.recv().unwrap();
     format!("http://{}:{}", addr.ip(), addr.port())
 }
 
 lazy_static! {
-    static ref PATH: Mutex<OsString> = { Mutex::new(OsString::new()) };
-    static ref POOL: CpuPool = { CpuPool::new(1) };
-    static ref SERVER: String = { new_server() };
+    static ref PATH: Mutex<OsString> = Mutex::new(OsString::new());
+    static ref SERVER: String = new_server();
 }
 
-/// Sets up the server to serve a 1 MiB file, until the returned `TempDir` goes out of scope and the
-/// file is deleted.
+/// Sets up the server to serve a `kib` KiB file, until the returned `TempDir` goes out of scope
+/// and the file is deleted.
 fn setup(kib: usize) -> TempDir {
     let tmpdir = tempdir::TempDir::new("http-file-bench").unwrap();
     let tmppath = tmpdir.path().join("f");
@@ -77,50 +72,55 @@ fn setup(kib: usize) -> TempDir {
     tmpdir
 }
 
-fn serve_full_entity(b: &mut test::Bencher, kib: usize) {
+fn serve_full_entity(c: &mut Criterion, name: &str, kib: usize) {
     let _tmpdir = setup(kib);
-    let client = reqwest::Client::new();
-    let mut buf = Vec::with_capacity(kib);
+    let client = reqwest::blocking::Client::new();
+    let mut buf = Vec::with_capacity(1024 * kib);
     let mut run = || {
         let mut resp = client.get(&*SERVER).send().unwrap();
         buf.clear();
         let size = resp.read_to_end(&mut buf).unwrap();
-        assert_eq!(reqwest::StatusCode::Ok, resp.status());
+        assert_eq!(reqwest::StatusCode::OK, resp.status());
         assert_eq!(1024 * kib, size);
     };
     run(); // warm.
-    b.iter(run);
-    b.bytes = 1024 * kib as u64;
+    let mut g = c.benchmark_group("file");
+    g.throughput(Throughput::Bytes(1024 * kib as u64));
+    g.bench_function(name, |b| b.iter(&mut run));
+    g.finish();
 }
 
-#[bench]
-fn serve_full_entity_1mib(b: &mut test::Bencher) {
-    serve_full_entity(b, 1024);
+fn serve_full_entity_1mib(c: &mut Criterion) {
+    serve_full_entity(c, "serve_full_entity_1mib", 1024);
 }
 
-#[bench]
-fn serve_full_entity_1kib(b: &mut test::Bencher) {
-    serve_full_entity(b, 1);
+fn serve_full_entity_1kib(c: &mut Criterion) {
+    serve_full_entity(c, "serve_full_entity_1kib", 1);
 }
 
-#[bench]
-fn serve_last_byte_1mib(b: &mut test::Bencher) {
+fn serve_last_byte_1mib(c: &mut Criterion) {
     let _tmpdir = setup(1024);
-    let client = reqwest::Client::new();
+    let client = reqwest::blocking::Client::new();
     let mut buf = Vec::with_capacity(1);
     let mut run = || {
         let mut resp = client
             .get(&*SERVER)
-            .header(reqwest::header::Range::Bytes(vec![
-                reqwest::header::ByteRangeSpec::Last(1),
-            ]))
+            .header(reqwest::header::RANGE, "bytes=-1")
             .send()
             .unwrap();
         buf.clear();
         let size = resp.read_to_end(&mut buf).unwrap();
-        assert_eq!(reqwest::StatusCode::PartialContent, resp.status());
+        assert_eq!(reqwest::StatusCode::PARTIAL_CONTENT, resp.status());
         assert_eq!(1, size);
     };
     run(); // warm.
-    b.iter(run);
+    c.bench_function("file/serve_last_byte_1mib", |b| b.iter(&mut run));
 }
+
+criterion_group!(
+    benches,
+    serve_full_entity_1mib,
+    serve_full_entity_1kib,
+    serve_last_byte_1mib
+);
+criterion_main!(benches);
@@ -8,7 +8,7 @@
 
 //! Test program which serves a local file on `http://127.0.0.1:1337/`.
 //!
-//! Performs file IO on a separate thread pool from the reactor so that it doesn't block on
+//! Performs file IO via `tokio::task::spawn_blocking` so that the reactor doesn't block on
 //! local disk. Supports HEAD, conditional GET, and byte range requests. Some commands to try:
 //!
 //! ```
@@ -18,51 +18,44 @@
 //! $ curl -v -H 'Range: bytes=1-10,30-40' http://127.0.0.1:1337/
 //! ```
 
-extern crate env_logger;
-extern crate futures;
-extern crate futures_cpupool;
-extern crate http;
-extern crate http_serve;
-extern crate hyper;
-extern crate leak;
-extern crate mime;
-extern crate tokio;
-
-use futures::Future;
-use futures_cpupool::{CpuFuture, CpuPool};
 use http::{Request, Response};
-use http_serve::ChunkedReadFile;
-use hyper::Body;
-use leak::Leak;
+use http_serve::{Body, ChunkedReadFile, ContentTypes};
+use hyper::body::Incoming;
+use hyper_util::rt::TokioIo;
 
 struct Context {
     path: ::std::ffi::OsString,
-    pool: CpuPool,
 }
 
 fn try_serve(
     ctx: &'static Context,
-    req: Request<Body>,
+    req: Request<Incoming>,
 ) -> Result<Response<Body>, ::std::io::Error> {
     let f = ::std::fs::File::open(&ctx.path)?;
-    let headers = http::header::HeaderMap::new();
-    let f = ChunkedReadFile::new(f, Some(ctx.pool.clone()), headers)?;
+    let mut headers = http::header::HeaderMap::new();
+    ContentTypes::new()
+        .with_sniffing(true)
+        .add_header(ctx.path.as_ref(), &f, &mut headers)?;
+    let f = ChunkedReadFile::new(f, headers)?;
     Ok(http_serve::serve(f, &req))
 }
 
-fn serve(ctx: &'static Context, req: Request<Body>) -> CpuFuture<Response<Body>, ::std::io::Error> {
-    ctx.pool.spawn_fn(move || {
-        Ok(match try_serve(ctx, req) {
-            Ok(r) => r,
-            Err(e) => http::Response::builder()
-                .status(http::StatusCode::INTERNAL_SERVER_ERROR)
-                .body(format!("I/O error: {}", e).into())
-                .unwrap(),
-        })
+async fn serve(
+    ctx: &'static Context,
+    req: Request<Incoming>,
+) -> Result<Response<Body>, ::std::io::Error> {
+    let r = tokio::task::spawn_blocking(move || try_serve(ctx, req)).await?;
+    Ok(match r {
+        Ok(r) => r,
+        Err(e) => http::Response::builder()
+            .status(http::StatusCode::INTERNAL_SERVER_ERROR)
+            .body(format!("I/O error: {}", e).into())
+            .unwrap(),
     })
 }
 
-fn main() {
+#[tokio::main]
+async fn main() {
     let mut args = ::std::env::args_os();
     if args.len() != 2 {
         use std::io::Write;
@@ -71,19 +64,24 @@ fn main() {
     }
     let path = args.nth(1).unwrap();
 
-    let ctx = Box::new(Context {
-        path: path,
-        pool: CpuPool::new(1),
-    }).leak();
+    let ctx: &'static Context = Box::leak(Box::new(Context { path }));
 
     env_logger::init();
-    let addr = "127.0.0.1:1337".parse().unwrap();
-    let server = hyper::server::Server::bind(&addr)
-        .serve(move || hyper::service::service_fn(move |req| serve(ctx, req)));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:1337").await.unwrap();
     println!(
-        "Serving {} on http://{} with 1 thread.",
+        "Serving {} on http://{}",
         ctx.path.to_string_lossy(),
-        server.local_addr()
+        listener.local_addr().unwrap()
     );
-    tokio::run(server.map_err(|e| eprintln!("server error: {}", e)))
+    loop {
+        let (tcp, _) = listener.accept().await.unwrap();
+        let svc = hyper::service::service_fn(move |req| serve(ctx, req));
+        tokio::spawn(async move {
+            let conn = hyper::server::conn::http1::Builder::new()
+                .serve_connection(TokioIo::new(tcp), svc);
+            if let Err(e) = conn.await {
+                eprintln!("connection error: {}", e);
+            }
+        });
+    }
 }
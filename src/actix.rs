@@ -0,0 +1,119 @@
+// Copyright (c) 2018 Scott Lamb <slamb@slamb.org>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! actix-web integration, available with the `actix` feature.
+//!
+//! actix-web uses its own copy of the `http` types, so methods, headers, and statuses are
+//! converted by name and value.
+
+use crate::{serve, Body, BoxError, Entity};
+use actix_web::body::{BodyStream, BoxBody, SizedStream};
+use actix_web::{HttpRequest, HttpResponse, Responder};
+use futures_util::TryStreamExt;
+use http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use http::{Method, Request};
+use std::io;
+
+/// An `Entity` which can be returned from an actix-web handler.
+///
+/// The response is produced by `serve`. Its `Content-Length` is passed to actix-web as the
+/// body's size, so it is sent correctly for `HEAD` requests as well as `GET`.
+///
+/// ```
+/// # use http_serve::{ActixEntity, BoxError, InMemoryEntity};
+/// type E = InMemoryEntity<&'static [u8], bytes::Bytes, BoxError>;
+///
+/// async fn handler() -> ActixEntity<E> {
+///     ActixEntity(InMemoryEntity::new(&b"hello"[..]))
+/// }
+/// ```
+pub struct ActixEntity<E: Entity>(pub E);
+
+impl<E> Responder for ActixEntity<E>
+where
+    E: Entity,
+    E::Error: Into<BoxError>,
+{
+    type Body = BoxBody;
+
+    fn respond_to(self, req: &HttpRequest) -> HttpResponse<BoxBody> {
+        let mut r = Request::new(());
+        *r.method_mut() =
+            Method::from_bytes(req.method().as_str().as_bytes()).expect("valid method");
+        let hdrs: &mut HeaderMap = r.headers_mut();
+        for (k, v) in req.headers() {
+            if let (Ok(k), Ok(v)) = (
+                HeaderName::from_bytes(k.as_str().as_bytes()),
+                HeaderValue::from_bytes(v.as_bytes()),
+            ) {
+                hdrs.append(k, v);
+            }
+        }
+
+        let resp = serve::<_, Body<E::Data, E::Error>, _>(self.0, &r);
+        let (parts, body) = resp.into_parts();
+        let status = actix_web::http::StatusCode::from_u16(parts.status.as_u16())
+            .expect("valid status");
+        let mut builder = HttpResponse::build(status);
+        let mut len = None;
+        for (k, v) in &parts.headers {
+            if k == header::CONTENT_LENGTH {
+                // actix-web writes this itself, based on the body's size.
+                len = v.to_str().ok().and_then(|v| v.parse::<u64>().ok());
+                continue;
+            }
+            let v = actix_web::http::header::HeaderValue::from_bytes(v.as_bytes())
+                .expect("valid header value");
+            builder.append_header((k.as_str(), v));
+        }
+        // actix-web requires errors which convert to `Box<dyn Error>`, which `BoxError` doesn't.
+        let stream = crate::body::bytes_stream(body.into_stream()).map_err(io::Error::other);
+        match len {
+            Some(len) => builder.body(SizedStream::new(len, stream)),
+            None => builder.body(BodyStream::new(stream)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ActixEntity;
+    use crate::{BoxError, InMemoryEntity};
+    use actix_web::body::{to_bytes, BodySize, MessageBody};
+    use actix_web::http::{header, Method, StatusCode};
+    use actix_web::test::TestRequest;
+    use actix_web::Responder;
+    use bytes::Bytes;
+    use futures::executor::block_on;
+
+    type E = InMemoryEntity<&'static [u8], Bytes, BoxError>;
+
+    #[test]
+    fn respond_to() {
+        let e = || ActixEntity(E::new(&b"hello"[..]));
+        let req = TestRequest::default()
+            .insert_header((header::RANGE, "bytes=1-3"))
+            .to_http_request();
+        let resp = e().respond_to(&req);
+        assert_eq!(StatusCode::PARTIAL_CONTENT, resp.status());
+        assert_eq!(resp.headers().get(header::CONTENT_RANGE).unwrap(), "bytes 1-3/5");
+        assert_eq!(BodySize::Sized(3), resp.body().size());
+        let b = block_on(to_bytes(resp.into_body())).unwrap();
+        assert_eq!(&b"ell"[..], &b[..]);
+
+        // HEAD responses advertise the full length but have no body.
+        let req = TestRequest::default()
+            .method(Method::HEAD)
+            .to_http_request();
+        let resp = e().respond_to(&req);
+        assert_eq!(StatusCode::OK, resp.status());
+        assert_eq!(BodySize::Sized(5), resp.body().size());
+        let b = block_on(to_bytes(resp.into_body())).unwrap();
+        assert!(b.is_empty());
+    }
+}
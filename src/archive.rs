@@ -0,0 +1,1101 @@
+// Copyright (c) 2018 Scott Lamb <slamb@slamb.org>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Serving members of existing zip and tar archives without extracting them.
+
+use crate::coding::{negotiate_coding, Coding};
+use crate::content_etag;
+use crate::content_type::ContentTypes;
+use crate::file::ChunkedReadFile;
+use crate::serving::serve;
+use crate::Entity;
+use bytes::Buf;
+use flate2::{Decompress, FlushDecompress, Status};
+use futures_util::future;
+use futures_util::stream::{self, BoxStream, StreamExt};
+use http::header::{self, HeaderMap, HeaderValue};
+use http::{Request, Response};
+use sha2::{Digest, Sha256};
+use std::cmp;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::ops::Range;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// The size of each chunk of inflated output.
+const INFLATE_CHUNK_SIZE: usize = 65_536;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Method {
+    Stored,
+    Deflated,
+}
+
+struct Member {
+    name: String,
+    method: Method,
+
+    /// The member's data within the archive, compressed if `method` is `Deflated`.
+    data: Range<u64>,
+
+    /// The length of the uncompressed data.
+    len: u64,
+
+    /// The CRC-32 from a zip archive's central directory.
+    crc: Option<u32>,
+
+    /// The Adler-32 of the uncompressed data, computed for a `Deflated` member served as
+    /// `Content-Encoding: deflate`, either while passing through all of its data or on first use
+    /// of the trailer alone.
+    adler: Mutex<Option<u32>>,
+}
+
+/// An index of the members of a zip or tar archive, for serving them individually.
+///
+/// `open` reads the archive's central directory (for zip) or headers (for tar) once. `member`
+/// then returns an `Entity` for any regular file within it. Stored members (including all tar
+/// members) are served directly from the archive, supporting byte ranges efficiently. Deflated
+/// zip members are served as `Content-Encoding: deflate` when the client accepts that, wrapping
+/// the stored raw deflate data in the zlib framing HTTP requires; otherwise they're inflated on
+/// the fly, and a byte range requires inflating everything before it.
+///
+/// Each member's etag is derived from the archive's (as in `ChunkedReadFile`) and the member's
+/// name, CRC-32 (for zip), and representation. Its `Content-Type` is derived from its name,
+/// and its last modified time is the archive's.
+///
+/// ```no_run
+/// # use bytes::Bytes;
+/// # use http::{Request, Response, StatusCode};
+/// # use http_serve::{Archive, Body, BoxError};
+/// # fn main() -> Result<(), std::io::Error> {
+/// let docs: Archive<Bytes, BoxError> = Archive::open(std::fs::File::open("docs.zip")?)?;
+/// # let req = Request::get("/index.html").body(()).unwrap();
+/// let resp: Response<Body> = match docs.serve(&req.uri().path()[1..], &req) {
+///     Some(resp) => resp,
+///     None => Response::builder()
+///         .status(StatusCode::NOT_FOUND)
+///         .body("not found".into())
+///         .unwrap(),
+/// };
+/// # Ok(())
+/// # }
+/// ```
+pub struct Archive<D, E>
+where
+    D: 'static + Send + Buf + From<Vec<u8>> + From<&'static [u8]>,
+    E: 'static + Send + Into<Box<dyn ::std::error::Error + Send + Sync>> + From<Box<io::Error>>,
+{
+    file: ChunkedReadFile<D, E>,
+    members: HashMap<String, Arc<Member>>,
+    content_types: ContentTypes,
+}
+
+impl<D, E> Archive<D, E>
+where
+    D: 'static + Send + Buf + From<Vec<u8>> + From<&'static [u8]>,
+    E: 'static + Send + Into<Box<dyn ::std::error::Error + Send + Sync>> + From<Box<io::Error>>,
+{
+    /// Indexes the zip or tar archive in `file`, detecting the format from its contents.
+    ///
+    /// Like `ChunkedReadFile::new`, this performs blocking filesystem operations. Fails if the
+    /// archive is neither a zip nor a tar file or is corrupt. Members which can't be served
+    /// (zip members which are encrypted, use compression methods other than deflate, or have
+    /// non-UTF-8 names; tar members which aren't regular files) are skipped.
+    pub fn open(file: File) -> Result<Self, io::Error> {
+        let len = file.metadata()?.len();
+        let mut magic = [0u8; 512];
+        let magic = &mut magic[..cmp::min(512, len) as usize];
+        file.read_exact_at(magic, 0)?;
+
+        // A zip file is identified by its end of central directory record rather than its first
+        // bytes, which may be a local file header, a ZIP64 record (if there are no members), or
+        // something else entirely (as in self-extracting archives).
+        let tar = magic.len() == 512 && &magic[257..262] == b"ustar";
+        let members = if tar && !magic.starts_with(b"PK") {
+            index_tar(&file, len)?
+        } else if let Some(eocd_pos) = find_eocd(&file, len)? {
+            index_zip(&file, len, eocd_pos)?
+        } else {
+            return Err(invalid("unrecognized archive format"));
+        };
+        let mut by_name = HashMap::with_capacity(members.len());
+        for m in members {
+            // Later members replace earlier ones of the same name, as when extracting.
+            by_name.insert(m.name.clone(), Arc::new(m));
+        }
+        Ok(Archive {
+            file: ChunkedReadFile::new(file, HeaderMap::new())?,
+            members: by_name,
+            content_types: ContentTypes::new(),
+        })
+    }
+
+    /// Sets the table used to derive each member's `Content-Type` from its name.
+    pub fn with_content_types(self, content_types: ContentTypes) -> Self {
+        Archive {
+            content_types,
+            ..self
+        }
+    }
+
+    /// Returns the names of the servable members, in no particular order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.members.keys().map(|k| &k[..])
+    }
+
+    /// Returns the member at `path` (ignoring any leading `/` or `./`) as an entity suited to a
+    /// request with the given headers, or `None` if there's no such member.
+    ///
+    /// A deflated member's representation depends on the request's `Accept-Encoding`; the caller
+    /// is responsible for adding `Vary: accept-encoding` to the response. `Archive::serve` does
+    /// so automatically.
+    pub fn member(&self, path: &str, req_hdrs: &HeaderMap) -> Option<ArchiveMember<D, E>> {
+        let m = self.members.get(normalize(path))?;
+        let repr = match m.method {
+            Method::Stored => Repr::Stored,
+            Method::Deflated => {
+                match negotiate_coding(req_hdrs, &[Coding::Deflate, Coding::Identity]) {
+                    Some(Coding::Deflate) => Repr::Zlib,
+                    _ => Repr::Inflated,
+                }
+            }
+        };
+        let mut headers = HeaderMap::new();
+        if let Some(v) = self.content_types.for_path(Path::new(&m.name)) {
+            headers.insert(header::CONTENT_TYPE, v);
+        }
+        if repr == Repr::Zlib {
+            headers.insert(
+                header::CONTENT_ENCODING,
+                HeaderValue::from_static(Coding::Deflate.as_str()),
+            );
+        }
+        let etag = self.file.etag().map(|archive| {
+            let mut h = Sha256::new();
+            h.update(archive.as_bytes());
+            h.update((m.name.len() as u64).to_be_bytes());
+            h.update(m.name.as_bytes());
+            match m.crc {
+                Some(crc) => h.update(crc.to_be_bytes()),
+                None => h.update(m.data.start.to_be_bytes()),
+            }
+            h.update(m.len.to_be_bytes());
+            h.update([repr as u8]);
+            let weak = archive.as_bytes().starts_with(b"W/");
+            content_etag::format_derived_etag(&h.finalize(), weak)
+        });
+        Some(ArchiveMember {
+            file: self.file.clone(),
+            member: m.clone(),
+            repr,
+            headers,
+            etag,
+        })
+    }
+
+    /// Serves the member at `path` as in `member` and `serve`, or returns `None` if there's no
+    /// such member.
+    ///
+    /// Adds `Vary: accept-encoding` to the response if the member is deflated.
+    pub fn serve<P, PI>(&self, path: &str, req: &Request<PI>) -> Option<Response<P>>
+    where
+        P: http_body::Body + From<BoxStream<'static, Result<D, E>>>,
+    {
+        let m = self.member(path, req.headers())?;
+        let deflated = m.member.method == Method::Deflated;
+        let mut resp = serve(m, req);
+        if deflated {
+            resp.headers_mut()
+                .append(header::VARY, HeaderValue::from_static("accept-encoding"));
+        }
+        Some(resp)
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Repr {
+    /// Stored data, as is.
+    Stored,
+
+    /// Deflated data, wrapped in zlib framing for `Content-Encoding: deflate`.
+    Zlib,
+
+    /// Deflated data, inflated on the fly.
+    Inflated,
+}
+
+/// A member of an `Archive`, as returned by `Archive::member`.
+pub struct ArchiveMember<D, E>
+where
+    D: 'static + Send + Buf + From<Vec<u8>> + From<&'static [u8]>,
+    E: 'static + Send + Into<Box<dyn ::std::error::Error + Send + Sync>> + From<Box<io::Error>>,
+{
+    file: ChunkedReadFile<D, E>,
+    member: Arc<Member>,
+    repr: Repr,
+    headers: HeaderMap,
+    etag: Option<HeaderValue>,
+}
+
+impl<D, E> Entity for ArchiveMember<D, E>
+where
+    D: 'static + Send + Buf + From<Vec<u8>> + From<&'static [u8]>,
+    E: 'static + Send + Into<Box<dyn ::std::error::Error + Send + Sync>> + From<Box<io::Error>>,
+{
+    type Data = D;
+    type Error = E;
+
+    fn len(&self) -> u64 {
+        let m = &self.member;
+        match self.repr {
+            Repr::Stored | Repr::Inflated => m.len,
+            Repr::Zlib => 2 + (m.data.end - m.data.start) + 4,
+        }
+    }
+
+    fn get_range(&self, range: Range<u64>) -> BoxStream<'static, Result<D, E>> {
+        if range.start == range.end {
+            return stream::empty().boxed();
+        }
+        let m = &self.member;
+        match self.repr {
+            Repr::Stored => self
+                .file
+                .get_range(m.data.start + range.start..m.data.start + range.end),
+            Repr::Inflated => inflate(self.file.get_range(m.data.clone()), range),
+            Repr::Zlib => {
+                let clen = m.data.end - m.data.start;
+                let mut streams = Vec::with_capacity(3);
+
+                // The zlib header: deflate with a 32 KiB window, no preset dictionary, and the
+                // "fastest" level hint (the level isn't known, and the hint isn't used).
+                if range.start < 2 {
+                    let h: &'static [u8] = &[0x78, 0x01];
+                    let r = range.start as usize..cmp::min(range.end, 2) as usize;
+                    streams.push(stream::once(future::ready(Ok(h[r].into()))).boxed());
+                }
+                if range.start < 2 + clen && range.end > 2 {
+                    let start = m.data.start + range.start.saturating_sub(2);
+                    let end = m.data.start + cmp::min(range.end - 2, clen);
+                    let s = self.file.get_range(start..end);
+                    let whole = start == m.data.start && end == m.data.end;
+                    if whole && m.adler.lock().unwrap().is_none() {
+                        streams.push(with_adler(s, m.clone()));
+                    } else {
+                        streams.push(s);
+                    }
+                }
+                if range.end > 2 + clen {
+                    let r = range.start.saturating_sub(2 + clen) as usize..(range.end - 2 - clen)
+                        as usize;
+                    streams.push(adler_trailer(
+                        m.clone(),
+                        self.file.get_range(m.data.clone()),
+                        r,
+                    ));
+                }
+                stream::iter(streams).flatten().boxed()
+            }
+        }
+    }
+
+    fn add_headers(&self, h: &mut HeaderMap) {
+        h.extend(self.headers.iter().map(|(k, v)| (k.clone(), v.clone())));
+    }
+
+    fn etag(&self) -> Option<HeaderValue> {
+        self.etag.clone()
+    }
+
+    fn last_modified(&self) -> Option<SystemTime> {
+        self.file.last_modified()
+    }
+}
+
+/// Returns `range` of the big-endian Adler-32 of `m`'s uncompressed data, computing it from
+/// `compressed` if it isn't yet known.
+fn adler_trailer<D, E>(
+    m: Arc<Member>,
+    compressed: BoxStream<'static, Result<D, E>>,
+    range: Range<usize>,
+) -> BoxStream<'static, Result<D, E>>
+where
+    D: 'static + Send + Buf + From<Vec<u8>>,
+    E: 'static + Send + From<Box<io::Error>>,
+{
+    stream::once(async move {
+        let known = *m.adler.lock().unwrap();
+        let adler = match known {
+            Some(a) => a,
+            None => {
+                let mut adler = Adler32::new();
+                let mut s = inflate(compressed, 0..m.len);
+                while let Some(d) = s.next().await {
+                    let mut d = d?;
+                    while d.has_remaining() {
+                        let n = {
+                            let c = d.chunk();
+                            adler.update(c);
+                            c.len()
+                        };
+                        d.advance(n);
+                    }
+                }
+                let a = adler.sum();
+                *m.adler.lock().unwrap() = Some(a);
+                a
+            }
+        };
+        Ok(adler.to_be_bytes()[range].to_vec().into())
+    })
+    .boxed()
+}
+
+/// Passes through `m`'s full compressed data, inflating it along the way to store the Adler-32
+/// of the uncompressed data in `m` at the end.
+fn with_adler<D, E>(
+    compressed: BoxStream<'static, Result<D, E>>,
+    m: Arc<Member>,
+) -> BoxStream<'static, Result<D, E>>
+where
+    D: 'static + Send + Buf + From<Vec<u8>>,
+    E: 'static + Send,
+{
+    struct State<D, E> {
+        compressed: BoxStream<'static, Result<D, E>>,
+        m: Arc<Member>,
+        inflater: Decompress,
+        adler: Adler32,
+        out: Vec<u8>,
+
+        /// False if inflating failed; the data is still passed through, and the trailer will
+        /// report the error when it inflates the data itself.
+        ok: bool,
+    }
+    impl<D, E> State<D, E> {
+        fn update(&mut self, mut input: &[u8]) -> bool {
+            loop {
+                let (before_in, before_out) = (self.inflater.total_in(), self.inflater.total_out());
+                let status = match self
+                    .inflater
+                    .decompress(input, &mut self.out, FlushDecompress::None)
+                {
+                    Ok(s) => s,
+                    Err(_) => return false,
+                };
+                let consumed = (self.inflater.total_in() - before_in) as usize;
+                let produced = (self.inflater.total_out() - before_out) as usize;
+                self.adler.update(&self.out[..produced]);
+                input = &input[consumed..];
+                let done = status == Status::StreamEnd
+                    || (input.is_empty() && produced < self.out.len())
+                    || (consumed == 0 && produced == 0);
+                if done {
+                    return true;
+                }
+            }
+        }
+    }
+    let state = State {
+        compressed,
+        m,
+        inflater: Decompress::new(false),
+        adler: Adler32::new(),
+        out: vec![0u8; INFLATE_CHUNK_SIZE],
+        ok: true,
+    };
+    stream::unfold(Some(state), |state| async move {
+        let mut st = state?;
+        match st.compressed.next().await {
+            Some(Ok(mut d)) => {
+                if d.chunk().len() != d.remaining() {
+                    // Inflate a contiguous copy rather than consuming a discontiguous buffer.
+                    d = d.copy_to_bytes(d.remaining()).to_vec().into();
+                }
+                if st.ok {
+                    st.ok = st.update(d.chunk());
+                }
+                Some((Ok(d), Some(st)))
+            }
+            Some(Err(e)) => Some((Err(e), None)),
+            None => {
+                if st.ok && st.inflater.total_out() == st.m.len {
+                    *st.m.adler.lock().unwrap() = Some(st.adler.sum());
+                }
+                None
+            }
+        }
+    })
+    .boxed()
+}
+
+/// Inflates raw deflate data, yielding `range` of the output.
+fn inflate<D, E>(
+    compressed: BoxStream<'static, Result<D, E>>,
+    range: Range<u64>,
+) -> BoxStream<'static, Result<D, E>>
+where
+    D: 'static + Send + Buf + From<Vec<u8>>,
+    E: 'static + Send + From<Box<io::Error>>,
+{
+    struct State<D, E> {
+        compressed: BoxStream<'static, Result<D, E>>,
+        input: Option<D>,
+        inflater: Decompress,
+        range: Range<u64>,
+    }
+    let state = State {
+        compressed,
+        input: None,
+        inflater: Decompress::new(false),
+        range,
+    };
+    stream::unfold(Some(state), |state| async move {
+        let mut st = state?;
+        loop {
+            let pos = st.inflater.total_out();
+            if pos >= st.range.end {
+                return None;
+            }
+            if st.input.as_ref().map(|i| !i.has_remaining()).unwrap_or(true) {
+                st.input = match st.compressed.next().await {
+                    Some(Ok(d)) => Some(d),
+                    Some(Err(e)) => return Some((Err(e), None)),
+                    None => {
+                        let e = invalid("deflate data is shorter than expected");
+                        return Some((Err(Box::new(e).into()), None));
+                    }
+                };
+            }
+            let input = st.input.as_mut().unwrap();
+            let mut out = vec![0u8; INFLATE_CHUNK_SIZE];
+            let before_in = st.inflater.total_in();
+            let status = st
+                .inflater
+                .decompress(input.chunk(), &mut out, FlushDecompress::None);
+            let status = match status {
+                Ok(s) => s,
+                Err(e) => {
+                    let e = io::Error::new(io::ErrorKind::InvalidData, e);
+                    return Some((Err(Box::new(e).into()), None));
+                }
+            };
+            input.advance((st.inflater.total_in() - before_in) as usize);
+            let end = st.inflater.total_out();
+            let start = cmp::max(pos, st.range.start);
+            let stop = cmp::min(end, st.range.end);
+            if status == Status::StreamEnd && end < st.range.end {
+                let e = invalid("inflated data is shorter than expected");
+                return Some((Err(Box::new(e).into()), None));
+            }
+            if start < stop {
+                out.truncate((stop - pos) as usize);
+                out.drain(..(start - pos) as usize);
+                return Some((Ok(out.into()), Some(st)));
+            }
+        }
+    })
+    .boxed()
+}
+
+/// A running Adler-32 checksum, as used in the zlib trailer.
+struct Adler32 {
+    a: u32,
+    b: u32,
+}
+
+impl Adler32 {
+    fn new() -> Self {
+        Adler32 { a: 1, b: 0 }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        // 5552 is the most bytes which can be summed before `b` may overflow.
+        for chunk in data.chunks(5552) {
+            for &x in chunk {
+                self.a += u32::from(x);
+                self.b += self.a;
+            }
+            self.a %= 65_521;
+            self.b %= 65_521;
+        }
+    }
+
+    fn sum(&self) -> u32 {
+        self.b << 16 | self.a
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Strips leading `/` and `./` components from a member name.
+fn normalize(mut name: &str) -> &str {
+    loop {
+        if let Some(n) = name.strip_prefix('/') {
+            name = n;
+        } else if let Some(n) = name.strip_prefix("./") {
+            name = n;
+        } else {
+            return name;
+        }
+    }
+}
+
+fn u16_at(b: &[u8], pos: usize) -> u16 {
+    u16::from_le_bytes([b[pos], b[pos + 1]])
+}
+
+fn u32_at(b: &[u8], pos: usize) -> u32 {
+    let mut a = [0u8; 4];
+    a.copy_from_slice(&b[pos..pos + 4]);
+    u32::from_le_bytes(a)
+}
+
+fn u64_at(b: &[u8], pos: usize) -> u64 {
+    let mut a = [0u8; 8];
+    a.copy_from_slice(&b[pos..pos + 8]);
+    u64::from_le_bytes(a)
+}
+
+fn read_at(file: &File, pos: u64, len: u64, file_len: u64) -> Result<Vec<u8>, io::Error> {
+    if pos.checked_add(len).map(|end| end > file_len).unwrap_or(true) {
+        return Err(invalid("zip structure extends past end of file"));
+    }
+    let mut buf = vec![0u8; len as usize];
+    file.read_exact_at(&mut buf, pos)?;
+    Ok(buf)
+}
+
+/// Returns the position of a zip archive's end of central directory record, which is followed by
+/// a comment of up to 64 KiB, or `None` if there's no such record.
+fn find_eocd(file: &File, len: u64) -> Result<Option<u64>, io::Error> {
+    let tail_len = cmp::min(len, 22 + 65_535);
+    let tail = read_at(file, len - tail_len, tail_len, len)?;
+    let eocd = (0..tail.len().saturating_sub(21)).rev().find(|&i| {
+        tail[i..].starts_with(b"PK\x05\x06")
+            && i + 22 + usize::from(u16_at(&tail, i + 20)) <= tail.len()
+    });
+    Ok(eocd.map(|i| len - tail_len + i as u64))
+}
+
+/// Indexes a zip archive via its central directory, given the position of its end of central
+/// directory record.
+fn index_zip(file: &File, len: u64, eocd_pos: u64) -> Result<Vec<Member>, io::Error> {
+    let eocd = read_at(file, eocd_pos, 22, len)?;
+    let mut entries = u64::from(u16_at(&eocd, 10));
+    let mut cd_len = u64::from(u32_at(&eocd, 12));
+    let mut cd_pos = u64::from(u32_at(&eocd, 16));
+
+    // The central directory ends where the end record (or ZIP64 end record) begins.
+    let mut cd_end = eocd_pos;
+
+    // Use the ZIP64 end of central directory record, if there's a locator for one. It's usually
+    // immediately before the locator, which is where to look if the recorded position is off
+    // (see below).
+    if eocd_pos >= 20 {
+        let locator = read_at(file, eocd_pos - 20, 20, len)?;
+        if locator.starts_with(b"PK\x06\x07") {
+            let mut rec_pos = u64_at(&locator, 8);
+            let mut rec = read_at(file, rec_pos, 56, len).unwrap_or_default();
+            if !rec.starts_with(b"PK\x06\x06") && eocd_pos >= 20 + 56 {
+                rec_pos = eocd_pos - 20 - 56;
+                rec = read_at(file, rec_pos, 56, len)?;
+            }
+            if !rec.starts_with(b"PK\x06\x06") {
+                return Err(invalid("bad zip64 end of central directory record"));
+            }
+            entries = u64_at(&rec, 32);
+            cd_len = u64_at(&rec, 40);
+            cd_pos = u64_at(&rec, 48);
+            cd_end = rec_pos;
+        }
+    }
+
+    // Positions are relative to the start of the zip data, which may be preceded by other data,
+    // as in a self-extracting archive made by prepending a stub to an ordinary zip. As in
+    // Info-ZIP and Python's `zipfile`, find the length of this prefix from where the central
+    // directory actually ends.
+    let prefix = cd_pos
+        .checked_add(cd_len)
+        .and_then(|e| cd_end.checked_sub(e))
+        .ok_or_else(|| invalid("bad zip central directory position"))?;
+    let cd = read_at(file, cd_pos + prefix, cd_len, len)?;
+    let mut members = Vec::new();
+    let mut p = 0;
+    for _ in 0..entries {
+        if p + 46 > cd.len() || !cd[p..].starts_with(b"PK\x01\x02") {
+            return Err(invalid("bad zip central directory header"));
+        }
+        let flags = u16_at(&cd, p + 8);
+        let method = u16_at(&cd, p + 10);
+        let crc = u32_at(&cd, p + 16);
+        let mut clen = u64::from(u32_at(&cd, p + 20));
+        let mut ulen = u64::from(u32_at(&cd, p + 24));
+        let name_len = usize::from(u16_at(&cd, p + 28));
+        let extra_len = usize::from(u16_at(&cd, p + 30));
+        let comment_len = usize::from(u16_at(&cd, p + 32));
+        let mut offset = u64::from(u32_at(&cd, p + 42));
+        let name_start = p + 46;
+        let extra_start = name_start + name_len;
+        p = extra_start + extra_len + comment_len;
+        if p > cd.len() {
+            return Err(invalid("bad zip central directory header"));
+        }
+
+        // ZIP64 extended information holds, in order, whichever fields are 0xffff_ffff above.
+        let mut extra = &cd[extra_start..extra_start + extra_len];
+        while extra.len() >= 4 {
+            let id = u16_at(extra, 0);
+            let size = cmp::min(usize::from(u16_at(extra, 2)), extra.len() - 4);
+            let mut field = &extra[4..4 + size];
+            if id == 0x0001 {
+                for v in [&mut ulen, &mut clen, &mut offset] {
+                    if *v == 0xffff_ffff && field.len() >= 8 {
+                        *v = u64_at(field, 0);
+                        field = &field[8..];
+                    }
+                }
+            }
+            extra = &extra[4 + size..];
+        }
+
+        let name = match ::std::str::from_utf8(&cd[name_start..extra_start]) {
+            Ok(n) => normalize(n),
+            Err(_) => continue,
+        };
+        let method = match method {
+            0 => Method::Stored,
+            8 => Method::Deflated,
+            _ => continue,
+        };
+        if flags & 1 != 0 || name.is_empty() || name.ends_with('/') {
+            continue;
+        }
+        if method == Method::Stored && clen != ulen {
+            return Err(invalid("stored zip member with differing sizes"));
+        }
+
+        // The data follows the local file header, whose variable-length fields may differ from
+        // the central directory's.
+        let offset = offset
+            .checked_add(prefix)
+            .ok_or_else(|| invalid("bad zip local file header"))?;
+        let local = read_at(file, offset, 30, len)?;
+        if !local.starts_with(b"PK\x03\x04") {
+            return Err(invalid("bad zip local file header"));
+        }
+        let data_start =
+            offset + 30 + u64::from(u16_at(&local, 26)) + u64::from(u16_at(&local, 28));
+        if data_start.checked_add(clen).map(|e| e > len).unwrap_or(true) {
+            return Err(invalid("zip member extends past end of file"));
+        }
+        members.push(Member {
+            name: name.to_owned(),
+            method,
+            data: data_start..data_start + clen,
+            len: ulen,
+            crc: Some(crc),
+            adler: Mutex::new(None),
+        });
+    }
+    Ok(members)
+}
+
+/// Parses a tar numeric field: octal, or base-256 if the high bit of the first byte is set.
+fn tar_number(field: &[u8]) -> Result<u64, io::Error> {
+    if field[0] & 0x80 != 0 {
+        let mut v: u64 = u64::from(field[0] & 0x7f);
+        for &b in &field[1..] {
+            v = v
+                .checked_mul(256)
+                .ok_or_else(|| invalid("tar number too large"))?
+                | u64::from(b);
+        }
+        return Ok(v);
+    }
+    let s = ::std::str::from_utf8(field).map_err(|_| invalid("bad tar number"))?;
+    let s = s.trim_matches(|c| c == '\0' || c == ' ');
+    if s.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(s, 8).map_err(|_| invalid("bad tar number"))
+}
+
+/// Returns the contents of a NUL-terminated tar string field.
+fn tar_str(field: &[u8]) -> &[u8] {
+    let n = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    &field[..n]
+}
+
+/// Indexes a tar archive by reading each member's headers.
+fn index_tar(file: &File, len: u64) -> Result<Vec<Member>, io::Error> {
+    let mut members = Vec::new();
+    let mut pos = 0;
+    let mut long_name: Option<Vec<u8>> = None;
+    let mut pax_size: Option<u64> = None;
+    let mut h = [0u8; 512];
+    while pos + 512 <= len {
+        file.read_exact_at(&mut h, pos)?;
+        if h.iter().all(|&b| b == 0) {
+            break;
+        }
+        let expected = tar_number(&h[148..156])?;
+        let sum: u64 = h
+            .iter()
+            .enumerate()
+            .map(|(i, &b)| if (148..156).contains(&i) { 32 } else { u64::from(b) })
+            .sum();
+        if sum != expected {
+            return Err(invalid("bad tar header checksum"));
+        }
+        let size = match pax_size.take() {
+            Some(s) => s,
+            None => tar_number(&h[124..136])?,
+        };
+        let data_start = pos + 512;
+        let data_end = data_start
+            .checked_add(size)
+            .filter(|&e| e <= len)
+            .ok_or_else(|| invalid("tar member extends past end of file"))?;
+        pos = data_start + size.div_ceil(512) * 512;
+        match h[156] {
+            b'x' | b'L' => {
+                let data = read_at(file, data_start, size, len)?;
+                if h[156] == b'L' {
+                    long_name = Some(tar_str(&data).to_vec());
+                    continue;
+                }
+                let mut rest = &data[..];
+                while !rest.is_empty() {
+                    let sp = rest
+                        .iter()
+                        .position(|&b| b == b' ')
+                        .ok_or_else(|| invalid("bad pax record"))?;
+                    let n: usize = ::std::str::from_utf8(&rest[..sp])
+                        .ok()
+                        .and_then(|n| n.parse().ok())
+                        .filter(|&n| n >= sp + 2 && n <= rest.len() && rest[n - 1] == b'\n')
+                        .ok_or_else(|| invalid("bad pax record"))?;
+                    let record = &rest[sp + 1..n - 1];
+                    rest = &rest[n..];
+                    if let Some(v) = record.strip_prefix(b"path=") {
+                        long_name = Some(v.to_vec());
+                    } else if let Some(v) = record.strip_prefix(b"size=") {
+                        let v = ::std::str::from_utf8(v).ok().and_then(|v| v.parse().ok());
+                        pax_size = Some(v.ok_or_else(|| invalid("bad pax size"))?);
+                    }
+                }
+            }
+            b'0' | b'\0' | b'7' => {
+                let name = match long_name.take() {
+                    Some(n) => n,
+                    None if &h[257..262] == b"ustar" && h[345] != 0 => {
+                        let mut n = tar_str(&h[345..500]).to_vec();
+                        n.push(b'/');
+                        n.extend_from_slice(tar_str(&h[0..100]));
+                        n
+                    }
+                    None => tar_str(&h[0..100]).to_vec(),
+                };
+                let name = match String::from_utf8(name) {
+                    Ok(n) => n,
+                    Err(_) => continue,
+                };
+                let name = normalize(&name);
+                if name.is_empty() || name.ends_with('/') {
+                    continue;
+                }
+                members.push(Member {
+                    name: name.to_owned(),
+                    method: Method::Stored,
+                    data: data_start..data_end,
+                    len: size,
+                    crc: None,
+                    adler: Mutex::new(None),
+                });
+            }
+            _ => long_name = None,
+        }
+    }
+    Ok(members)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Adler32, Archive};
+    use crate::{Body, BoxError, Entity, InMemoryEntity, TarEntity, ZipArchive};
+    use bytes::Bytes;
+    use flate2::write::{DeflateEncoder, ZlibDecoder};
+    use flate2::{Compression, Crc};
+    use http::header::{self, HeaderMap, HeaderValue};
+    use http::{Request, Response, StatusCode};
+    use http_body_util::BodyExt;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use tempdir::TempDir;
+
+    type Ar = Archive<Bytes, BoxError>;
+
+    /// Writes a zip with deflated members, as most zip tools do.
+    fn deflated_zip(members: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut cd = Vec::new();
+        for &(name, data) in members {
+            let mut enc = DeflateEncoder::new(Vec::new(), Compression::default());
+            enc.write_all(data).unwrap();
+            let compressed = enc.finish().unwrap();
+            let mut crc = Crc::new();
+            crc.update(data);
+            let mut fields = Vec::new();
+            fields.extend_from_slice(&20u16.to_le_bytes()); // version needed.
+            fields.extend_from_slice(&0u16.to_le_bytes()); // flags.
+            fields.extend_from_slice(&8u16.to_le_bytes()); // method: deflate.
+            fields.extend_from_slice(&[0, 0, 0x21, 0]); // time and date.
+            fields.extend_from_slice(&crc.sum().to_le_bytes());
+            fields.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+            fields.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            fields.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            fields.extend_from_slice(&0u16.to_le_bytes()); // extra length.
+            cd.extend_from_slice(b"PK\x01\x02\x14\x00");
+            cd.extend_from_slice(&fields);
+            cd.extend_from_slice(&[0; 10]); // comment length, disk, attributes.
+            cd.extend_from_slice(&(out.len() as u32).to_le_bytes());
+            cd.extend_from_slice(name.as_bytes());
+            out.extend_from_slice(b"PK\x03\x04");
+            out.extend_from_slice(&fields);
+            out.extend_from_slice(name.as_bytes());
+            out.extend_from_slice(&compressed);
+        }
+        let cd_pos = out.len() as u32;
+        let n = members.len() as u16;
+        out.extend_from_slice(&cd);
+        out.extend_from_slice(b"PK\x05\x06\0\0\0\0");
+        out.extend_from_slice(&n.to_le_bytes());
+        out.extend_from_slice(&n.to_le_bytes());
+        out.extend_from_slice(&(cd.len() as u32).to_le_bytes());
+        out.extend_from_slice(&cd_pos.to_le_bytes());
+        out.extend_from_slice(&0u16.to_le_bytes());
+        out
+    }
+
+    fn ae_hdrs(value: &'static str) -> HeaderMap {
+        let mut h = HeaderMap::new();
+        h.insert(header::ACCEPT_ENCODING, HeaderValue::from_static(value));
+        h
+    }
+
+    async fn body<T: Entity<Data = Bytes, Error = BoxError>>(e: &T) -> Vec<u8> {
+        crate::body_bytes(e.get_range(0..e.len())).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn stored_zip() {
+        let zip: ZipArchive<Bytes, BoxError> = ZipArchive::new()
+            .with_member("index.html", InMemoryEntity::new(&b"<html>hi</html>"[..]))
+            .with_member("dir/", InMemoryEntity::new(&b""[..]))
+            .with_member("dir/data.bin", InMemoryEntity::new(vec![3u8; 1000]));
+        let tmp = TempDir::new("http-archive").unwrap();
+        let p = tmp.path().join("a.zip");
+        let bytes = crate::body_bytes(zip.get_range(0..zip.len())).await.unwrap();
+        fs::write(&p, bytes).unwrap();
+
+        let a = Ar::open(File::open(&p).unwrap()).unwrap();
+        let mut names: Vec<&str> = a.names().collect();
+        names.sort();
+        assert_eq!(vec!["dir/data.bin", "index.html"], names);
+        assert!(a.member("dir/", &HeaderMap::new()).is_none());
+        assert!(a.member("missing", &HeaderMap::new()).is_none());
+
+        let m = a.member("/index.html", &ae_hdrs("deflate")).unwrap();
+        assert_eq!(b"<html>hi</html>", &body(&m).await[..]);
+        let mut h = HeaderMap::new();
+        m.add_headers(&mut h);
+        assert_eq!("text/html; charset=utf-8", h[header::CONTENT_TYPE]);
+        assert!(h.get(header::CONTENT_ENCODING).is_none());
+
+        let d = a.member("dir/data.bin", &HeaderMap::new()).unwrap();
+        assert_eq!(&[3u8; 10][..], &crate::body_bytes(d.get_range(500..510)).await.unwrap()[..]);
+        assert!(m.etag().is_some());
+        assert_ne!(m.etag(), d.etag());
+    }
+
+    #[tokio::test]
+    async fn deflated_zip_member() {
+        let text = b"hello hello hello hello, world\n".repeat(5000);
+        let tmp = TempDir::new("http-archive").unwrap();
+        let p = tmp.path().join("a.zip");
+        fs::write(&p, deflated_zip(&[("a.txt", &text), ("./b.txt", b"b")])).unwrap();
+        let a = Ar::open(File::open(&p).unwrap()).unwrap();
+
+        // Inflated.
+        let m = a.member("a.txt", &HeaderMap::new()).unwrap();
+        assert_eq!(text.len() as u64, m.len());
+        assert_eq!(&text[..], &body(&m).await[..]);
+        let part = crate::body_bytes(m.get_range(100_000..100_010)).await.unwrap();
+        assert_eq!(&text[100_000..100_010], &part[..]);
+        assert_eq!(b"b", &body(&a.member("b.txt", &HeaderMap::new()).unwrap()).await[..]);
+
+        // Passed through with zlib framing.
+        let z = a.member("a.txt", &ae_hdrs("gzip, deflate")).unwrap();
+        let mut h = HeaderMap::new();
+        z.add_headers(&mut h);
+        assert_eq!("deflate", h[header::CONTENT_ENCODING]);
+        assert_eq!("text/plain; charset=utf-8", h[header::CONTENT_TYPE]);
+        assert_ne!(m.etag(), z.etag());
+        let zlib = body(&z).await;
+        assert_eq!(z.len(), zlib.len() as u64);
+        let mut dec = ZlibDecoder::new(Vec::new());
+        dec.write_all(&zlib).unwrap();
+        assert_eq!(&text[..], &dec.finish().unwrap()[..]);
+
+        // Passing through all of the deflate data computes the Adler-32 without reading it again.
+        let a = Ar::open(File::open(&p).unwrap()).unwrap();
+        let z = a.member("a.txt", &ae_hdrs("deflate")).unwrap();
+        let len = z.len();
+        crate::body_bytes(z.get_range(0..len - 4)).await.unwrap();
+        assert!(z.member.adler.lock().unwrap().is_some());
+
+        // The trailer alone, from a fresh member (with the Adler-32 now cached) and otherwise.
+        let len = z.len();
+        let trailer = crate::body_bytes(z.get_range(len - 3..len)).await.unwrap();
+        assert_eq!(&zlib[zlib.len() - 3..], &trailer[..]);
+        let a = Ar::open(File::open(&p).unwrap()).unwrap();
+        let z = a.member("a.txt", &ae_hdrs("deflate")).unwrap();
+        let trailer = crate::body_bytes(z.get_range(1..len)).await.unwrap();
+        assert_eq!(&zlib[1..], &trailer[..]);
+    }
+
+    #[tokio::test]
+    async fn serve() {
+        let tmp = TempDir::new("http-archive").unwrap();
+        let p = tmp.path().join("a.zip");
+        fs::write(&p, deflated_zip(&[("a.txt", b"hello")])).unwrap();
+        let a = Ar::open(File::open(&p).unwrap()).unwrap();
+        let req = Request::get("/").body(()).unwrap();
+        let resp: Response<Body> = a.serve("a.txt", &req).unwrap();
+        assert_eq!(StatusCode::OK, resp.status());
+        assert_eq!("accept-encoding", resp.headers()[header::VARY]);
+        let b = resp.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(b"hello", &b[..]);
+        assert!(a.serve::<Body, _>("b.txt", &req).is_none());
+    }
+
+    #[tokio::test]
+    async fn tar() {
+        let tmp = TempDir::new("http-archive").unwrap();
+        let root = tmp.path().join("root");
+        let long = "l".repeat(150);
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("sub/a.css"), b"a {}").unwrap();
+        fs::write(root.join(&long), b"long").unwrap();
+        let t: TarEntity<Bytes, BoxError> = TarEntity::new(&root).unwrap();
+        let p = tmp.path().join("a.tar");
+        fs::write(&p, body(&t).await).unwrap();
+
+        let a = Ar::open(File::open(&p).unwrap()).unwrap();
+        let mut names: Vec<&str> = a.names().collect();
+        names.sort();
+        assert_eq!(vec![&long[..], "sub/a.css"], names);
+        let m = a.member("sub/a.css", &ae_hdrs("deflate")).unwrap();
+        assert_eq!(b"a {}", &body(&m).await[..]);
+        let mut h = HeaderMap::new();
+        m.add_headers(&mut h);
+        assert_eq!("text/css; charset=utf-8", h[header::CONTENT_TYPE]);
+        assert_eq!(b"long", &body(&a.member(&long, &HeaderMap::new()).unwrap()).await[..]);
+    }
+
+    /// Returns a tar header with the given type and size and a valid checksum.
+    fn tar_header(typeflag: u8, size: usize) -> Vec<u8> {
+        let mut h = vec![0u8; 512];
+        h[..1].copy_from_slice(b"a");
+        h[124..135].copy_from_slice(format!("{:011o}", size).as_bytes());
+        h[156] = typeflag;
+        h[257..263].copy_from_slice(b"ustar\0");
+        h[263..265].copy_from_slice(b"00");
+        h[148..156].copy_from_slice(b"        ");
+        let sum: u32 = h.iter().map(|&b| u32::from(b)).sum();
+        h[148..155].copy_from_slice(format!("{:06o}\0", sum).as_bytes());
+        h
+    }
+
+    #[test]
+    fn bad_pax_records() {
+        let tmp = TempDir::new("http-archive").unwrap();
+        let p = tmp.path().join("a.tar");
+        for &record in &[&b"2 x"[..], b"3 x", b"9 path=x\n"] {
+            let mut t = tar_header(b'x', record.len());
+            t.extend_from_slice(record);
+            t.resize(1024, 0);
+            t.extend_from_slice(&tar_header(b'0', 0));
+            t.resize(2560, 0);
+            fs::write(&p, &t).unwrap();
+            let r = Ar::open(File::open(&p).unwrap());
+            if record.ends_with(b"\n") {
+                assert_eq!(vec!["x"], r.unwrap().names().collect::<Vec<_>>());
+            } else {
+                let e = r.err().unwrap();
+                assert!(e.to_string().contains("bad pax record"), "{}", e);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn empty_zip() {
+        let zip: ZipArchive<Bytes, BoxError> = ZipArchive::new();
+        let tmp = TempDir::new("http-archive").unwrap();
+        let p = tmp.path().join("a.zip");
+        fs::write(&p, body(&zip).await).unwrap();
+        let a = Ar::open(File::open(&p).unwrap()).unwrap();
+        assert_eq!(0, a.names().count());
+    }
+
+    #[tokio::test]
+    async fn prefixed_zip() {
+        let stub = b"#!/bin/sh\necho self-extracting stub\nexit 0\n".repeat(20);
+        let tmp = TempDir::new("http-archive").unwrap();
+        let p = tmp.path().join("a.zip");
+
+        // An ordinary zip, and one with ZIP64 end records, each with a stub prepended.
+        let zip: ZipArchive<Bytes, BoxError> =
+            ZipArchive::new().with_member("b.txt", InMemoryEntity::new(&b"stored"[..]));
+        for zip in &[deflated_zip(&[("a.txt", b"hello")]), body(&zip).await] {
+            let mut f = stub.clone();
+            f.extend_from_slice(zip);
+            fs::write(&p, &f).unwrap();
+            let a = Ar::open(File::open(&p).unwrap()).unwrap();
+            let name = a.names().next().unwrap().to_owned();
+            let m = a.member(&name, &HeaderMap::new()).unwrap();
+            let expected: &[u8] = if name == "a.txt" { b"hello" } else { b"stored" };
+            assert_eq!(expected, &body(&m).await[..]);
+        }
+    }
+
+    #[test]
+    fn unrecognized() {
+        let tmp = TempDir::new("http-archive").unwrap();
+        let p = tmp.path().join("a.txt");
+        fs::write(&p, b"not an archive").unwrap();
+        assert!(Ar::open(File::open(&p).unwrap()).is_err());
+    }
+
+    #[test]
+    fn adler32() {
+        let mut a = Adler32::new();
+        a.update(b"Wikipedia");
+        assert_eq!(0x11e6_0398, a.sum());
+        let mut a = Adler32::new();
+        a.update(&[0xff; 100_000]);
+        assert_eq!(0x149a_302c, a.sum());
+    }
+}
@@ -0,0 +1,90 @@
+// Copyright (c) 2018 Scott Lamb <slamb@slamb.org>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! axum integration, available with the `axum` feature.
+
+use crate::{serve, Body, BoxError, Entity};
+use axum_core::response::{IntoResponse, Response};
+use http::header::HeaderMap;
+use http::{Method, Request};
+
+/// An `Entity` along with the request parts `serve` needs, which can be returned from an axum
+/// handler.
+///
+/// ```
+/// # use http::header::HeaderMap;
+/// # use http::Method;
+/// # use http_serve::{AxumEntity, BoxError, InMemoryEntity};
+/// type E = InMemoryEntity<&'static [u8], bytes::Bytes, BoxError>;
+///
+/// async fn handler(method: Method, headers: HeaderMap) -> AxumEntity<E> {
+///     AxumEntity::new(InMemoryEntity::new(&b"hello"[..]), method, headers)
+/// }
+/// ```
+pub struct AxumEntity<E: Entity> {
+    entity: E,
+    req: Request<()>,
+}
+
+impl<E: Entity> AxumEntity<E> {
+    /// Creates a new `AxumEntity` to serve `entity` in response to a request with the given
+    /// method and headers, as extracted by axum.
+    pub fn new(entity: E, method: Method, headers: HeaderMap) -> Self {
+        let mut req = Request::new(());
+        *req.method_mut() = method;
+        *req.headers_mut() = headers;
+        AxumEntity { entity, req }
+    }
+
+    /// Creates a new `AxumEntity` to serve `entity` in response to `req`.
+    pub fn from_request<B>(entity: E, req: &Request<B>) -> Self {
+        Self::new(entity, req.method().clone(), req.headers().clone())
+    }
+}
+
+impl<E> IntoResponse for AxumEntity<E>
+where
+    E: Entity,
+    E::Error: Into<BoxError>,
+{
+    fn into_response(self) -> Response {
+        let resp = serve::<_, Body<E::Data, E::Error>, _>(self.entity, &self.req);
+        resp.map(|b| axum_core::body::Body::from_stream(crate::body::bytes_stream(b.into_stream())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AxumEntity;
+    use crate::{BoxError, InMemoryEntity};
+    use axum_core::response::IntoResponse;
+    use bytes::Bytes;
+    use http::header::{self, HeaderMap, HeaderValue};
+    use http::{Method, StatusCode};
+    use http_body_util::BodyExt;
+
+    type E = InMemoryEntity<&'static [u8], Bytes, BoxError>;
+
+    #[tokio::test]
+    async fn into_response() {
+        let e = || E::new(&b"hello"[..]);
+        let mut hdrs = HeaderMap::new();
+        hdrs.insert(header::RANGE, HeaderValue::from_static("bytes=1-3"));
+        let resp = AxumEntity::new(e(), Method::GET, hdrs).into_response();
+        assert_eq!(StatusCode::PARTIAL_CONTENT, resp.status());
+        assert_eq!(resp.headers().get(header::CONTENT_LENGTH).unwrap(), "3");
+        let b = resp.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&b"ell"[..], &b[..]);
+
+        let resp = AxumEntity::new(e(), Method::HEAD, HeaderMap::new()).into_response();
+        assert_eq!(StatusCode::OK, resp.status());
+        assert_eq!(resp.headers().get(header::CONTENT_LENGTH).unwrap(), "5");
+        let b = resp.into_body().collect().await.unwrap().to_bytes();
+        assert!(b.is_empty());
+    }
+}
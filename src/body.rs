@@ -0,0 +1,123 @@
+// Copyright (c) 2018 Scott Lamb <slamb@slamb.org>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use bytes::{Buf, Bytes};
+use futures_util::future;
+use futures_util::stream::{self, BoxStream, StreamExt};
+use http_body::Frame;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// A boxed error, the usual `Error` type of entities and bodies.
+pub type BoxError = Box<dyn ::std::error::Error + Send + Sync>;
+
+/// A `http_body::Body` which yields the chunks of a stream, as produced by `serve` and
+/// `streaming_body`.
+///
+/// Those functions can produce any body type which implements `From<BoxStream<'static,
+/// Result<D, E>>>`; this is the simplest such type. An error from the stream causes hyper to
+/// abort the connection.
+pub struct Body<D = Bytes, E = BoxError>(BoxStream<'static, Result<D, E>>);
+
+impl<D, E> Body<D, E>
+where
+    D: Send + 'static,
+    E: Send + 'static,
+{
+    /// Returns a body with no data.
+    pub fn empty() -> Self {
+        Body(stream::empty().boxed())
+    }
+
+    /// Returns the underlying stream, for adapting to other frameworks' body types.
+    pub fn into_stream(self) -> BoxStream<'static, Result<D, E>> {
+        self.0
+    }
+}
+
+impl<D, E> From<BoxStream<'static, Result<D, E>>> for Body<D, E> {
+    fn from(s: BoxStream<'static, Result<D, E>>) -> Self {
+        Body(s)
+    }
+}
+
+impl<D, E> From<Vec<u8>> for Body<D, E>
+where
+    D: From<Vec<u8>> + Send + 'static,
+    E: Send + 'static,
+{
+    fn from(v: Vec<u8>) -> Self {
+        Body(stream::once(future::ready(Ok(v.into()))).boxed())
+    }
+}
+
+impl<D, E> From<String> for Body<D, E>
+where
+    D: From<Vec<u8>> + Send + 'static,
+    E: Send + 'static,
+{
+    fn from(s: String) -> Self {
+        s.into_bytes().into()
+    }
+}
+
+impl<D, E> From<&'static str> for Body<D, E>
+where
+    D: From<&'static [u8]> + Send + 'static,
+    E: Send + 'static,
+{
+    fn from(s: &'static str) -> Self {
+        Body(stream::once(future::ready(Ok(s.as_bytes().into()))).boxed())
+    }
+}
+
+/// Converts a stream of chunks into one of `Bytes`, as other frameworks' body types expect.
+#[cfg(any(feature = "actix", feature = "axum"))]
+pub(crate) fn bytes_stream<D, E>(
+    s: BoxStream<'static, Result<D, E>>,
+) -> BoxStream<'static, Result<Bytes, BoxError>>
+where
+    D: Buf + Send + 'static,
+    E: Into<BoxError> + Send + 'static,
+{
+    s.map(|r| match r {
+        Ok(mut d) => Ok(d.copy_to_bytes(d.remaining())),
+        Err(e) => Err(e.into()),
+    }).boxed()
+}
+
+impl<D: Buf, E> http_body::Body for Body<D, E> {
+    type Data = D;
+    type Error = E;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<D>, E>>> {
+        self.0
+            .as_mut()
+            .poll_next(cx)
+            .map(|o| o.map(|r| r.map(Frame::data)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Body;
+    use bytes::Bytes;
+    use futures::executor::block_on;
+    use http_body_util::BodyExt;
+
+    #[test]
+    fn collect() {
+        let b: Body = "hello".into();
+        assert_eq!(&b"hello"[..], &block_on(b.collect()).unwrap().to_bytes()[..]);
+        let b: Body<Bytes, ()> = Body::empty();
+        assert!(block_on(b.collect()).unwrap().to_bytes().is_empty());
+    }
+}
@@ -6,8 +6,8 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use futures::Stream;
-use futures::sync::mpsc;
+use futures_channel::mpsc;
+use futures_util::stream::{BoxStream, StreamExt};
 use std::io::{self, Write};
 use std::mem;
 
@@ -39,13 +39,10 @@ where
     D: From<Vec<u8>> + Send + 'static,
     E: Send + 'static,
 {
-    pub(crate) fn with_chunk_size(cap: usize) -> (Self, Box<Stream<Item = D, Error = E> + Send>) {
+    pub(crate) fn with_chunk_size(cap: usize) -> (Self, BoxStream<'static, Result<D, E>>) {
         assert!(cap > 0);
         let (snd, rcv) = mpsc::unbounded();
-        let body = Box::new(
-            rcv.map_err(|()| unreachable!())
-                .and_then(::futures::future::result),
-        );
+        let body = rcv.boxed();
         (
             BodyWriter {
                 sender: snd,
@@ -89,7 +86,7 @@ where
         if !self.buf.is_empty() {
             let cap = self.buf.capacity();
             let full_buf = mem::replace(&mut self.buf, Vec::with_capacity(cap));
-            if let Err(_) = self.sender.unbounded_send(Ok(full_buf.into())) {
+            if self.sender.unbounded_send(Ok(full_buf.into())).is_err() {
                 // If this error is returned, no further writes will succeed either.
                 // Therefore, it's acceptable to just drop the full_buf (now e.into_inner())
                 // rather than put it back as self.buf; it won't cause us to write a stream with
@@ -117,10 +114,11 @@ where
 #[cfg(test)]
 mod tests {
     use super::BodyWriter;
-    use futures::{Future, Stream};
+    use futures::executor::block_on;
+    use futures::stream::{BoxStream, StreamExt, TryStreamExt};
     use std::io::Write;
 
-    type BodyStream = Box<Stream<Item = Vec<u8>, Error = ()> + Send>;
+    type BodyStream = BoxStream<'static, Result<Vec<u8>, ()>>;
 
     // A smaller-than-chunk-size write shouldn't be flushed on write, and there's currently no Drop
     // implementation to do it either.
@@ -130,7 +128,7 @@ mod tests {
         assert_eq!(w.write(b"1").unwrap(), 1);
         w.truncate();
         drop(w);
-        assert_eq!(b"", &body.concat2().wait().unwrap()[..]);
+        assert_eq!(b"", &block_on(body.try_concat()).unwrap()[..]);
     }
 
     // With a flush, the content should show up.
@@ -140,7 +138,7 @@ mod tests {
         assert_eq!(w.write(b"1").unwrap(), 1);
         w.flush().unwrap();
         drop(w);
-        assert_eq!(b"1", &body.concat2().wait().unwrap()[..]);
+        assert_eq!(b"1", &block_on(body.try_concat()).unwrap()[..]);
     }
 
     // A write of exactly the chunk size should be automatically flushed.
@@ -150,7 +148,7 @@ mod tests {
         assert_eq!(w.write(b"1234").unwrap(), 4);
         w.flush().unwrap();
         drop(w);
-        assert_eq!(b"1234", &body.concat2().wait().unwrap()[..]);
+        assert_eq!(b"1234", &block_on(body.try_concat()).unwrap()[..]);
     }
 
     // ...and everything should be set up for the next write as well.
@@ -161,7 +159,7 @@ mod tests {
         assert_eq!(w.write(b"5678").unwrap(), 4);
         w.flush().unwrap();
         drop(w);
-        assert_eq!(b"12345678", &body.concat2().wait().unwrap()[..]);
+        assert_eq!(b"12345678", &block_on(body.try_concat()).unwrap()[..]);
     }
 
     // A larger-than-chunk-size write should be turned into a chunk-size write.
@@ -170,7 +168,7 @@ mod tests {
         let (mut w, body): (_, BodyStream) = BodyWriter::with_chunk_size(4);
         assert_eq!(w.write(b"123456").unwrap(), 4);
         drop(w);
-        assert_eq!(b"1234", &body.concat2().wait().unwrap()[..]);
+        assert_eq!(b"1234", &block_on(body.try_concat()).unwrap()[..]);
     }
 
     // ...similarly, one that uses all the remaining capacity of the chunk.
@@ -180,7 +178,7 @@ mod tests {
         assert_eq!(w.write(b"1").unwrap(), 1);
         assert_eq!(w.write(b"2345").unwrap(), 3);
         drop(w);
-        assert_eq!(b"1234", &body.concat2().wait().unwrap()[..]);
+        assert_eq!(b"1234", &block_on(body.try_concat()).unwrap()[..]);
     }
 
     // Aborting should add an Err element to the stream, ignoring any unflushed bytes.
@@ -191,10 +189,7 @@ mod tests {
         w.truncate();
         w.abort(());
         drop(w);
-        let items = body.then(|r| -> Result<_, ()> { Ok(r) })
-            .collect()
-            .wait()
-            .unwrap();
+        let items: Vec<_> = block_on(body.collect());
         assert_eq!(items.len(), 2);
         assert_eq!(b"1234", &items[0].as_ref().unwrap()[..]);
         items[1].as_ref().unwrap_err();
@@ -0,0 +1,365 @@
+// Copyright (c) 2018 Scott Lamb <slamb@slamb.org>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use http::header::{self, HeaderMap, HeaderValue};
+
+/// A content coding, as named in `Accept-Encoding` and `Content-Encoding` headers.
+///
+/// `Identity` represents the absence of a content coding; it's never sent in a
+/// `Content-Encoding` header.
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+pub enum Coding {
+    Identity,
+    Gzip,
+    Deflate,
+    Brotli,
+    Zstd,
+}
+
+impl Coding {
+    /// Returns the `content-coding` token, as used in `Accept-Encoding` and `Content-Encoding`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Coding::Identity => "identity",
+            Coding::Gzip => "gzip",
+            Coding::Deflate => "deflate",
+            Coding::Brotli => "br",
+            Coding::Zstd => "zstd",
+        }
+    }
+
+    /// Returns the coding named by the given (case-insensitive) token, if known.
+    ///
+    /// As required by [RFC 7230 section
+    /// 4.2.3](https://tools.ietf.org/html/rfc7230#section-4.2.3), `x-gzip` is considered
+    /// equivalent to `gzip`.
+    pub fn from_token(token: &str) -> Option<Self> {
+        let t = token.to_ascii_lowercase();
+        Some(match &t[..] {
+            "identity" => Coding::Identity,
+            "gzip" | "x-gzip" => Coding::Gzip,
+            "deflate" => Coding::Deflate,
+            "br" => Coding::Brotli,
+            "zstd" => Coding::Zstd,
+            _ => return None,
+        })
+    }
+}
+
+/// One element of an `Accept-Encoding` header: a coding and its quality value.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct QualityItem {
+    /// The coding: a lowercased `content-coding` token, `identity`, or `*`.
+    pub coding: String,
+
+    /// The quality value in thousandths, from 0 (not acceptable) to 1000 (most preferred).
+    pub quality: u16,
+}
+
+/// A parsed `Accept-Encoding` header, as described in [RFC 7231 section
+/// 5.3.4](https://tools.ietf.org/html/rfc7231#section-5.3.4).
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct AcceptEncoding {
+    items: Vec<QualityItem>,
+}
+
+impl AcceptEncoding {
+    /// Parses the `Accept-Encoding` header(s) of a request, returning `None` if there are none.
+    ///
+    /// Multiple header fields are combined as if they were a single comma-separated list.
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let mut values = headers.get_all(header::ACCEPT_ENCODING).iter().peekable();
+        values.peek()?;
+        let mut ae = AcceptEncoding::default();
+        for v in values {
+            ae.extend(v);
+        }
+        Some(ae)
+    }
+
+    /// Parses a single `Accept-Encoding` header value.
+    ///
+    /// Unparseable elements (such as one with a malformed quality value) are skipped rather than
+    /// invalidating the rest of the header.
+    pub fn parse(value: &HeaderValue) -> Self {
+        let mut ae = AcceptEncoding::default();
+        ae.extend(value);
+        ae
+    }
+
+    fn extend(&mut self, value: &HeaderValue) {
+        let value = match value.to_str() {
+            Ok(v) => v,
+            Err(_) => return,
+        };
+        self.items.extend(value.split(',').filter_map(parse_item));
+    }
+
+    /// Returns all parsed elements, in the order they appeared.
+    pub fn items(&self) -> &[QualityItem] {
+        &self.items
+    }
+
+    /// Returns the quality value of `coding` in thousandths, following the rules of RFC 7231
+    /// section 5.3.4.
+    ///
+    /// A coding which is listed explicitly gets the listed quality; otherwise `*` applies.
+    /// Identity is acceptable by default unless excluded by `identity;q=0` or `*;q=0`. When it's
+    /// not listed, it's given the lowest non-zero quality so that any acceptable coding is
+    /// preferred to it.
+    pub fn quality(&self, coding: Coding) -> u16 {
+        let (mut explicit, mut star) = (None, None);
+        for i in &self.items {
+            if i.coding == "*" {
+                star = Some(i.quality);
+            } else if Coding::from_token(&i.coding) == Some(coding) {
+                explicit = Some(i.quality);
+            }
+        }
+        let default = if coding == Coding::Identity { 1 } else { 0 };
+        explicit.or(star).unwrap_or(default)
+    }
+
+    /// Returns the most preferred acceptable coding of `available`, or `None` if none are
+    /// acceptable.
+    ///
+    /// Ties are broken in favor of the earliest entry in `available`, so servers should list
+    /// their own preferences first. `available` should include `Coding::Identity` if the
+    /// response can be sent without a content coding.
+    pub fn negotiate(&self, available: &[Coding]) -> Option<Coding> {
+        let mut best: Option<(Coding, u16)> = None;
+        for &c in available {
+            let q = self.quality(c);
+            if q > 0 && best.map(|(_, best_q)| q > best_q).unwrap_or(true) {
+                best = Some((c, q));
+            }
+        }
+        best.map(|(c, _)| c)
+    }
+}
+
+/// Parses `codings [ weight ]`, returning `None` if unparseable.
+fn parse_item(item: &str) -> Option<QualityItem> {
+    let mut parts = item.splitn(2, ';');
+    let coding = parts.next().expect("splitn returns at least one part").trim();
+    if coding.is_empty() || !coding.bytes().all(is_tchar) {
+        return None;
+    }
+    let quality = match parts.next() {
+        None => 1000,
+        Some(w) => {
+            // weight = OWS ";" OWS "q=" qvalue
+            let w = w.trim();
+            if !w.starts_with("q=") && !w.starts_with("Q=") {
+                return None;
+            }
+            parse_qvalue(&w[2..])?
+        }
+    };
+    Some(QualityItem {
+        coding: coding.to_ascii_lowercase(),
+        quality,
+    })
+}
+
+/// Parses a `qvalue` into thousandths, as described in [RFC 7231 section
+/// 5.3.1](https://tools.ietf.org/html/rfc7231#section-5.3.1).
+///
+/// ```text
+/// qvalue = ( "0" [ "." 0*3DIGIT ] )
+///        / ( "1" [ "." 0*3("0") ] )
+/// ```
+pub(crate) fn parse_qvalue(q: &str) -> Option<u16> {
+    let b = q.as_bytes();
+    if b.is_empty() || b.len() > 5 || (b[0] != b'0' && b[0] != b'1') {
+        return None;
+    }
+    let mut thousandths = u16::from(b[0] - b'0') * 1000;
+    if b.len() > 1 {
+        if b[1] != b'.' {
+            return None;
+        }
+        let mut place = 100;
+        for &d in &b[2..] {
+            if !d.is_ascii_digit() {
+                return None;
+            }
+            thousandths += u16::from(d - b'0') * place;
+            place /= 10;
+        }
+    }
+    if thousandths > 1000 {
+        return None;
+    }
+    Some(thousandths)
+}
+
+/// Returns true iff `b` is a `tchar` as described in [RFC 7230 section
+/// 3.2.6](https://tools.ietf.org/html/rfc7230#section-3.2.6).
+fn is_tchar(b: u8) -> bool {
+    match b {
+        b'!' | b'#' | b'$' | b'%' | b'&' | b'\'' | b'*' | b'+' | b'-' | b'.' | b'^' | b'_'
+        | b'`' | b'|' | b'~' => true,
+        b => b.is_ascii_alphanumeric(),
+    }
+}
+
+/// Returns the most preferred acceptable coding of `available` for a request with the given
+/// headers, or `None` if none are acceptable.
+///
+/// "A request without an Accept-Encoding header field implies that the user agent has no
+/// preferences regarding content-codings. Although this allows the server to use any
+/// content-coding in a response, it does not imply that the user agent will be able to correctly
+/// process all encodings." In that case, this returns `Coding::Identity` if it's available and
+/// otherwise the first entry of `available`.
+///
+/// See `AcceptEncoding::negotiate` for the rules when the header is present.
+pub fn negotiate_coding(headers: &HeaderMap, available: &[Coding]) -> Option<Coding> {
+    match AcceptEncoding::from_headers(headers) {
+        Some(ae) => ae.negotiate(available),
+        None if available.contains(&Coding::Identity) => Some(Coding::Identity),
+        None => available.first().cloned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{negotiate_coding, AcceptEncoding, Coding, QualityItem};
+    use http::header::{self, HeaderMap, HeaderValue};
+
+    fn ae_hdrs(value: &'static str) -> HeaderMap {
+        let mut h = HeaderMap::new();
+        h.insert(header::ACCEPT_ENCODING, HeaderValue::from_static(value));
+        h
+    }
+
+    fn item(coding: &str, quality: u16) -> QualityItem {
+        QualityItem {
+            coding: coding.to_owned(),
+            quality,
+        }
+    }
+
+    #[test]
+    fn parse() {
+        let ae = AcceptEncoding::parse(&HeaderValue::from_static(
+            "gzip, BR;q=0.5 ,zstd ; q=0.25, *;q=0, x-custom;q=1.000",
+        ));
+        assert_eq!(
+            ae.items(),
+            &[
+                item("gzip", 1000),
+                item("br", 500),
+                item("zstd", 250),
+                item("*", 0),
+                item("x-custom", 1000),
+            ]
+        );
+        assert!(AcceptEncoding::parse(&HeaderValue::from_static("")).items().is_empty());
+    }
+
+    #[test]
+    fn parse_malformed() {
+        // Malformed elements are skipped; the rest still apply.
+        let ae = AcceptEncoding::parse(&HeaderValue::from_static(
+            "gzip;q=abc, br;q=1.5, zstd;q=0.0001, deflate;foo=bar, identity=q=0, a b, x;q=0.5",
+        ));
+        assert_eq!(ae.items(), &[item("x", 500)]);
+    }
+
+    #[test]
+    fn from_headers() {
+        assert_eq!(None, AcceptEncoding::from_headers(&HeaderMap::new()));
+        let mut h = HeaderMap::new();
+        h.append(header::ACCEPT_ENCODING, HeaderValue::from_static("gzip"));
+        h.append(header::ACCEPT_ENCODING, HeaderValue::from_static("br;q=0.5"));
+        assert_eq!(
+            AcceptEncoding::from_headers(&h).unwrap().items(),
+            &[item("gzip", 1000), item("br", 500)]
+        );
+    }
+
+    /// Tests gzip vs identity as described in RFC 7231 section 5.3.4.
+    #[test]
+    fn gzip_vs_identity() {
+        let gzip = |v| negotiate_coding(&ae_hdrs(v), &[Coding::Gzip, Coding::Identity]);
+
+        // "A request without an Accept-Encoding header field implies that the
+        // user agent has no preferences regarding content-codings. Although
+        // this allows the server to use any content-coding in a response, it
+        // does not imply that the user agent will be able to correctly process
+        // all encodings." Identity seems safer; don't gzip.
+        assert_eq!(
+            Some(Coding::Identity),
+            negotiate_coding(&HeaderMap::new(), &[Coding::Gzip, Coding::Identity])
+        );
+
+        // "If the representation's content-coding is one of the
+        // content-codings listed in the Accept-Encoding field, then it is
+        // acceptable unless it is accompanied by a qvalue of 0.  (As
+        // defined in Section 5.3.1, a qvalue of 0 means "not acceptable".)"
+        assert_eq!(Some(Coding::Gzip), gzip("gzip"));
+        assert_eq!(Some(Coding::Gzip), gzip("x-gzip"));
+        assert_eq!(Some(Coding::Gzip), gzip("gzip;q=0.001"));
+        assert_eq!(Some(Coding::Identity), gzip("gzip;q=0"));
+
+        // "An Accept-Encoding header field with a combined field-value that is
+        // empty implies that the user agent does not want any content-coding in
+        // response."
+        assert_eq!(Some(Coding::Identity), gzip(""));
+
+        // "The asterisk "*" symbol in an Accept-Encoding field
+        // matches any available content-coding not explicitly listed in the
+        // header field."
+        assert_eq!(Some(Coding::Gzip), gzip("*"));
+        assert_eq!(Some(Coding::Identity), gzip("gzip;q=0, *"));
+        assert_eq!(Some(Coding::Gzip), gzip("identity=q=0, *"));
+
+        // "If multiple content-codings are acceptable, then the acceptable
+        // content-coding with the highest non-zero qvalue is preferred."
+        assert_eq!(Some(Coding::Gzip), gzip("identity;q=0.5, gzip;q=1.0"));
+        assert_eq!(Some(Coding::Identity), gzip("identity;q=1.0, gzip;q=0.5"));
+
+        // "If the representation has no content-coding, then it is
+        // acceptable by default unless specifically excluded by the
+        // Accept-Encoding field stating either "identity;q=0" or "*;q=0"
+        // without a more specific entry for "identity"."
+        assert_eq!(None, gzip("*;q=0"));
+        assert_eq!(None, gzip("identity;q=0"));
+        assert_eq!(Some(Coding::Identity), gzip("identity, *;q=0"));
+        assert_eq!(None, gzip("deflate, identity;q=0"));
+
+        // A malformed element doesn't affect the others.
+        assert_eq!(Some(Coding::Gzip), gzip("br;q=bogus, gzip"));
+    }
+
+    #[test]
+    fn multiple() {
+        let all = [
+            Coding::Brotli,
+            Coding::Zstd,
+            Coding::Gzip,
+            Coding::Identity,
+        ];
+        let n = |v| negotiate_coding(&ae_hdrs(v), &all);
+
+        // Ties go to the earliest available coding.
+        assert_eq!(Some(Coding::Brotli), n("gzip, br, zstd"));
+        assert_eq!(Some(Coding::Brotli), n("*"));
+        assert_eq!(Some(Coding::Zstd), n("gzip, zstd"));
+
+        // Otherwise the highest quality value wins.
+        assert_eq!(Some(Coding::Gzip), n("gzip;q=1.0, br;q=0.5, zstd;q=0.8"));
+        assert_eq!(Some(Coding::Zstd), n("br;q=0, *;q=0.5"));
+        assert_eq!(Some(Coding::Identity), n("identity;q=1.0, br;q=0.5, gzip;q=0.5"));
+
+        // Nothing is acceptable.
+        assert_eq!(None, negotiate_coding(&ae_hdrs("br"), &[Coding::Gzip]));
+        assert_eq!(None, negotiate_coding(&ae_hdrs("gzip"), &[]));
+    }
+}
@@ -0,0 +1,240 @@
+// Copyright (c) 2018 Scott Lamb <slamb@slamb.org>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use crate::content_etag;
+use crate::Entity;
+use bytes::Buf;
+use futures_util::stream::{self, BoxStream, StreamExt};
+use http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use sha2::{Digest, Sha256};
+use std::cmp;
+use std::ops::Range;
+use std::time::SystemTime;
+
+/// An entity made of several parts served back to back, such as file segments joined with
+/// generated headers.
+///
+/// The parts may be of different types but must share `Data` and `Error` types. A byte range
+/// of the whole is mapped onto the parts it covers; `get_range` isn't called on the others.
+///
+/// The parts' own headers are ignored; supply the whole's headers via `with_content_type` and
+/// `with_header`. The etag is derived from the parts' etags, and is weak if any of them is weak
+/// or absent if any of them is absent. The last modified time is the latest of the parts', or
+/// absent if any of them is absent.
+///
+/// ```
+/// # use bytes::Bytes;
+/// # use http::header::HeaderValue;
+/// # use http_serve::{BoxError, Concat, InMemoryEntity};
+/// # fn main() {
+/// let header: InMemoryEntity<_, Bytes, BoxError> = InMemoryEntity::new(&b"header\n"[..]);
+/// let body: InMemoryEntity<_, Bytes, BoxError> = InMemoryEntity::new(b"body\n".to_vec());
+/// let e = Concat::new()
+///     .with_part(header)
+///     .with_part(body)
+///     .with_content_type(HeaderValue::from_static("text/plain"));
+/// # }
+/// ```
+pub struct Concat<D, E> {
+    parts: Vec<Box<dyn Entity<Data = D, Error = E>>>,
+
+    /// The offset of the end of each part within the whole.
+    ends: Vec<u64>,
+
+    headers: HeaderMap,
+}
+
+impl<D, E> Default for Concat<D, E> {
+    fn default() -> Self {
+        Concat {
+            parts: Vec::new(),
+            ends: Vec::new(),
+            headers: HeaderMap::new(),
+        }
+    }
+}
+
+impl<D, E> Concat<D, E>
+where
+    D: 'static + Send + Buf + From<Vec<u8>> + From<&'static [u8]>,
+    E: 'static + Send,
+{
+    /// Creates a new entity with no parts.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends a part.
+    pub fn with_part<P: Entity<Data = D, Error = E>>(mut self, part: P) -> Self {
+        let end = self.len() + part.len();
+        self.parts.push(Box::new(part));
+        self.ends.push(end);
+        self
+    }
+
+    /// Sets the `Content-Type` header, replacing any existing one.
+    pub fn with_content_type(mut self, content_type: HeaderValue) -> Self {
+        self.headers.insert(header::CONTENT_TYPE, content_type);
+        self
+    }
+
+    /// Adds a header to be sent with the entity.
+    pub fn with_header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.headers.append(name, value);
+        self
+    }
+}
+
+impl<D, E> Entity for Concat<D, E>
+where
+    D: 'static + Send + Buf + From<Vec<u8>> + From<&'static [u8]>,
+    E: 'static + Send,
+{
+    type Data = D;
+    type Error = E;
+
+    fn len(&self) -> u64 {
+        self.ends.last().cloned().unwrap_or(0)
+    }
+
+    fn get_range(&self, range: Range<u64>) -> BoxStream<'static, Result<D, E>> {
+        if range.start == range.end {
+            return stream::empty().boxed();
+        }
+
+        // The first part which ends after the range starts.
+        let first = match self.ends.binary_search(&range.start) {
+            Ok(i) => i + 1,
+            Err(i) => i,
+        };
+        let mut streams = Vec::new();
+        for i in first..self.parts.len() {
+            let part_start = if i == 0 { 0 } else { self.ends[i - 1] };
+            if part_start >= range.end {
+                break;
+            }
+            let start = range.start.saturating_sub(part_start);
+            let end = cmp::min(range.end, self.ends[i]) - part_start;
+            if start < end {
+                streams.push(self.parts[i].get_range(start..end));
+            }
+        }
+        stream::iter(streams).flatten().boxed()
+    }
+
+    fn add_headers(&self, h: &mut HeaderMap) {
+        h.extend(self.headers.iter().map(|(k, v)| (k.clone(), v.clone())));
+    }
+
+    fn etag(&self) -> Option<HeaderValue> {
+        let mut h = Sha256::new();
+        let mut weak = false;
+        for p in &self.parts {
+            let e = p.etag()?;
+            let e = e.as_bytes();
+            weak |= e.starts_with(b"W/");
+            h.update((e.len() as u64).to_be_bytes());
+            h.update(e);
+        }
+        Some(content_etag::format_derived_etag(&h.finalize(), weak))
+    }
+
+    fn last_modified(&self) -> Option<SystemTime> {
+        let mut latest = None;
+        for p in &self.parts {
+            latest = cmp::max(latest, Some(p.last_modified()?));
+        }
+        latest
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Concat;
+    use crate::{BoxError as E, Entity, InMemoryEntity};
+    use bytes::Bytes;
+    use futures::executor::block_on;
+    use http::header::HeaderValue;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    type Mem = InMemoryEntity<&'static [u8], Bytes, E>;
+
+    fn body(e: &Concat<Bytes, E>, range: ::std::ops::Range<u64>) -> Vec<u8> {
+        block_on(crate::body_bytes(e.get_range(range))).unwrap()
+    }
+
+    fn parts() -> Concat<Bytes, E> {
+        Concat::new()
+            .with_part(Mem::new(b"abc"))
+            .with_part(Mem::new(b""))
+            .with_part(Mem::new(b"defg"))
+            .with_part(InMemoryEntity::new(b"hi".to_vec()))
+    }
+
+    #[test]
+    fn get_range() {
+        let e = parts();
+        assert_eq!(9, e.len());
+        assert_eq!(0, Concat::<Bytes, E>::new().len());
+        assert_eq!(b"abcdefghi", &body(&e, 0..9)[..]);
+        assert_eq!(b"bc", &body(&e, 1..3)[..]);
+        assert_eq!(b"cd", &body(&e, 2..4)[..]);
+        assert_eq!(b"defg", &body(&e, 3..7)[..]);
+        assert_eq!(b"efgh", &body(&e, 4..8)[..]);
+        assert_eq!(b"bcdefgh", &body(&e, 1..8)[..]);
+        assert_eq!(b"i", &body(&e, 8..9)[..]);
+        assert_eq!(b"", &body(&e, 3..3)[..]);
+    }
+
+    #[test]
+    fn etag() {
+        let e = parts();
+        let etag = e.etag().unwrap();
+        assert!(etag.as_bytes().starts_with(b"\""));
+        assert_eq!(etag, parts().etag().unwrap());
+
+        // The same bytes split differently have different parts, so a different etag.
+        let other = Concat::new()
+            .with_part(Mem::new(b"abcd"))
+            .with_part(Mem::new(b"efghi"));
+        assert_ne!(Some(etag), other.etag());
+
+        let weak = Concat::new()
+            .with_part(Mem::new(b"a"))
+            .with_part(Mem::new(b"b").with_etag(Some(HeaderValue::from_static("W/\"b\""))));
+        assert!(weak.etag().unwrap().as_bytes().starts_with(b"W/\""));
+
+        let none = Concat::new()
+            .with_part(Mem::new(b"a"))
+            .with_part(Mem::new(b"b").with_etag(None));
+        assert_eq!(None, none.etag());
+    }
+
+    #[test]
+    fn last_modified() {
+        let t = UNIX_EPOCH + Duration::from_secs(10);
+        let e = Concat::<Bytes, E>::new()
+            .with_part(Mem::new(b"a").with_last_modified(t))
+            .with_part(Mem::new(b"b").with_last_modified(UNIX_EPOCH));
+        assert_eq!(Some(t), e.last_modified());
+        assert_eq!(None, e.with_part(Mem::new(b"c")).last_modified());
+    }
+
+    #[test]
+    fn serve() {
+        let req = http::Request::get("/")
+            .header(http::header::RANGE, "bytes=2-4")
+            .body(())
+            .unwrap();
+        let resp: http::Response<crate::Body> = crate::serve(parts(), &req);
+        assert_eq!(http::StatusCode::PARTIAL_CONTENT, resp.status());
+        assert_eq!("bytes 2-4/9", resp.headers()[http::header::CONTENT_RANGE]);
+        let body = block_on(crate::body_bytes(resp.into_body().into_stream())).unwrap();
+        assert_eq!(b"cde", &body[..]);
+    }
+}
@@ -0,0 +1,357 @@
+// Copyright (c) 2018 Scott Lamb <slamb@slamb.org>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use http::header::HeaderValue;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::fs::{File, Metadata};
+use std::io;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// The extended attribute in which etags are persisted.
+#[cfg(target_os = "linux")]
+const XATTR_NAME: &[u8] = b"user.http-serve.etag\0";
+
+/// The size of each read while hashing.
+const HASH_BUF_LEN: usize = 65_536;
+
+/// Identifies a version of a file: if any of these change, the etag must be recomputed.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+struct FileVersion {
+    dev: u64,
+    inode: u64,
+    mtime: SystemTime,
+    len: u64,
+}
+
+impl FileVersion {
+    fn new(m: &Metadata) -> Result<Self, io::Error> {
+        Ok(FileVersion {
+            dev: m.dev(),
+            inode: m.ino(),
+            mtime: m.modified()?,
+            len: m.len(),
+        })
+    }
+
+    /// Returns the prefix of the persisted xattr value for this version. The device and inode
+    /// are omitted because they may differ for the same file when e.g. restoring from backup;
+    /// the attribute is attached to the inode anyway.
+    #[cfg(target_os = "linux")]
+    fn xattr_prefix(&self) -> String {
+        let d = self.mtime
+            .duration_since(::std::time::UNIX_EPOCH)
+            .unwrap_or(::std::time::Duration::from_secs(0));
+        format!("{}.{:09} {} ", d.as_secs(), d.subsec_nanos(), self.len)
+    }
+}
+
+/// The default maximum number of cached etags.
+const DEFAULT_CAPACITY: usize = 10_000;
+
+/// Cached etags, keyed by (device, inode).
+#[derive(Default)]
+struct Entries {
+    /// The latest known version of each (device, inode), its etag, and when it was last used.
+    map: HashMap<(u64, u64), (FileVersion, HeaderValue, u64)>,
+
+    /// Incremented on each use, to find the least recently used entry.
+    tick: u64,
+}
+
+impl Entries {
+    fn get(&mut self, key: &(u64, u64), version: &FileVersion) -> Option<HeaderValue> {
+        self.tick += 1;
+        match self.map.get_mut(key) {
+            Some((v, etag, used)) if v == version => {
+                *used = self.tick;
+                Some(etag.clone())
+            }
+            _ => None,
+        }
+    }
+
+    /// Inserts an entry, first evicting the least recently used one if the cache is full.
+    fn insert(
+        &mut self,
+        key: (u64, u64),
+        version: FileVersion,
+        etag: HeaderValue,
+        capacity: usize,
+    ) {
+        self.tick += 1;
+        if self.map.len() >= capacity && !self.map.contains_key(&key) {
+            let lru = self.map.iter().min_by_key(|(_, &(_, _, used))| used).map(|(k, _)| *k);
+            if let Some(k) = lru {
+                self.map.remove(&k);
+            }
+        }
+        self.map.insert(key, (version, etag, self.tick));
+    }
+}
+
+/// Computes and caches strong etags derived from the SHA-256 of a file's contents.
+///
+/// Unlike the default etag of `ChunkedReadFile`, which is derived from the file's inode number
+/// and modification time, a content hash is the same for identical bytes redeployed or served
+/// from several replicas, so clients can revalidate with `304 Not Modified` across both.
+///
+/// Hashing requires reading the entire file, so results are cached in memory keyed by the file's
+/// device, inode, modification time, and length. Clones share the same cache. It holds at most
+/// 10,000 etags by default (see `with_capacity`), evicting the least recently used, so entries for
+/// deleted or replaced files don't accumulate. Optionally, etags are also persisted in the
+/// `user.http-serve.etag` extended attribute so they survive restarts; see `with_xattr`.
+///
+/// Use with `ChunkedReadFileBuilder::with_content_etag`. Computing an etag performs blocking I/O;
+/// `ChunkedReadFileBuilder::build_async` does so via `tokio::task::spawn_blocking`.
+#[derive(Clone)]
+pub struct ContentEtagCache {
+    entries: Arc<Mutex<Entries>>,
+    capacity: usize,
+    xattr: bool,
+}
+
+impl Default for ContentEtagCache {
+    fn default() -> Self {
+        ContentEtagCache {
+            entries: Arc::default(),
+            capacity: DEFAULT_CAPACITY,
+            xattr: false,
+        }
+    }
+}
+
+impl ContentEtagCache {
+    /// Creates a new, empty cache which doesn't use extended attributes.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the maximum number of etags held in memory. Panics if `capacity` is zero.
+    pub fn with_capacity(self, capacity: usize) -> Self {
+        assert!(capacity > 0, "capacity must be positive");
+        ContentEtagCache { capacity, ..self }
+    }
+
+    /// Sets whether etags are read from and written to the `user.http-serve.etag` extended
+    /// attribute.
+    ///
+    /// The stored value records the modification time and length it was computed for and is
+    /// ignored if they don't match. Failures to write the attribute (as on read-only or
+    /// unsupporting filesystems) are ignored. This is only supported on Linux; elsewhere it has
+    /// no effect.
+    pub fn with_xattr(self, xattr: bool) -> Self {
+        ContentEtagCache { xattr, ..self }
+    }
+
+    /// Returns the etag for `file`, hashing it if necessary.
+    pub fn etag(&self, file: &File) -> Result<HeaderValue, io::Error> {
+        self.etag_for(file, &file.metadata()?)
+    }
+
+    /// Returns the etag for `file` with the given metadata.
+    pub(crate) fn etag_for(&self, file: &File, m: &Metadata) -> Result<HeaderValue, io::Error> {
+        let version = FileVersion::new(m)?;
+        let key = (version.dev, version.inode);
+        if let Some(etag) = self.entries.lock().unwrap().get(&key, &version) {
+            return Ok(etag);
+        }
+        let etag = match self.read_xattr(file, &version) {
+            Some(e) => e,
+            None => {
+                let e = hash(file, version.len)?;
+                self.write_xattr(file, &version, &e);
+                e
+            }
+        };
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(key, version, etag.clone(), self.capacity);
+        Ok(etag)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn read_xattr(&self, file: &File, version: &FileVersion) -> Option<HeaderValue> {
+        use std::os::unix::io::AsRawFd;
+        if !self.xattr {
+            return None;
+        }
+        let mut buf = [0u8; 256];
+        let r = unsafe {
+            ::libc::fgetxattr(
+                file.as_raw_fd(),
+                XATTR_NAME.as_ptr() as *const ::libc::c_char,
+                buf.as_mut_ptr() as *mut ::libc::c_void,
+                buf.len(),
+            )
+        };
+        if r < 0 {
+            return None;
+        }
+        let val = &buf[..r as usize];
+        let prefix = version.xattr_prefix();
+        if !val.starts_with(prefix.as_bytes()) {
+            return None;
+        }
+        HeaderValue::from_bytes(&val[prefix.len()..]).ok()
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn read_xattr(&self, _file: &File, _version: &FileVersion) -> Option<HeaderValue> {
+        None
+    }
+
+    #[cfg(target_os = "linux")]
+    fn write_xattr(&self, file: &File, version: &FileVersion, etag: &HeaderValue) {
+        use std::os::unix::io::AsRawFd;
+        if !self.xattr {
+            return;
+        }
+        let mut val = version.xattr_prefix().into_bytes();
+        val.extend_from_slice(etag.as_bytes());
+        unsafe {
+            ::libc::fsetxattr(
+                file.as_raw_fd(),
+                XATTR_NAME.as_ptr() as *const ::libc::c_char,
+                val.as_ptr() as *const ::libc::c_void,
+                val.len(),
+                0,
+            )
+        };
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn write_xattr(&self, _file: &File, _version: &FileVersion, _etag: &HeaderValue) {}
+}
+
+/// Returns a strong etag derived from the given bytes, as for a file with the same contents.
+pub(crate) fn etag_for_bytes(data: &[u8]) -> HeaderValue {
+    format_etag(&Sha256::digest(data))
+}
+
+fn format_etag(digest: &[u8]) -> HeaderValue {
+    format_derived_etag(digest, false)
+}
+
+/// Formats a digest as an etag, for entities whose etags are derived from others' and are weak
+/// if any of those are weak.
+pub(crate) fn format_derived_etag(digest: &[u8], weak: bool) -> HeaderValue {
+    let mut s = String::with_capacity(4 + 2 * digest.len());
+    if weak {
+        s.push_str("W/");
+    }
+    s.push('"');
+    for b in digest {
+        write!(&mut s, "{:02x}", b).unwrap();
+    }
+    s.push('"');
+    HeaderValue::from_str(&s).unwrap()
+}
+
+/// Hashes the first `len` bytes of `file`, failing if it is shorter.
+fn hash(file: &File, len: u64) -> Result<HeaderValue, io::Error> {
+    let mut h = Sha256::new();
+    let mut buf = vec![0u8; HASH_BUF_LEN];
+    let mut pos = 0;
+    while pos < len {
+        let n = ::std::cmp::min(len - pos, HASH_BUF_LEN as u64) as usize;
+        let n = match file.read_at(&mut buf[..n], pos) {
+            Ok(0) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "file is shorter than expected",
+                ))
+            }
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        h.update(&buf[..n]);
+        pos += n as u64;
+    }
+    Ok(format_etag(&h.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+    use super::ContentEtagCache;
+    use std::fs::File;
+    use std::io::Write;
+
+    #[test]
+    fn same_content_same_etag() {
+        let tmp = TempDir::new("http-content-etag").unwrap();
+        let a = tmp.path().join("a");
+        let b = tmp.path().join("b");
+        File::create(&a).unwrap().write_all(b"hello").unwrap();
+        File::create(&b).unwrap().write_all(b"hello").unwrap();
+        let cache = ContentEtagCache::new();
+        let ea = cache.etag(&File::open(&a).unwrap()).unwrap();
+        assert_eq!(
+            ea,
+            "\"2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824\""
+        );
+        assert_eq!(ea, cache.etag(&File::open(&b).unwrap()).unwrap());
+
+        // Modifying the file invalidates the cached entry.
+        File::create(&a).unwrap().write_all(b"world").unwrap();
+        assert_ne!(ea, cache.etag(&File::open(&a).unwrap()).unwrap());
+    }
+
+    #[test]
+    fn capacity() {
+        let tmp = TempDir::new("http-content-etag").unwrap();
+        let cache = ContentEtagCache::new().with_capacity(2);
+        let files: Vec<File> = ["a", "b", "c"]
+            .iter()
+            .map(|n| {
+                let p = tmp.path().join(n);
+                File::create(&p).unwrap().write_all(n.as_bytes()).unwrap();
+                File::open(&p).unwrap()
+            })
+            .collect();
+        cache.etag(&files[0]).unwrap();
+        cache.etag(&files[1]).unwrap();
+        cache.etag(&files[0]).unwrap();
+        cache.etag(&files[2]).unwrap();
+
+        // "b" was least recently used, so it was evicted.
+        let entries = cache.entries.lock().unwrap();
+        assert_eq!(2, entries.map.len());
+        let ino = |f: &File| ::std::os::unix::fs::MetadataExt::ino(&f.metadata().unwrap());
+        assert!(entries.map.keys().all(|&(_, i)| i != ino(&files[1])));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn xattr() {
+        let tmp = TempDir::new("http-content-etag").unwrap();
+        let p = tmp.path().join("a");
+        File::create(&p).unwrap().write_all(b"hello").unwrap();
+        let f = File::open(&p).unwrap();
+        let e = ContentEtagCache::new().with_xattr(true).etag(&f).unwrap();
+        let cache = ContentEtagCache::new().with_xattr(true);
+        let version = super::FileVersion::new(&f.metadata().unwrap()).unwrap();
+        match cache.read_xattr(&f, &version) {
+            Some(stored) => assert_eq!(e, stored),
+            None => return, // the filesystem doesn't support user xattrs.
+        }
+
+        // A fresh cache should use the stored etag rather than hashing; prove it by storing a
+        // bogus value.
+        let bogus = ::http::header::HeaderValue::from_static("\"bogus\"");
+        cache.write_xattr(&f, &version, &bogus);
+        assert_eq!(bogus, cache.etag(&f).unwrap());
+        assert_eq!(e, ContentEtagCache::new().etag(&f).unwrap());
+    }
+}
@@ -0,0 +1,302 @@
+// Copyright (c) 2018 Scott Lamb <slamb@slamb.org>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use http::header::{self, HeaderMap, HeaderValue};
+use mime::{self, Mime};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+/// The built-in extension table, as (extension, type) pairs.
+static DEFAULT_TYPES: &[(&str, &str)] = &[
+    ("avif", "image/avif"),
+    ("bmp", "image/bmp"),
+    ("css", "text/css"),
+    ("csv", "text/csv"),
+    ("gif", "image/gif"),
+    ("gz", "application/gzip"),
+    ("htm", "text/html"),
+    ("html", "text/html"),
+    ("ico", "image/x-icon"),
+    ("jpeg", "image/jpeg"),
+    ("jpg", "image/jpeg"),
+    ("js", "text/javascript"),
+    ("json", "application/json"),
+    ("m3u8", "application/vnd.apple.mpegurl"),
+    ("md", "text/markdown"),
+    ("mjs", "text/javascript"),
+    ("mp3", "audio/mpeg"),
+    ("mp4", "video/mp4"),
+    ("mpd", "application/dash+xml"),
+    ("oga", "audio/ogg"),
+    ("ogg", "audio/ogg"),
+    ("ogv", "video/ogg"),
+    ("otf", "font/otf"),
+    ("pdf", "application/pdf"),
+    ("png", "image/png"),
+    ("svg", "image/svg+xml"),
+    ("tar", "application/x-tar"),
+    ("ttf", "font/ttf"),
+    ("txt", "text/plain"),
+    ("wasm", "application/wasm"),
+    ("wav", "audio/wav"),
+    ("webm", "video/webm"),
+    ("webp", "image/webp"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("xml", "text/xml"),
+    ("zip", "application/zip"),
+    ("zst", "application/zstd"),
+];
+
+/// The number of leading bytes examined when sniffing.
+const SNIFF_LEN: usize = 512;
+
+/// Derives `Content-Type` headers for files from their extensions.
+///
+/// `ContentTypes::new()` starts with a built-in table of common web types, which can be extended
+/// or overridden with `with_extension`. Types in the `text` top-level type are given a
+/// `charset=utf-8` parameter unless they specify one already. Optionally, the type of files
+/// without an extension can be guessed from their first bytes; see `with_sniffing`.
+///
+/// ```
+/// # extern crate http_serve;
+/// # extern crate mime;
+/// # use http_serve::ContentTypes;
+/// # use std::path::Path;
+/// # fn main() {
+/// let types = ContentTypes::new()
+///     .with_extension("log", mime::TEXT_PLAIN);
+/// assert_eq!(types.for_path(Path::new("a/b.log")).unwrap(), "text/plain; charset=utf-8");
+/// assert_eq!(types.for_path(Path::new("a/b.PNG")).unwrap(), "image/png");
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct ContentTypes {
+    /// Lowercase extension to type.
+    by_ext: HashMap<String, Mime>,
+    sniff: bool,
+}
+
+impl Default for ContentTypes {
+    fn default() -> Self {
+        ContentTypes::new()
+    }
+}
+
+impl ContentTypes {
+    /// Creates a table with the built-in mappings and no sniffing.
+    pub fn new() -> Self {
+        ContentTypes {
+            by_ext: DEFAULT_TYPES
+                .iter()
+                .map(|&(ext, t)| (ext.to_owned(), t.parse().expect("valid built-in type")))
+                .collect(),
+            sniff: false,
+        }
+    }
+
+    /// Creates a table with no mappings and no sniffing.
+    pub fn empty() -> Self {
+        ContentTypes {
+            by_ext: HashMap::new(),
+            sniff: false,
+        }
+    }
+
+    /// Maps the given extension (without leading `.`, matched case-insensitively) to `mime`,
+    /// replacing any existing mapping.
+    pub fn with_extension<X: Into<String>>(mut self, ext: X, mime: Mime) -> Self {
+        self.by_ext.insert(ext.into().to_ascii_lowercase(), mime);
+        self
+    }
+
+    /// Sets whether the type of a file without an extension is guessed from its first bytes.
+    ///
+    /// This recognizes a handful of common binary formats by their magic numbers, HTML documents
+    /// by a leading `<!DOCTYPE html` or `<html`, and otherwise treats valid UTF-8 without control
+    /// characters as `text/plain`. It requires an extra `read(2)` call.
+    pub fn with_sniffing(self, sniff: bool) -> Self {
+        ContentTypes { sniff, ..self }
+    }
+
+    /// Returns the `Content-Type` for `path` based on its extension, if it's in the table.
+    pub fn for_path(&self, path: &Path) -> Option<HeaderValue> {
+        let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+        self.by_ext.get(&ext).map(header_value)
+    }
+
+    /// Returns the `Content-Type` for `path` based on its extension or, if it has none and
+    /// sniffing is enabled, on the first bytes of `file`.
+    pub fn for_file(&self, path: &Path, file: &File) -> Result<Option<HeaderValue>, io::Error> {
+        if path.extension().is_some() || !self.sniff {
+            return Ok(self.for_path(path));
+        }
+        let mut buf = [0u8; SNIFF_LEN];
+        let mut len = 0;
+        while len < SNIFF_LEN {
+            match file.read_at(&mut buf[len..], len as u64) {
+                Ok(0) => break,
+                Ok(n) => len += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(sniff(&buf[..len], len == SNIFF_LEN).map(|m| header_value(&m)))
+    }
+
+    /// Inserts a `Content-Type` header for `path` and `file` as in `for_file`, unless `headers`
+    /// already has one.
+    pub fn add_header(
+        &self,
+        path: &Path,
+        file: &File,
+        headers: &mut HeaderMap,
+    ) -> Result<(), io::Error> {
+        if headers.contains_key(header::CONTENT_TYPE) {
+            return Ok(());
+        }
+        if let Some(v) = self.for_file(path, file)? {
+            headers.insert(header::CONTENT_TYPE, v);
+        }
+        Ok(())
+    }
+}
+
+/// Returns a header value for `m`, adding `charset=utf-8` to text types which lack a charset.
+fn header_value(m: &Mime) -> HeaderValue {
+    let s = if m.type_() == mime::TEXT && m.get_param(mime::CHARSET).is_none() {
+        format!("{}; charset=utf-8", m)
+    } else {
+        m.to_string()
+    };
+    HeaderValue::from_str(&s).expect("Mime is a valid header value")
+}
+
+/// Guesses a type from the leading bytes of a file, which may have been truncated to
+/// `SNIFF_LEN` bytes.
+fn sniff(data: &[u8], truncated: bool) -> Option<Mime> {
+    static MAGIC: &[(&[u8], &str)] = &[
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+        (b"%PDF-", "application/pdf"),
+        (b"PK\x03\x04", "application/zip"),
+        (b"\x1f\x8b", "application/gzip"),
+        (b"\x28\xb5\x2f\xfd", "application/zstd"),
+        (b"\0asm", "application/wasm"),
+    ];
+    if data.is_empty() {
+        return None;
+    }
+    for &(magic, t) in MAGIC {
+        if data.starts_with(magic) {
+            return Some(t.parse().unwrap());
+        }
+    }
+    if data.len() >= 12 && &data[0..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        return Some("image/webp".parse().unwrap());
+    }
+
+    let text = match ::std::str::from_utf8(data) {
+        Ok(s) => s,
+
+        // A multi-byte sequence may have been cut off at the end of the buffer.
+        Err(ref e) if truncated && e.error_len().is_none() => {
+            ::std::str::from_utf8(&data[..e.valid_up_to()]).unwrap()
+        }
+        Err(_) => return None,
+    };
+    let text = text.trim_start_matches('\u{feff}');
+    if text.chars().any(|c| c.is_control() && !c.is_whitespace()) {
+        return None;
+    }
+    let start = text.trim_start().as_bytes();
+    let html = [&b"<!doctype html"[..], &b"<html"[..]].iter().any(|p| {
+        start.len() >= p.len() && start[..p.len()].eq_ignore_ascii_case(p)
+    });
+    Some(if html { mime::TEXT_HTML } else { mime::TEXT_PLAIN })
+}
+
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+    use super::{sniff, ContentTypes};
+    use http::header::{self, HeaderMap, HeaderValue};
+    use std::fs::File;
+    use std::io::Write;
+    use std::path::Path;
+
+    #[test]
+    fn extensions() {
+        let t = ContentTypes::new();
+        assert_eq!(
+            t.for_path(Path::new("index.HTML")).unwrap(),
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(t.for_path(Path::new("a.json")).unwrap(), "application/json");
+        assert_eq!(None, t.for_path(Path::new("a.unknown")));
+        assert_eq!(None, t.for_path(Path::new("README")));
+
+        let t = t.with_extension("JSON", "text/plain; charset=latin1".parse().unwrap())
+            .with_extension("unknown", mime::APPLICATION_OCTET_STREAM);
+        assert_eq!(
+            t.for_path(Path::new("a.json")).unwrap(),
+            "text/plain; charset=latin1"
+        );
+        assert_eq!(
+            t.for_path(Path::new("a.unknown")).unwrap(),
+            "application/octet-stream"
+        );
+        assert_eq!(None, ContentTypes::empty().for_path(Path::new("a.html")));
+    }
+
+    #[test]
+    fn sniffing() {
+        assert_eq!(Some(mime::IMAGE_PNG), sniff(b"\x89PNG\r\n\x1a\nrest", false));
+        assert_eq!(
+            Some(mime::TEXT_HTML),
+            sniff(b"\n  <!DOCTYPE HTML><title>x</title>", false)
+        );
+        assert_eq!(Some(mime::TEXT_PLAIN), sniff(b"hello\nworld\n", false));
+        assert_eq!(Some(mime::TEXT_PLAIN), sniff(b"caf\xc3", true));
+        assert_eq!(None, sniff(b"caf\xc3", false));
+        assert_eq!(None, sniff(b"\x00\x01\x02", false));
+        assert_eq!(None, sniff(b"", false));
+    }
+
+    #[test]
+    fn files() {
+        let tmp = TempDir::new("http-content-type").unwrap();
+        let p = tmp.path().join("README");
+        File::create(&p).unwrap().write_all(b"plain text").unwrap();
+        let f = File::open(&p).unwrap();
+
+        let t = ContentTypes::new();
+        assert_eq!(None, t.for_file(&p, &f).unwrap());
+        let t = t.with_sniffing(true);
+        assert_eq!(
+            t.for_file(&p, &f).unwrap().unwrap(),
+            "text/plain; charset=utf-8"
+        );
+
+        let mut h = HeaderMap::new();
+        h.insert(header::CONTENT_TYPE, HeaderValue::from_static("x/y"));
+        t.add_header(&p, &f, &mut h).unwrap();
+        assert_eq!(h.get(header::CONTENT_TYPE).unwrap(), "x/y");
+        let mut h = HeaderMap::new();
+        t.add_header(&p, &f, &mut h).unwrap();
+        assert_eq!(
+            h.get(header::CONTENT_TYPE).unwrap(),
+            "text/plain; charset=utf-8"
+        );
+    }
+}
@@ -0,0 +1,477 @@
+// Copyright (c) 2018 Scott Lamb <slamb@slamb.org>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use crate::coding::{negotiate_coding, Coding};
+use crate::content_etag::etag_for_bytes;
+use crate::content_type::ContentTypes;
+use crate::serving::serve;
+use crate::static_dir::percent_decode;
+use crate::InMemoryEntity;
+use bytes::Buf;
+use futures_util::stream::BoxStream;
+use http::header::{self, HeaderMap, HeaderValue};
+use http::{Request, Response, StatusCode};
+use std::fmt::Write as FmtWrite;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+/// A compressed variant of an `EmbeddedFile`.
+#[derive(Debug)]
+pub struct EmbeddedVariant {
+    /// The compressed bytes.
+    pub data: &'static [u8],
+
+    /// The variant's own strong etag, including quotes.
+    pub etag: &'static str,
+}
+
+/// A file embedded by `Embed`. Normally only constructed by generated code.
+#[derive(Debug)]
+pub struct EmbeddedFile {
+    /// The path relative to the embedded directory, with `/` separators and no leading `/`.
+    pub path: &'static str,
+
+    /// The uncompressed bytes.
+    pub data: &'static [u8],
+
+    /// The strong etag of `data`, including quotes.
+    pub etag: &'static str,
+
+    /// The `Content-Type` header value, if known.
+    pub content_type: Option<&'static str>,
+
+    /// The file's modification time at build time, in seconds since the epoch.
+    pub last_modified: u64,
+
+    /// The gzip-compressed variant, if any.
+    pub gzip: Option<EmbeddedVariant>,
+
+    /// The brotli-compressed variant, if any.
+    pub brotli: Option<EmbeddedVariant>,
+}
+
+impl EmbeddedFile {
+    /// Returns an entity for the representation best suited to a request with the given headers.
+    ///
+    /// A compressed variant is chosen according to `Accept-Encoding` and given a
+    /// `Content-Encoding` header. The response should carry `Vary: accept-encoding` if the file
+    /// has any variants; `EmbeddedDir::serve` adds this automatically.
+    pub fn entity<D, E>(&self, req_hdrs: &HeaderMap) -> InMemoryEntity<&'static [u8], D, E>
+    where
+        D: 'static + Send + Buf + From<Vec<u8>> + From<&'static [u8]>,
+        E: 'static + Send + Into<Box<dyn ::std::error::Error + Send + Sync>> + From<Box<io::Error>>,
+    {
+        let mut available = Vec::with_capacity(3);
+        if self.brotli.is_some() {
+            available.push(Coding::Brotli);
+        }
+        if self.gzip.is_some() {
+            available.push(Coding::Gzip);
+        }
+        available.push(Coding::Identity);
+        let coding = negotiate_coding(req_hdrs, &available).unwrap_or(Coding::Identity);
+        let variant = match coding {
+            Coding::Brotli => self.brotli.as_ref(),
+            Coding::Gzip => self.gzip.as_ref(),
+            _ => None,
+        };
+        let (data, etag) = match variant {
+            Some(v) => (v.data, v.etag),
+            None => (self.data, self.etag),
+        };
+        let mut e = InMemoryEntity::new(data)
+            .with_etag(Some(HeaderValue::from_static(etag)))
+            .with_last_modified(UNIX_EPOCH + Duration::from_secs(self.last_modified));
+        if let Some(t) = self.content_type {
+            e = e.with_content_type(HeaderValue::from_static(t));
+        }
+        if variant.is_some() {
+            e = e.with_header(
+                header::CONTENT_ENCODING,
+                HeaderValue::from_static(coding.as_str()),
+            );
+        }
+        e
+    }
+}
+
+/// A directory embedded by `Embed`. Normally only constructed by generated code.
+#[derive(Debug)]
+pub struct EmbeddedDir {
+    /// The files, sorted by path.
+    pub files: &'static [EmbeddedFile],
+}
+
+impl EmbeddedDir {
+    /// Returns the file with the given relative path, if any. A leading `/` is ignored.
+    pub fn get(&self, path: &str) -> Option<&'static EmbeddedFile> {
+        let path = path.trim_start_matches('/');
+        self.files
+            .binary_search_by(|f| f.path.cmp(path))
+            .ok()
+            .map(|i| &self.files[i])
+    }
+
+    /// Serves the file named by the request's path, via `serve`.
+    ///
+    /// The path is percent-decoded; paths ending in `/` are served via their `index.html`.
+    /// Missing files produce `404 Not Found`.
+    pub fn serve<D, E, P, PI>(&self, req: &Request<PI>) -> Response<P>
+    where
+        D: 'static + Send + Buf + From<Vec<u8>> + From<&'static [u8]>,
+        E: 'static + Send + Into<Box<dyn ::std::error::Error + Send + Sync>> + From<Box<io::Error>>,
+        P: http_body::Body + From<BoxStream<'static, Result<D, E>>>,
+    {
+        let path = percent_decode(req.uri().path()).and_then(|p| String::from_utf8(p).ok());
+        let f = path.and_then(|mut p| {
+            if p.ends_with('/') {
+                p.push_str("index.html");
+            }
+            self.get(&p)
+        });
+        let f = match f {
+            Some(f) => f,
+            None => {
+                return Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body(crate::static_body::<D, E>("Not found").into())
+                    .unwrap()
+            }
+        };
+        let e: InMemoryEntity<_, D, E> = f.entity(req.headers());
+        let mut resp = serve(e, req);
+        if f.gzip.is_some() || f.brotli.is_some() {
+            resp.headers_mut()
+                .append(header::VARY, HeaderValue::from_static("accept-encoding"));
+        }
+        resp
+    }
+}
+
+/// Generates an `EmbeddedDir` from a directory at build time.
+///
+/// A `build.rs` script uses `Embed` to walk a directory and generate Rust source for an
+/// `EmbeddedDir` which holds each file's bytes, strong etag, `Content-Type`, and optionally
+/// compressed variants, all computed at build time. Compressed variants are kept only when
+/// smaller than the original. At runtime, `EmbeddedDir::serve` serves the files with no hashing
+/// or compression. `http-serve` must be both a dependency and a build dependency.
+///
+/// `build.rs`:
+///
+/// ```no_run
+/// # extern crate http_serve;
+/// fn main() {
+///     let out = std::path::Path::new(&std::env::var_os("OUT_DIR").unwrap()).join("ui.rs");
+///     http_serve::Embed::new("ui").write(&out, "UI").unwrap();
+/// }
+/// ```
+///
+/// `main.rs`:
+///
+/// ```ignore
+/// include!(concat!(env!("OUT_DIR"), "/ui.rs"));
+///
+/// fn serve<B>(req: Request<B>) -> Response<http_serve::Body> {
+///     UI.serve(&req)
+/// }
+/// ```
+pub struct Embed {
+    root: PathBuf,
+    content_types: ContentTypes,
+    gzip: bool,
+    brotli: bool,
+}
+
+impl Embed {
+    /// Creates a generator for the files under `root`, with gzip variants and (if the `brotli`
+    /// feature is enabled) brotli variants.
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Embed {
+            root: root.into(),
+            content_types: ContentTypes::new(),
+            gzip: true,
+            brotli: cfg!(feature = "brotli"),
+        }
+    }
+
+    /// Sets the table used to derive each file's `Content-Type`.
+    pub fn with_content_types(self, content_types: ContentTypes) -> Self {
+        Embed {
+            content_types,
+            ..self
+        }
+    }
+
+    /// Sets whether gzip variants are generated.
+    pub fn with_gzip(self, gzip: bool) -> Self {
+        Embed { gzip, ..self }
+    }
+
+    /// Sets whether brotli variants are generated.
+    ///
+    /// Panics if `brotli` is true and the `brotli` feature is not enabled.
+    pub fn with_brotli(self, brotli: bool) -> Self {
+        assert!(!brotli || cfg!(feature = "brotli"));
+        Embed { brotli, ..self }
+    }
+
+    /// Writes Rust source defining `pub static <name>: EmbeddedDir` to `out`.
+    ///
+    /// Compressed variants are written alongside `out`, which should be within `OUT_DIR`. Also
+    /// prints `cargo:rerun-if-changed` lines for the directory tree.
+    pub fn write(&self, out: &Path, name: &str) -> Result<(), io::Error> {
+        let mut files = Vec::new();
+        walk(&self.root, &mut files)?;
+        let mut entries = Vec::with_capacity(files.len());
+        for p in files {
+            let rel = p.strip_prefix(&self.root).expect("walk returns paths under root");
+            let rel = rel.to_str()
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("non-UTF-8 path {}", rel.display()),
+                    )
+                })?
+                .replace(::std::path::MAIN_SEPARATOR, "/");
+            entries.push((rel, p));
+        }
+        entries.sort();
+
+        let out_dir = out.parent().unwrap_or_else(|| Path::new("."));
+        let mut src = String::new();
+        writeln!(src, "pub static {}: ::http_serve::EmbeddedDir =", name).unwrap();
+        writeln!(src, "    ::http_serve::EmbeddedDir {{\n        files: &[").unwrap();
+        for (i, (rel, p)) in entries.iter().enumerate() {
+            println!("cargo:rerun-if-changed={}", p.display());
+            let data = fs::read(p)?;
+            let mtime = fs::metadata(p)?
+                .modified()?
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            let abs = fs::canonicalize(p)?;
+            let content_type = self.content_types
+                .for_path(p)
+                .map(|v| format!("Some({:?})", v.to_str().unwrap()))
+                .unwrap_or_else(|| "None".to_owned());
+            let gzip = if self.gzip {
+                self.variant(out_dir, name, i, "gz", &data, gzip(&data)?)?
+            } else {
+                "None".to_owned()
+            };
+            let brotli = if self.brotli {
+                self.variant(out_dir, name, i, "br", &data, brotli(&data)?)?
+            } else {
+                "None".to_owned()
+            };
+            write!(
+                src,
+                "            ::http_serve::EmbeddedFile {{\n\
+                 \x20               path: {:?},\n\
+                 \x20               data: include_bytes!({:?}),\n\
+                 \x20               etag: {:?},\n\
+                 \x20               content_type: {},\n\
+                 \x20               last_modified: {},\n\
+                 \x20               gzip: {},\n\
+                 \x20               brotli: {},\n\
+                 \x20           }},\n",
+                rel,
+                abs.to_str().expect("non-UTF-8 path"),
+                etag_for_bytes(&data).to_str().unwrap(),
+                content_type,
+                mtime,
+                gzip,
+                brotli
+            ).unwrap();
+        }
+        src.push_str("        ],\n    };\n");
+        println!("cargo:rerun-if-changed={}", self.root.display());
+        File::create(out)?.write_all(src.as_bytes())
+    }
+
+    /// Writes a compressed variant and returns the source for its `Option<EmbeddedVariant>`.
+    fn variant(
+        &self,
+        out_dir: &Path,
+        name: &str,
+        i: usize,
+        ext: &str,
+        data: &[u8],
+        compressed: Vec<u8>,
+    ) -> Result<String, io::Error> {
+        if compressed.len() >= data.len() {
+            return Ok("None".to_owned());
+        }
+        let p = out_dir.join(format!("{}.{}.{}", name, i, ext));
+        File::create(&p)?.write_all(&compressed)?;
+        let p = fs::canonicalize(&p)?;
+        Ok(format!(
+            "Some(::http_serve::EmbeddedVariant {{ data: include_bytes!({:?}), etag: {:?} }})",
+            p.to_str().expect("non-UTF-8 path"),
+            etag_for_bytes(&compressed).to_str().unwrap()
+        ))
+    }
+}
+
+/// Appends the paths of all regular files under `dir` to `out`, following symbolic links.
+fn walk(dir: &Path, out: &mut Vec<PathBuf>) -> Result<(), io::Error> {
+    for e in fs::read_dir(dir)? {
+        let p = e?.path();
+        let m = fs::metadata(&p)?;
+        if m.is_dir() {
+            walk(&p, out)?;
+        } else if m.is_file() {
+            out.push(p);
+        }
+    }
+    Ok(())
+}
+
+fn gzip(data: &[u8]) -> Result<Vec<u8>, io::Error> {
+    let mut e = ::flate2::GzBuilder::new()
+        .mtime(0)
+        .write(Vec::new(), ::flate2::Compression::best());
+    e.write_all(data)?;
+    e.finish()
+}
+
+#[cfg(feature = "brotli")]
+fn brotli(data: &[u8]) -> Result<Vec<u8>, io::Error> {
+    let mut out = Vec::new();
+    {
+        let mut w = ::brotli::CompressorWriter::new(&mut out, 4096, 11, 22);
+        w.write_all(data)?;
+    }
+    Ok(out)
+}
+
+#[cfg(not(feature = "brotli"))]
+fn brotli(_data: &[u8]) -> Result<Vec<u8>, io::Error> {
+    unreachable!("brotli variants require the brotli feature")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Embed, EmbeddedDir, EmbeddedFile, EmbeddedVariant};
+    use crate::{Body, BoxError as E, Entity};
+    use bytes::Bytes;
+    use futures::executor::block_on;
+    use http::header::{self, HeaderMap, HeaderValue};
+    use http::{Request, StatusCode};
+    use http_body_util::BodyExt;
+    use std::fs::{self, File};
+    use std::io::{Read, Write};
+    use tempdir::TempDir;
+
+    static DIR: EmbeddedDir = EmbeddedDir {
+        files: &[
+            EmbeddedFile {
+                path: "a.txt",
+                data: b"aaaa",
+                etag: "\"a\"",
+                content_type: Some("text/plain; charset=utf-8"),
+                last_modified: 1_000_000_000,
+                gzip: Some(EmbeddedVariant {
+                    data: b"gz",
+                    etag: "\"a-gz\"",
+                }),
+                brotli: None,
+            },
+            EmbeddedFile {
+                path: "sub/index.html",
+                data: b"index",
+                etag: "\"i\"",
+                content_type: None,
+                last_modified: 0,
+                gzip: None,
+                brotli: None,
+            },
+        ],
+    };
+
+    fn body(resp: ::http::Response<Body>) -> Vec<u8> {
+        block_on(resp.into_body().collect()).unwrap().to_bytes().to_vec()
+    }
+
+    #[test]
+    fn runtime() {
+        assert_eq!("a.txt", DIR.get("/a.txt").unwrap().path);
+        assert!(DIR.get("b.txt").is_none());
+
+        let e = DIR.get("a.txt").unwrap().entity::<Bytes, E>(&HeaderMap::new());
+        assert_eq!(e.etag().unwrap(), "\"a\"");
+        let mut h = HeaderMap::new();
+        e.add_headers(&mut h);
+        assert_eq!(h.get(header::CONTENT_TYPE).unwrap(), "text/plain; charset=utf-8");
+        assert!(h.get(header::CONTENT_ENCODING).is_none());
+
+        let req = Request::get("/a.txt")
+            .header(header::ACCEPT_ENCODING, "gzip")
+            .body(())
+            .unwrap();
+        let resp = DIR.serve::<Bytes, E, Body, _>(&req);
+        assert_eq!(StatusCode::OK, resp.status());
+        assert_eq!(resp.headers().get(header::CONTENT_ENCODING).unwrap(), "gzip");
+        assert_eq!(resp.headers().get(header::ETAG).unwrap(), "\"a-gz\"");
+        assert_eq!(resp.headers().get(header::VARY).unwrap(), "accept-encoding");
+        assert_eq!(b"gz", &body(resp)[..]);
+
+        let req = Request::get("/sub/").body(()).unwrap();
+        let resp = DIR.serve::<Bytes, E, Body, _>(&req);
+        assert_eq!(StatusCode::OK, resp.status());
+        assert!(resp.headers().get(header::VARY).is_none());
+        assert_eq!(b"index", &body(resp)[..]);
+
+        let req = Request::get("/missing").body(()).unwrap();
+        let resp = DIR.serve::<Bytes, E, Body, _>(&req);
+        assert_eq!(StatusCode::NOT_FOUND, resp.status());
+
+        let req = Request::get("/a.txt")
+            .header(header::IF_NONE_MATCH, HeaderValue::from_static("\"a\""))
+            .body(())
+            .unwrap();
+        let resp = DIR.serve::<Bytes, E, Body, _>(&req);
+        assert_eq!(StatusCode::NOT_MODIFIED, resp.status());
+    }
+
+    #[test]
+    fn generate() {
+        let tmp = TempDir::new("http-embed").unwrap();
+        let root = tmp.path().join("root");
+        fs::create_dir_all(root.join("js")).unwrap();
+        let big = b"repetitive ".repeat(100);
+        File::create(root.join("index.html"))
+            .unwrap()
+            .write_all(&big)
+            .unwrap();
+        File::create(root.join("js/app.js"))
+            .unwrap()
+            .write_all(b"x")
+            .unwrap();
+        let out = tmp.path().join("assets.rs");
+        Embed::new(&root).with_brotli(false).write(&out, "ASSETS").unwrap();
+
+        let mut src = String::new();
+        File::open(&out).unwrap().read_to_string(&mut src).unwrap();
+        assert!(src.starts_with("pub static ASSETS: ::http_serve::EmbeddedDir ="));
+        let index = src.find("path: \"index.html\"").unwrap();
+        let app = src.find("path: \"js/app.js\"").unwrap();
+        assert!(index < app);
+        assert!(src.contains("content_type: Some(\"text/html; charset=utf-8\")"));
+        assert!(src.contains("content_type: Some(\"text/javascript; charset=utf-8\")"));
+
+        // index.html compresses well; app.js doesn't.
+        let gz = tmp.path().join("ASSETS.0.gz");
+        assert!(fs::metadata(&gz).unwrap().len() < big.len() as u64);
+        assert!(!tmp.path().join("ASSETS.1.gz").exists());
+        assert_eq!(2, src.matches("brotli: None").count());
+        assert_eq!(1, src.matches("gzip: None").count());
+    }
+}
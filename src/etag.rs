@@ -38,7 +38,7 @@ struct List<'a> {
 }
 
 impl<'a> List<'a> {
-    fn from(l: &[u8]) -> List {
+    fn from(l: &[u8]) -> List<'_> {
         List {
             remaining: l,
             corrupt: false,
@@ -84,7 +84,7 @@ impl<'a> Iterator for List<'a> {
             }
         }
         self.remaining = rem;
-        return Some(etag);
+        Some(etag)
     }
 }
 
@@ -6,76 +6,352 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use Entity;
+use crate::content_etag::ContentEtagCache;
+use crate::Entity;
 use bytes::Buf;
-use futures::{Sink, Stream};
-use futures_cpupool::CpuPool;
+use futures_core::Stream;
+use futures_util::stream::{BoxStream, StreamExt};
 use http::header::{HeaderMap, HeaderValue};
+use std::collections::VecDeque;
+use std::future::Future;
 use std::io;
 use std::ops::Range;
 use std::os::unix::fs::{FileExt, MetadataExt};
+use std::pin::Pin;
 use std::sync::Arc;
+use std::task::{Context, Poll};
 use std::time::{self, SystemTime};
+use tokio::task::JoinHandle;
 
-// This stream breaks apart the file into chunks of at most CHUNK_SIZE. This size is
+// This stream breaks apart the file into chunks of at most CHUNK_SIZE by default. This size is
 // a tradeoff between memory usage and thread handoffs.
-static CHUNK_SIZE: u64 = 65_536;
+static CHUNK_SIZE: usize = 65_536;
 
 /// A HTTP entity created from a `std::fs::File` which reads the file
-/// chunk-by-chunk on a `CpuPool`.
-#[derive(Clone)]
+/// chunk-by-chunk via `tokio::task::spawn_blocking`.
+///
+/// The file should not be modified while being served. If it's truncated or its length or
+/// modification time otherwise changes, the body stream ends in an error rather than silently
+/// sending a short or inconsistent body, so that hyper aborts the connection.
 pub struct ChunkedReadFile<
     D: 'static + Send + Buf + From<Vec<u8>> + From<&'static [u8]>,
-    E: 'static + Send + Into<Box<::std::error::Error + Send + Sync>> + From<Box<::std::io::Error>>,
+    E: 'static + Send + Into<Box<dyn ::std::error::Error + Send + Sync>> + From<Box<io::Error>>,
 > {
-    inner: Arc<ChunkedReadFileInner>,
+    pub(crate) inner: Arc<ChunkedReadFileInner>,
     phantom: ::std::marker::PhantomData<(D, E)>,
 }
 
-struct ChunkedReadFileInner {
+impl<D, E> Clone for ChunkedReadFile<D, E>
+where
+    D: 'static + Send + Buf + From<Vec<u8>> + From<&'static [u8]>,
+    E: 'static + Send + Into<Box<dyn ::std::error::Error + Send + Sync>> + From<Box<io::Error>>,
+{
+    fn clone(&self) -> Self {
+        ChunkedReadFile {
+            inner: Arc::clone(&self.inner),
+            phantom: ::std::marker::PhantomData,
+        }
+    }
+}
+
+pub(crate) struct ChunkedReadFileInner {
     len: u64,
     inode: u64,
     mtime: SystemTime,
-    f: ::std::fs::File,
-    pool: Option<CpuPool>,
+    pub(crate) f: ::std::fs::File,
     headers: HeaderMap,
+    chunk_size: usize,
+    read_ahead: usize,
+    fadvise: bool,
+
+    /// An etag supplied at construction time, overriding the default one.
+    etag: Option<HeaderValue>,
 }
 
-impl<D, E> ChunkedReadFile<D, E>
-where
-    D: 'static + Send + Buf + From<Vec<u8>> + From<&'static [u8]>,
-    E: 'static + Send + Into<Box<::std::error::Error + Send + Sync>> + From<Box<::std::io::Error>>,
-{
-    /// Creates a new ChunkedReadFile.
+impl ChunkedReadFileInner {
+    /// Returns an error if the file's length or modification time has changed since
+    /// construction, meaning that bytes already read may not match the advertised entity.
+    fn check_unmodified(&self) -> Result<(), io::Error> {
+        let m = self.f.metadata()?;
+        if m.len() != self.len || m.modified()? != self.mtime {
+            return Err(io::Error::other("file was modified while being served"));
+        }
+        Ok(())
+    }
+
+    /// Reads exactly the given range, failing if the file is shorter.
+    fn read_chunk(&self, range: Range<u64>) -> Result<Vec<u8>, io::Error> {
+        let mut chunk = vec![0u8; (range.end - range.start) as usize];
+        let mut filled = 0;
+        while filled < chunk.len() {
+            match self.f.read_at(&mut chunk[filled..], range.start + filled as u64) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "file is shorter than expected",
+                    ))
+                }
+                Ok(n) => filled += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(chunk)
+    }
+}
+
+/// A builder for a `ChunkedReadFile` with non-default read parameters, as returned by
+/// `ChunkedReadFile::builder`.
+///
+/// The defaults (64 KiB chunks, no read-ahead, no `posix_fadvise`) suit many small concurrent
+/// requests. Large sequential downloads over fast links may benefit from larger chunks and
+/// read-ahead, which reduce the number of handoffs between the blocking threads and the tokio
+/// reactor.
+pub struct ChunkedReadFileBuilder {
+    file: ::std::fs::File,
+    headers: HeaderMap,
+    chunk_size: usize,
+    read_ahead: usize,
+    fadvise: bool,
+    content_etag: Option<ContentEtagCache>,
+}
+
+impl ChunkedReadFileBuilder {
+    /// Sets the maximum size of each chunk, and thus of each `read(2)` call.
+    pub fn with_chunk_size(self, chunk_size: usize) -> Self {
+        assert!(chunk_size > 0);
+        ChunkedReadFileBuilder { chunk_size, ..self }
+    }
+
+    /// Sets how many chunks may be read ahead of the consumer.
     ///
-    /// `read(2)` calls will be performed on the supplied `pool` so that they don't block the
-    /// tokio reactor thread on local disk I/O. Note that `File::open` and this constructor
-    /// (specifically, its call to `fstat(2)`) may also block, so they typically shouldn't be
-    /// called on the tokio reactor either.
-    pub fn new(
-        file: ::std::fs::File,
-        pool: Option<CpuPool>,
-        headers: HeaderMap,
-    ) -> Result<Self, io::Error> {
-        let m = file.metadata()?;
+    /// Each request's stream may have this many reads in progress or buffered in addition to the
+    /// one being handed off, so memory use per request is roughly `(read_ahead + 1) * chunk_size`.
+    pub fn with_read_ahead(self, read_ahead: usize) -> Self {
+        ChunkedReadFileBuilder { read_ahead, ..self }
+    }
+
+    /// Sets whether `posix_fadvise(2)` is called with `POSIX_FADV_SEQUENTIAL` and
+    /// `POSIX_FADV_WILLNEED` for each requested range before reading it.
+    ///
+    /// This asks the kernel to read ahead aggressively. It's only supported on Linux; elsewhere
+    /// this has no effect.
+    pub fn with_fadvise(self, fadvise: bool) -> Self {
+        ChunkedReadFileBuilder { fadvise, ..self }
+    }
+
+    /// Uses a strong etag derived from the file's contents, looked up in or added to `cache`,
+    /// rather than the default one derived from its inode number and modification time.
+    ///
+    /// If the etag isn't cached, `build` reads the entire file to compute it; use `build_async` to
+    /// do so without blocking the tokio reactor. The cache holds a bounded number of etags, so
+    /// a long-lived cache may be shared by all requests; see `ContentEtagCache::with_capacity`.
+    pub fn with_content_etag(self, cache: &ContentEtagCache) -> Self {
+        ChunkedReadFileBuilder {
+            content_etag: Some(cache.clone()),
+            ..self
+        }
+    }
+
+    /// Builds the `ChunkedReadFile`, calling `fstat(2)` as in `ChunkedReadFile::new` and hashing
+    /// the file if requested by `with_content_etag`.
+    pub fn build<D, E>(self) -> Result<ChunkedReadFile<D, E>, io::Error>
+    where
+        D: 'static + Send + Buf + From<Vec<u8>> + From<&'static [u8]>,
+        E: 'static + Send + Into<Box<dyn ::std::error::Error + Send + Sync>> + From<Box<io::Error>>,
+    {
+        let m = self.file.metadata()?;
+        let etag = match self.content_etag {
+            Some(ref c) => Some(c.etag_for(&self.file, &m)?),
+            None => None,
+        };
         Ok(ChunkedReadFile {
             inner: Arc::new(ChunkedReadFileInner {
                 len: m.len(),
                 inode: m.ino(),
                 mtime: m.modified()?,
-                headers,
-                f: file,
-                pool: pool,
+                headers: self.headers,
+                f: self.file,
+                chunk_size: self.chunk_size,
+                read_ahead: self.read_ahead,
+                fadvise: self.fadvise,
+                etag,
             }),
             phantom: ::std::marker::PhantomData,
         })
     }
+
+    /// Builds the `ChunkedReadFile` as in `build`, performing the `fstat(2)` and any hashing via
+    /// `tokio::task::spawn_blocking`.
+    pub async fn build_async<D, E>(self) -> Result<ChunkedReadFile<D, E>, io::Error>
+    where
+        D: 'static + Send + Buf + From<Vec<u8>> + From<&'static [u8]>,
+        E: 'static + Send + Into<Box<dyn ::std::error::Error + Send + Sync>> + From<Box<io::Error>>,
+    {
+        tokio::task::spawn_blocking(move || self.build())
+            .await
+            .map_err(io::Error::other)?
+    }
+}
+
+/// Advises the kernel that `range` of `f` will be read sequentially soon.
+#[cfg(target_os = "linux")]
+fn fadvise(f: &::std::fs::File, range: &Range<u64>) {
+    use std::os::unix::io::AsRawFd;
+    let fd = f.as_raw_fd();
+    let off = range.start as ::libc::off_t;
+    let len = (range.end - range.start) as ::libc::off_t;
+
+    // The advice is only a hint, so failures are ignored.
+    unsafe {
+        ::libc::posix_fadvise(fd, off, len, ::libc::POSIX_FADV_SEQUENTIAL);
+        ::libc::posix_fadvise(fd, off, len, ::libc::POSIX_FADV_WILLNEED);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn fadvise(_f: &::std::fs::File, _range: &Range<u64>) {}
+
+impl<D, E> ChunkedReadFile<D, E>
+where
+    D: 'static + Send + Buf + From<Vec<u8>> + From<&'static [u8]>,
+    E: 'static + Send + Into<Box<dyn ::std::error::Error + Send + Sync>> + From<Box<io::Error>>,
+{
+    /// Creates a new ChunkedReadFile.
+    ///
+    /// `read(2)` calls will be performed via `tokio::task::spawn_blocking` so that they don't
+    /// block the tokio reactor on local disk I/O; thus the body must be polled within a tokio
+    /// runtime. Note that `File::open` and this constructor (specifically, its call to `fstat(2)`)
+    /// may also block, so they typically shouldn't be called on the tokio reactor either.
+    pub fn new(file: ::std::fs::File, headers: HeaderMap) -> Result<Self, io::Error> {
+        Self::builder(file, headers).build()
+    }
+
+    /// Returns a builder for a ChunkedReadFile with non-default chunk size, read-ahead, or
+    /// `posix_fadvise` settings. The arguments are as in `new`.
+    pub fn builder(file: ::std::fs::File, headers: HeaderMap) -> ChunkedReadFileBuilder {
+        ChunkedReadFileBuilder {
+            file,
+            headers,
+            chunk_size: CHUNK_SIZE,
+            read_ahead: 0,
+            fadvise: false,
+            content_etag: None,
+        }
+    }
+}
+
+/// The stream returned by `ChunkedReadFile::get_range`.
+struct ChunkStream<D, E> {
+    inner: Arc<ChunkedReadFileInner>,
+
+    /// The portion of the range for which no read has been started yet.
+    left: Range<u64>,
+
+    /// Started reads, in order. Each produces a chunk, or `None` after checking that the file
+    /// hasn't been modified once the range has been read.
+    pending: VecDeque<JoinHandle<Result<Option<Vec<u8>>, io::Error>>>,
+
+    /// The final chunk, held back until the check passes. Once the body's last byte is sent, the
+    /// response is complete, so a later error couldn't tell the client it was inconsistent.
+    held: Option<Vec<u8>>,
+
+    /// True iff the final check has been started (or the stream has failed).
+    checked: bool,
+
+    /// True iff `posix_fadvise` should be called before the next read.
+    advise: bool,
+    phantom: ::std::marker::PhantomData<fn() -> (D, E)>,
+}
+
+impl<D, E> ChunkStream<D, E> {
+    /// Starts reads until `read_ahead + 1` are in progress or the range is exhausted.
+    fn start_reads(&mut self) {
+        while !self.checked
+            && self.left.start < self.left.end
+            && self.pending.len() <= self.inner.read_ahead
+        {
+            let inner = Arc::clone(&self.inner);
+            let advise = if self.advise {
+                self.advise = false;
+                Some(self.left.clone())
+            } else {
+                None
+            };
+            let end = ::std::cmp::min(self.left.start + inner.chunk_size as u64, self.left.end);
+            let chunk = self.left.start..end;
+            self.left.start = end;
+            self.pending.push_back(tokio::task::spawn_blocking(move || {
+                if let Some(ref r) = advise {
+                    fadvise(&inner.f, r);
+                }
+                inner.read_chunk(chunk).map(Some)
+            }));
+        }
+    }
+
+    /// Starts checking that the bytes sent match the length and etag sent in the headers. This
+    /// must happen after all reads have finished.
+    fn start_check(&mut self) {
+        let inner = Arc::clone(&self.inner);
+        self.checked = true;
+        self.pending.push_back(tokio::task::spawn_blocking(move || {
+            inner.check_unmodified().map(|()| None)
+        }));
+    }
+}
+
+impl<D, E> Stream for ChunkStream<D, E>
+where
+    D: From<Vec<u8>>,
+    E: From<Box<io::Error>>,
+{
+    type Item = Result<D, E>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<D, E>>> {
+        let this = self.get_mut();
+        loop {
+            this.start_reads();
+            let r = match this.pending.front_mut() {
+                None if this.checked => return Poll::Ready(None),
+                None => {
+                    // An empty range.
+                    this.start_check();
+                    continue;
+                }
+                Some(h) => match Pin::new(h).poll(cx) {
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(r) => r,
+                },
+            };
+            this.pending.pop_front();
+            let e = match r {
+                Ok(Ok(Some(chunk))) => {
+                    if this.left.start == this.left.end && this.pending.is_empty() {
+                        this.held = Some(chunk);
+                        this.start_check();
+                        continue;
+                    }
+                    return Poll::Ready(Some(Ok(chunk.into())));
+                }
+                Ok(Ok(None)) => return Poll::Ready(this.held.take().map(|c| Ok(c.into()))),
+                Ok(Err(e)) => e,
+                Err(e) => io::Error::other(e),
+            };
+
+            // End the stream after the error; reads still in progress are abandoned.
+            this.pending.clear();
+            this.held = None;
+            this.checked = true;
+            return Poll::Ready(Some(Err(Box::new(e).into())));
+        }
+    }
 }
 
 impl<D, E> Entity for ChunkedReadFile<D, E>
 where
     D: 'static + Send + Buf + From<Vec<u8>> + From<&'static [u8]>,
-    E: 'static + Send + Into<Box<::std::error::Error + Send + Sync>> + From<Box<::std::io::Error>>,
+    E: 'static + Send + Into<Box<dyn ::std::error::Error + Send + Sync>> + From<Box<io::Error>>,
 {
     type Data = D;
     type Error = E;
@@ -84,41 +360,16 @@ where
         self.inner.len
     }
 
-    fn get_range(
-        &self,
-        range: Range<u64>,
-    ) -> Box<Stream<Item = Self::Data, Error = Self::Error> + Send> {
-        let stream =
-            ::futures::stream::unfold((range, Arc::clone(&self.inner)), move |(left, inner)| {
-                if left.start == left.end {
-                    return None;
-                }
-                let chunk_size = ::std::cmp::min(CHUNK_SIZE, left.end - left.start) as usize;
-                let mut chunk = Vec::with_capacity(chunk_size);
-                unsafe { chunk.set_len(chunk_size) };
-                let bytes_read = match inner.f.read_at(&mut chunk, left.start) {
-                    Err(e) => return Some(Err(Box::new(e).into())),
-                    Ok(b) => b,
-                };
-                chunk.truncate(bytes_read);
-                Some(Ok((
-                    chunk.into(),
-                    (left.start + bytes_read as u64..left.end, inner),
-                )))
-            });
-
-        let stream: Box<Stream<Item = D, Error = E> + Send> = match self.inner.pool {
-            Some(ref p) => {
-                let (snd, rcv) = ::futures::sync::mpsc::channel(0);
-                p.spawn(snd.send_all(stream.then(Ok))).forget();
-                Box::new(
-                    rcv.map_err(|()| unreachable!())
-                        .and_then(::futures::future::result),
-                )
-            }
-            None => Box::new(stream),
-        };
-        stream.into()
+    fn get_range(&self, range: Range<u64>) -> BoxStream<'static, Result<D, E>> {
+        ChunkStream {
+            inner: Arc::clone(&self.inner),
+            left: range,
+            pending: VecDeque::new(),
+            held: None,
+            checked: false,
+            advise: self.inner.fadvise,
+            phantom: ::std::marker::PhantomData,
+        }.boxed()
     }
 
     fn add_headers(&self, h: &mut HeaderMap) {
@@ -131,26 +382,10 @@ where
     }
 
     fn etag(&self) -> Option<HeaderValue> {
-        // This etag format is similar to Apache's. The etag should change if the file is modified
-        // or replaced. The length is probably redundant but doesn't harm anything.
-        let dur = self.inner
-            .mtime
-            .duration_since(time::UNIX_EPOCH)
-            .expect("modification time must be after epoch");
-
-        // Rust doesn't seem to understand these lengths are used in the macro invocation.
-        #[allow(dead_code)]
-        static HEX_U64_LEN: usize = 16;
-        #[allow(dead_code)]
-        static HEX_U32_LEN: usize = 16;
-        Some(fmt_ascii_val!(
-            HEX_U64_LEN * 3 + HEX_U64_LEN + 5,
-            "\"{:x}:{:x}:{:x}:{:x}\"",
-            self.inner.inode,
-            self.inner.len,
-            dur.as_secs(),
-            dur.subsec_nanos()
-        ))
+        if let Some(ref e) = self.inner.etag {
+            return Some(e.clone());
+        }
+        Some(etag(self.inner.inode, self.inner.len, self.inner.mtime))
     }
 
     fn last_modified(&self) -> Option<SystemTime> {
@@ -158,57 +393,153 @@ where
     }
 }
 
+/// Returns an etag for a file with the given inode number, length, and modification time.
+pub(crate) fn etag(inode: u64, len: u64, mtime: SystemTime) -> HeaderValue {
+    // This etag format is similar to Apache's. The etag should change if the file is modified
+    // or replaced. The length is probably redundant but doesn't harm anything.
+    let dur = mtime
+        .duration_since(time::UNIX_EPOCH)
+        .expect("modification time must be after epoch");
+
+    // Rust doesn't seem to understand these lengths are used in the macro invocation.
+    #[allow(dead_code)]
+    static HEX_U64_LEN: usize = 16;
+    #[allow(dead_code)]
+    static HEX_U32_LEN: usize = 16;
+    fmt_ascii_val!(
+        HEX_U64_LEN * 3 + HEX_U64_LEN + 5,
+        "\"{:x}:{:x}:{:x}:{:x}\"",
+        inode,
+        len,
+        dur.as_secs(),
+        dur.subsec_nanos()
+    )
+}
+
 #[cfg(test)]
 mod tests {
-    extern crate tempdir;
-
-    use self::tempdir::TempDir;
     use super::ChunkedReadFile;
     use super::Entity;
-    use futures::{Future, Stream};
-    use futures_cpupool::CpuPool;
+    use crate::content_etag::ContentEtagCache;
+    use crate::BoxError;
+    use bytes::Bytes;
+    use futures::stream::{StreamExt, TryStreamExt};
     use http::header::HeaderMap;
-    use hyper::Chunk;
-    use std::fs::File;
+    use std::fs::{File, OpenOptions};
     use std::io::Write;
+    use tempdir::TempDir;
 
-    type CRF = ChunkedReadFile<Chunk, Box<::std::error::Error + Sync + Send>>;
+    type Crf = ChunkedReadFile<Bytes, BoxError>;
 
-    fn basic_tests(pool: Option<CpuPool>) {
+    #[tokio::test]
+    async fn basic() {
         let tmp = TempDir::new("http-file").unwrap();
         let p = tmp.path().join("f");
         let mut f = File::create(&p).unwrap();
         f.write_all(b"asdf").unwrap();
 
-        let crf = CRF::new(File::open(&p).unwrap(), pool.clone(), HeaderMap::new()).unwrap();
+        let crf = Crf::new(File::open(&p).unwrap(), HeaderMap::new()).unwrap();
         assert_eq!(4, crf.len());
         let etag1 = crf.etag();
 
         // Test returning part/all of the stream.
-        assert_eq!(
-            &crf.get_range(0..4).concat2().wait().unwrap().as_ref(),
-            b"asdf"
-        );
-        assert_eq!(
-            &crf.get_range(1..3).concat2().wait().unwrap().as_ref(),
-            b"sd"
-        );
+        assert_eq!(&crate::body_bytes(crf.get_range(0..4)).await.unwrap(), b"asdf");
+        assert_eq!(&crate::body_bytes(crf.get_range(1..3)).await.unwrap(), b"sd");
 
         // A ChunkedReadFile constructed from a modified file should have a different etag.
         f.write_all(b"jkl;").unwrap();
-        let crf = CRF::new(File::open(&p).unwrap(), pool, HeaderMap::new()).unwrap();
+        let crf = Crf::new(File::open(&p).unwrap(), HeaderMap::new()).unwrap();
         assert_eq!(8, crf.len());
         let etag2 = crf.etag();
         assert_ne!(etag1, etag2);
     }
 
-    #[test]
-    fn with_pool() {
-        basic_tests(Some(CpuPool::new(1)));
+    #[tokio::test]
+    async fn modified() {
+        let tmp = TempDir::new("http-file").unwrap();
+        let p = tmp.path().join("f");
+        File::create(&p).unwrap().write_all(b"asdf").unwrap();
+
+        // Truncation is detected as a short read.
+        let crf = Crf::new(File::open(&p).unwrap(), HeaderMap::new()).unwrap();
+        File::create(&p).unwrap().write_all(b"as").unwrap();
+        let e = crate::body_bytes(crf.get_range(0..4)).await.unwrap_err();
+        assert!(e.to_string().contains("shorter"), "{}", e);
+
+        // Other modifications are detected after reading, before the final chunk is sent.
+        let crf: Crf = Crf::builder(File::open(&p).unwrap(), HeaderMap::new())
+            .with_chunk_size(1)
+            .build()
+            .unwrap();
+        OpenOptions::new()
+            .append(true)
+            .open(&p)
+            .unwrap()
+            .write_all(b"df")
+            .unwrap();
+        let mut s = crf.get_range(0..2);
+        assert_eq!(b"a", &s.next().await.unwrap().unwrap()[..]);
+        let e = s.next().await.unwrap().unwrap_err();
+        assert!(e.to_string().contains("modified"), "{}", e);
+        assert!(s.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn builder() {
+        let tmp = TempDir::new("http-file").unwrap();
+        let p = tmp.path().join("f");
+        let data: Vec<u8> = (0..10_000).map(|i| i as u8).collect();
+        File::create(&p).unwrap().write_all(&data).unwrap();
+
+        let crf: Crf = Crf::builder(File::open(&p).unwrap(), HeaderMap::new())
+            .with_chunk_size(4096)
+            .with_read_ahead(2)
+            .with_fadvise(true)
+            .build()
+            .unwrap();
+        let chunks: Vec<Bytes> = crf.get_range(0..10_000).try_collect().await.unwrap();
+        let lens: Vec<usize> = chunks.iter().map(|c| c.len()).collect();
+        assert_eq!(&[4096, 4096, 1808], &lens[..]);
+        let body: Vec<u8> = chunks.iter().flat_map(|c| c.iter().cloned()).collect();
+        assert_eq!(data, body);
+
+        let chunks: Vec<Bytes> = crf.get_range(100..5000).try_collect().await.unwrap();
+        let lens: Vec<usize> = chunks.iter().map(|c| c.len()).collect();
+        assert_eq!(&[4096, 804], &lens[..]);
+        assert!(crate::body_bytes(crf.get_range(5..5)).await.unwrap().is_empty());
     }
 
     #[test]
-    fn without_pool() {
-        basic_tests(None);
+    fn content_etag() {
+        let tmp = TempDir::new("http-file").unwrap();
+        let a = tmp.path().join("a");
+        let b = tmp.path().join("b");
+        File::create(&a).unwrap().write_all(b"asdf").unwrap();
+        File::create(&b).unwrap().write_all(b"asdf").unwrap();
+        let cache = ContentEtagCache::new();
+        let open = |p| -> Crf {
+            Crf::builder(File::open(p).unwrap(), HeaderMap::new())
+                .with_content_etag(&cache)
+                .build()
+                .unwrap()
+        };
+        let (fa, fb) = (open(&a), open(&b));
+        assert_eq!(fa.etag(), fb.etag());
+        assert_ne!(fa.etag(), Crf::new(File::open(&a).unwrap(), HeaderMap::new())
+            .unwrap()
+            .etag());
+    }
+
+    #[tokio::test]
+    async fn content_etag_async() {
+        let tmp = TempDir::new("http-file").unwrap();
+        let p = tmp.path().join("f");
+        File::create(&p).unwrap().write_all(b"asdf").unwrap();
+        let crf: Crf = Crf::builder(File::open(&p).unwrap(), HeaderMap::new())
+            .with_content_etag(&ContentEtagCache::new())
+            .build_async()
+            .await
+            .unwrap();
+        assert_eq!(Some(crate::content_etag::etag_for_bytes(b"asdf")), crf.etag());
     }
 }
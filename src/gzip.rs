@@ -6,12 +6,13 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use chunker;
+use crate::chunker;
 use std::io::{self, Write};
 use std::mem;
 
 /// A `std::io::Write` implementation that makes a chunked hyper response body stream.
-/// Automatically applies `gzip` content encoding if requested by the client.
+/// Automatically applies `gzip`, `br`, or `zstd` content encoding if requested by the client and
+/// supported by the server (see `StreamingBodyBuilder`).
 ///
 /// The stream is infinitely buffered; calls to `write` and `flush` never block. `flush` thus is a
 /// hint that data should be sent to the client as soon as possible, but this shouldn't be expected
@@ -23,14 +24,15 @@ use std::mem;
 /// or blocking. Many applications anyway produce output while holding a lock or database
 /// transaction that should finish quickly, so backpressure must be ignored anyway.
 ///
-/// On drop, the stream will be "finished" (for gzip, this writes a special footer). There's no way
-/// to know the complete stream was written successfully. It's inherent in the combination of
-/// HTTP / TCP / Unix sockets / hyper anyway that only the client knows this.
+/// On drop, the stream will be "finished" (for the compressed codings, this writes a special
+/// footer). There's no way to know the complete stream was written successfully. It's inherent in
+/// the combination of HTTP / TCP / Unix sockets / hyper anyway that only the client knows this.
 pub struct BodyWriter<D, E>(Inner<D, E>)
 where
     D: From<Vec<u8>> + Send + 'static,
     E: Send + 'static;
 
+#[allow(clippy::large_enum_variant)]
 enum Inner<D, E>
 where
     D: From<Vec<u8>> + Send + 'static,
@@ -38,6 +40,10 @@ where
 {
     Raw(chunker::BodyWriter<D, E>),
     Gzipped(::flate2::write::GzEncoder<chunker::BodyWriter<D, E>>),
+    #[cfg(feature = "brotli")]
+    Brotli(::brotli::CompressorWriter<chunker::BodyWriter<D, E>>),
+    #[cfg(feature = "zstd")]
+    Zstd(::zstd::stream::write::AutoFinishEncoder<'static, chunker::BodyWriter<D, E>>),
 
     /// No more data should be sent. `abort()` or `drop()` has been called, or a previous call
     /// discovered that the receiver has been dropped.
@@ -57,12 +63,31 @@ where
         BodyWriter(Inner::Gzipped(::flate2::GzBuilder::new().write(raw, level)))
     }
 
+    #[cfg(feature = "brotli")]
+    pub(crate) fn brotli(raw: chunker::BodyWriter<D, E>, quality: u32) -> Self {
+        // 22 is the default window size (log2) used by the brotli command-line tool.
+        BodyWriter(Inner::Brotli(::brotli::CompressorWriter::new(
+            raw, 4096, quality, 22,
+        )))
+    }
+
+    #[cfg(feature = "zstd")]
+    pub(crate) fn zstd(raw: chunker::BodyWriter<D, E>, level: i32) -> Self {
+        let e = ::zstd::stream::write::Encoder::new(raw, level)
+            .expect("zstd encoder creation should succeed with a valid level");
+        BodyWriter(Inner::Zstd(e.auto_finish()))
+    }
+
     /// Causes the HTTP connection to be dropped abruptly.
     pub fn abort(&mut self, error: E) {
         match mem::replace(&mut self.0, Inner::Dead) {
             Inner::Dead => (),
             Inner::Raw(ref mut w) => w.abort(error),
             Inner::Gzipped(ref mut g) => g.get_mut().abort(error),
+            #[cfg(feature = "brotli")]
+            Inner::Brotli(ref mut b) => b.get_mut().abort(error),
+            #[cfg(feature = "zstd")]
+            Inner::Zstd(ref mut z) => z.get_mut().abort(error),
         };
     }
 }
@@ -74,9 +99,13 @@ where
 {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         let r = match self.0 {
-            Inner::Dead => Err(io::Error::new(io::ErrorKind::BrokenPipe, "body is dead"))?,
+            Inner::Dead => return Err(io::Error::new(io::ErrorKind::BrokenPipe, "body is dead")),
             Inner::Raw(ref mut w) => w.write(buf),
             Inner::Gzipped(ref mut w) => w.write(buf),
+            #[cfg(feature = "brotli")]
+            Inner::Brotli(ref mut w) => w.write(buf),
+            #[cfg(feature = "zstd")]
+            Inner::Zstd(ref mut w) => w.write(buf),
         };
         if r.is_err() {
             self.0 = Inner::Dead;
@@ -86,9 +115,13 @@ where
 
     fn flush(&mut self) -> io::Result<()> {
         let r = match self.0 {
-            Inner::Dead => Err(io::Error::new(io::ErrorKind::BrokenPipe, "body is dead"))?,
+            Inner::Dead => return Err(io::Error::new(io::ErrorKind::BrokenPipe, "body is dead")),
             Inner::Raw(ref mut w) => w.flush(),
             Inner::Gzipped(ref mut w) => w.flush(),
+            #[cfg(feature = "brotli")]
+            Inner::Brotli(ref mut w) => w.flush(),
+            #[cfg(feature = "zstd")]
+            Inner::Zstd(ref mut w) => w.flush(),
         };
         if r.is_err() {
             self.0 = Inner::Dead;
@@ -0,0 +1,296 @@
+// Copyright (c) 2018 Scott Lamb <slamb@slamb.org>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use crate::content_etag;
+use crate::Entity;
+use bytes::{Buf, Bytes};
+use futures_util::future;
+use futures_util::stream::{self, BoxStream, StreamExt};
+use http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use std::io;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// Storage for the bytes of an `InMemoryEntity`, which can produce chunks of type `D`.
+///
+/// The storage is converted once, at construction, to a form which can be sliced without copying.
+/// The implementation for `&'static [u8]` works with any `Data` type. Those for `Bytes`,
+/// `Vec<u8>`, and `Arc<[u8]>` hold a `Bytes` and work with any `Data` type which implements
+/// `From<Bytes>`, such as `Bytes` itself.
+pub trait InMemoryStorage<D>: Send + Sync + 'static {
+    /// The form in which the entity holds the bytes.
+    type Stored: AsRef<[u8]> + Clone + Send + Sync + 'static;
+
+    /// Converts the bytes to the form in which the entity holds them.
+    fn into_stored(self) -> Self::Stored;
+
+    /// Returns the given range of the bytes as a chunk.
+    fn slice(stored: &Self::Stored, range: Range<usize>) -> D;
+}
+
+impl<D: From<&'static [u8]>> InMemoryStorage<D> for &'static [u8] {
+    type Stored = &'static [u8];
+
+    fn into_stored(self) -> Self::Stored {
+        self
+    }
+
+    fn slice(stored: &Self::Stored, range: Range<usize>) -> D {
+        D::from(&stored[range])
+    }
+}
+
+impl<D: From<Bytes>> InMemoryStorage<D> for Bytes {
+    type Stored = Bytes;
+
+    fn into_stored(self) -> Self::Stored {
+        self
+    }
+
+    fn slice(stored: &Self::Stored, range: Range<usize>) -> D {
+        D::from(stored.slice(range))
+    }
+}
+
+impl<D: From<Bytes>> InMemoryStorage<D> for Vec<u8> {
+    type Stored = Bytes;
+
+    fn into_stored(self) -> Self::Stored {
+        Bytes::from(self)
+    }
+
+    fn slice(stored: &Self::Stored, range: Range<usize>) -> D {
+        D::from(stored.slice(range))
+    }
+}
+
+impl<D: From<Bytes>> InMemoryStorage<D> for Arc<[u8]> {
+    type Stored = Bytes;
+
+    fn into_stored(self) -> Self::Stored {
+        Bytes::from_owner(self)
+    }
+
+    fn slice(stored: &Self::Stored, range: Range<usize>) -> D {
+        D::from(stored.slice(range))
+    }
+}
+
+#[derive(Clone)]
+enum Etag {
+    /// Derived from the content on first use; the result is shared between clones.
+    Content(Arc<Mutex<Option<HeaderValue>>>),
+
+    /// Supplied via `with_etag`.
+    Fixed(Option<HeaderValue>),
+}
+
+/// An entity whose bytes are held in memory.
+///
+/// By default, the entity has a strong etag derived from the SHA-256 of its contents, computed
+/// on first use and shared between clones, and no last modified time. Both can be changed, and
+/// headers added, as in the following example:
+///
+/// ```
+/// # use bytes::Bytes;
+/// # use http::header::{self, HeaderValue};
+/// # use http_serve::{BoxError, InMemoryEntity};
+/// # fn main() {
+/// static INDEX: &[u8] = b"<!DOCTYPE html><title>Hello</title>";
+/// let e: InMemoryEntity<_, Bytes, BoxError> =
+///     InMemoryEntity::new(INDEX)
+///         .with_content_type(HeaderValue::from_static("text/html; charset=utf-8"))
+///         .with_header(header::CONTENT_LANGUAGE, HeaderValue::from_static("en"));
+/// # }
+/// ```
+///
+/// Construct an entity once and clone it per request to avoid hashing its contents repeatedly.
+pub struct InMemoryEntity<S, D, E>
+where
+    S: InMemoryStorage<D>,
+    D: 'static + Send + Buf + From<Vec<u8>> + From<&'static [u8]>,
+    E: 'static + Send + Into<Box<dyn ::std::error::Error + Send + Sync>> + From<Box<io::Error>>,
+{
+    storage: S::Stored,
+    headers: HeaderMap,
+    etag: Etag,
+    last_modified: Option<SystemTime>,
+    phantom: ::std::marker::PhantomData<(D, E)>,
+}
+
+impl<S, D, E> InMemoryEntity<S, D, E>
+where
+    S: InMemoryStorage<D>,
+    D: 'static + Send + Buf + From<Vec<u8>> + From<&'static [u8]>,
+    E: 'static + Send + Into<Box<dyn ::std::error::Error + Send + Sync>> + From<Box<io::Error>>,
+{
+    /// Creates a new entity with the given contents.
+    pub fn new(storage: S) -> Self {
+        InMemoryEntity {
+            storage: storage.into_stored(),
+            headers: HeaderMap::new(),
+            etag: Etag::Content(Arc::new(Mutex::new(None))),
+            last_modified: None,
+            phantom: ::std::marker::PhantomData,
+        }
+    }
+
+    /// Sets the `Content-Type` header, replacing any existing one.
+    pub fn with_content_type(mut self, content_type: HeaderValue) -> Self {
+        self.headers.insert(header::CONTENT_TYPE, content_type);
+        self
+    }
+
+    /// Adds a header to be sent with the entity.
+    pub fn with_header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.headers.append(name, value);
+        self
+    }
+
+    /// Replaces the content-derived etag with the given one, or with none.
+    pub fn with_etag(self, etag: Option<HeaderValue>) -> Self {
+        InMemoryEntity {
+            etag: Etag::Fixed(etag),
+            ..self
+        }
+    }
+
+    /// Sets the last modified time.
+    pub fn with_last_modified(self, last_modified: SystemTime) -> Self {
+        InMemoryEntity {
+            last_modified: Some(last_modified),
+            ..self
+        }
+    }
+
+    /// Returns the entity's contents.
+    pub fn get_ref(&self) -> &[u8] {
+        self.storage.as_ref()
+    }
+}
+
+impl<S, D, E> Clone for InMemoryEntity<S, D, E>
+where
+    S: InMemoryStorage<D>,
+    D: 'static + Send + Buf + From<Vec<u8>> + From<&'static [u8]>,
+    E: 'static + Send + Into<Box<dyn ::std::error::Error + Send + Sync>> + From<Box<io::Error>>,
+{
+    fn clone(&self) -> Self {
+        InMemoryEntity {
+            storage: self.storage.clone(),
+            headers: self.headers.clone(),
+            etag: self.etag.clone(),
+            last_modified: self.last_modified,
+            phantom: ::std::marker::PhantomData,
+        }
+    }
+}
+
+impl<S, D, E> Entity for InMemoryEntity<S, D, E>
+where
+    S: InMemoryStorage<D>,
+    D: 'static + Send + Buf + From<Vec<u8>> + From<&'static [u8]>,
+    E: 'static + Send + Into<Box<dyn ::std::error::Error + Send + Sync>> + From<Box<io::Error>>,
+{
+    type Data = D;
+    type Error = E;
+
+    fn len(&self) -> u64 {
+        self.storage.as_ref().len() as u64
+    }
+
+    fn get_range(&self, range: Range<u64>) -> BoxStream<'static, Result<D, E>> {
+        if range.start == range.end {
+            return stream::empty().boxed();
+        }
+        let chunk = S::slice(&self.storage, range.start as usize..range.end as usize);
+        stream::once(future::ready(Ok(chunk))).boxed()
+    }
+
+    fn add_headers(&self, h: &mut HeaderMap) {
+        h.extend(self.headers.iter().map(|(k, v)| (k.clone(), v.clone())));
+    }
+
+    fn etag(&self) -> Option<HeaderValue> {
+        match self.etag {
+            Etag::Fixed(ref e) => e.clone(),
+            Etag::Content(ref cached) => {
+                let mut l = cached.lock().unwrap();
+                Some(
+                    l.get_or_insert_with(|| content_etag::etag_for_bytes(self.storage.as_ref()))
+                        .clone(),
+                )
+            }
+        }
+    }
+
+    fn last_modified(&self) -> Option<SystemTime> {
+        self.last_modified
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::InMemoryEntity;
+    use crate::{BoxError as E, Entity};
+    use bytes::Bytes;
+    use futures::executor::block_on;
+    use futures::StreamExt;
+    use http::header::{self, HeaderMap, HeaderValue};
+    use std::sync::Arc;
+    use std::time::UNIX_EPOCH;
+
+    fn body<T: Entity<Data = Bytes, Error = E>>(e: &T, range: ::std::ops::Range<u64>) -> Vec<u8> {
+        block_on(crate::body_bytes(e.get_range(range))).unwrap()
+    }
+
+    #[test]
+    fn storage_types() {
+        let s: InMemoryEntity<_, Bytes, E> = InMemoryEntity::new(&b"asdf"[..]);
+        let b: InMemoryEntity<_, Bytes, E> = InMemoryEntity::new(Bytes::from_static(b"asdf"));
+        let v: InMemoryEntity<_, Bytes, E> = InMemoryEntity::new(b"asdf".to_vec());
+        let a: InMemoryEntity<Arc<[u8]>, Bytes, E> = InMemoryEntity::new(Arc::from(&b"asdf"[..]));
+        assert_eq!(4, s.len());
+        assert_eq!(b"sd", &body(&s, 1..3)[..]);
+        assert_eq!(b"sd", &body(&b, 1..3)[..]);
+        assert_eq!(b"sd", &body(&v, 1..3)[..]);
+        assert_eq!(b"sd", &body(&a, 1..3)[..]);
+        assert_eq!(b"", &body(&a, 2..2)[..]);
+
+        // Chunks of owned storage share its buffer rather than copying.
+        let chunk = block_on(v.get_range(1..3).next()).unwrap().unwrap();
+        assert_eq!(v.get_ref()[1..].as_ptr(), chunk.as_ptr());
+        let chunk = block_on(a.get_range(1..3).next()).unwrap().unwrap();
+        assert_eq!(a.get_ref()[1..].as_ptr(), chunk.as_ptr());
+
+        // The content-derived etag is the same regardless of storage.
+        assert!(s.etag().is_some());
+        assert_eq!(s.etag(), b.etag());
+        assert_eq!(s.etag(), v.etag());
+        assert_eq!(s.etag(), a.etag());
+        let other: InMemoryEntity<_, Bytes, E> = InMemoryEntity::new(&b"asdg"[..]);
+        assert_ne!(s.etag(), other.etag());
+    }
+
+    #[test]
+    fn builder() {
+        let e: InMemoryEntity<_, Bytes, E> = InMemoryEntity::new(&b"asdf"[..])
+            .with_content_type(HeaderValue::from_static("text/plain"))
+            .with_header(header::CONTENT_LANGUAGE, HeaderValue::from_static("en"))
+            .with_etag(Some(HeaderValue::from_static("\"x\"")))
+            .with_last_modified(UNIX_EPOCH);
+        let mut h = HeaderMap::new();
+        e.add_headers(&mut h);
+        assert_eq!(h.get(header::CONTENT_TYPE).unwrap(), "text/plain");
+        assert_eq!(h.get(header::CONTENT_LANGUAGE).unwrap(), "en");
+        assert_eq!(e.etag().unwrap(), "\"x\"");
+        assert_eq!(Some(UNIX_EPOCH), e.last_modified());
+        assert_eq!(None, e.clone().with_etag(None).etag());
+    }
+}
@@ -6,9 +6,10 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-//! Helpers for serving HTTP GET and HEAD responses with [hyper](https://crates.io/crates/hyper)
-//! 0.11.x and [tokio](https://crates.io/crates/tokio). A future version is likely to switch to
-//! the interface of the [http](http://crates.io/crates/http) crate.
+//! Helpers for serving HTTP GET and HEAD responses asynchronously with the
+//! [http](http://crates.io/crates/http) crate and [tokio](https://crates.io/crates/tokio). Response
+//! bodies implement [http-body](https://crates.io/crates/http-body) 1.0's `Body` trait, so they can
+//! be returned directly from [hyper](https://crates.io/crates/hyper) 1.x services.
 //!
 //! This crate supplies two ways to respond to HTTP GET and HEAD requests:
 //!
@@ -28,9 +29,9 @@
 //!   <tr><th><th><code>serve</code><th><code>streaming_body</code></tr>
 //!   <tr><td>automatic byte range serving<td>yes<td>no (always sends full body)</tr>
 //!   <tr><td>backpressure<td>yes<td>no</tr>
-//!   <tr><td>conditional GET<td>yes<td>unimplemented (always sends body)</tr>
+//!   <tr><td>conditional GET<td>yes<td>yes (when an etag or last modified time is supplied)</tr>
 //!   <tr><td>sends first byte before length known<td>no<td>yes</tr>
-//!   <tr><td>automatic gzip content encoding<td>no<td>yes</tr>
+//!   <tr><td>automatic content encoding (gzip; optionally brotli and zstd)<td>no<td>yes</tr>
 //! </table>
 //!
 //! Use `serve` when:
@@ -43,40 +44,29 @@
 //!
 //! Consider `streaming_body` if data would be fully buffered each time a response is sent.
 //!
-//! Once you return a `hyper::server::Response` to hyper, your only way to signal error to the
-//! client is to abruptly close the HTTP connection while sending the body. If you want the ability
-//! to return a well-formatted error to the client while producing body bytes, you must buffer the
-//! entire body in-memory before returning anything to hyper.
+//! Once you return a `http::Response` to hyper, your only way to signal error to the client is to
+//! abruptly close the HTTP connection while sending the body. If you want the ability to return a
+//! well-formatted error to the client while producing body bytes, you must buffer the entire body
+//! in-memory before returning anything to hyper.
 //!
 //! If you are buffering a response in memory, `serve` requires copying the bytes (when using
-//! `Data = Vec<u8>` or similar) or atomic reference-counting (with `Data = Arc<Vec<u8>>` or
+//! `Data = Vec<u8>` or similar) or atomic reference-counting (with `Data = bytes::Bytes` or
 //! similar). `streaming_body` doesn't need to keep its own copy for potential future use; it may
 //! be cheaper because it can simply hand ownership of the existing `Vec<u8>`s to hyper.
 //!
-//! # Why the weird type bounds? Why not use `hyper::Body` and `hyper::Chunk` for everything?
+//! # Why the weird type bounds? Why not use `Body` and `bytes::Bytes` for everything?
 //!
-//! These bounds are compatible with `hyper::Body` and `hyper::Chunk`, and most callers will use
-//! those types. There are times when it's desirable to have more flexible ownership provided by a
-//! type such as `reffers::ARefs<'static, [u8]>`. One is `mmap`-based file serving: a
-//! `hyper::Chunk` would require copying the data in each chunk. An implementation with `ARefs`
-//! could instead `mmap` and `mlock` the data on another thread and provide chunks which `munmap`
-//! when dropped. In these cases, the caller can supply an alternate implementation of the
-//! `hyper::Payload` trait which uses a different `Data` type than `hyper::Chunk`.
-
-extern crate bytes;
-extern crate flate2;
-extern crate futures;
-extern crate futures_cpupool;
-extern crate http;
-extern crate httpdate;
-extern crate hyper;
-extern crate mime;
-extern crate smallvec;
-extern crate time;
-extern crate unicase;
+//! These bounds are compatible with `Body` and `bytes::Bytes`, and most callers will use those
+//! types. There are times when it's desirable to have more flexible ownership provided by a type
+//! such as `reffers::ARefs<'static, [u8]>`. One is `mmap`-based file serving: a `bytes::Bytes`
+//! would require copying the data in each chunk. `MmapFile` (available with the `mmap` feature)
+//! instead can `mmap` and `mlock` the data on another thread and provides chunks which `munmap`
+//! when dropped. In these cases, the caller can use a `Body` with a different `Data` type than
+//! `bytes::Bytes`, such as `MmapBody`, or supply another implementation of `http_body::Body`.
 
 use bytes::Buf;
-use futures::Stream;
+use futures_util::future;
+use futures_util::stream::{self, BoxStream, StreamExt};
 use http::header::{self, HeaderMap, HeaderValue};
 use std::ops::Range;
 use std::time::SystemTime;
@@ -91,30 +81,79 @@ macro_rules! fmt_ascii_val {
         use std::fmt::Write;
         write!(buf, $fmt, $($arg)*).expect("fmt_val fits within provided max len");
         unsafe {
-            ::http::header::HeaderValue::from_shared_unchecked(buf.freeze())
+            ::http::header::HeaderValue::from_maybe_shared_unchecked(buf.freeze())
         }
     }}
 }
 
+#[cfg(feature = "actix")]
+mod actix;
+mod archive;
+#[cfg(feature = "axum")]
+mod axum;
+mod body;
 mod chunker;
+mod coding;
+mod concat;
+mod content_etag;
+mod content_type;
+mod embed;
 mod etag;
 mod file;
 mod gzip;
+mod inmem;
+mod listing;
+#[cfg(feature = "mmap")]
+mod mmap;
+mod precompressed;
 mod range;
+#[cfg(all(feature = "sendfile", target_os = "linux"))]
+mod sendfile;
 mod serving;
-
-pub use file::ChunkedReadFile;
-pub use gzip::BodyWriter;
-pub use serving::serve;
+mod slice;
+mod static_dir;
+mod tar;
+#[cfg(feature = "tower")]
+mod tower;
+mod zip;
+
+#[cfg(feature = "actix")]
+pub use crate::actix::ActixEntity;
+pub use crate::archive::{Archive, ArchiveMember};
+#[cfg(feature = "axum")]
+pub use crate::axum::AxumEntity;
+pub use crate::body::{Body, BoxError};
+pub use crate::coding::{negotiate_coding, AcceptEncoding, Coding, QualityItem};
+pub use crate::concat::Concat;
+pub use crate::content_etag::ContentEtagCache;
+pub use crate::content_type::ContentTypes;
+pub use crate::embed::{Embed, EmbeddedDir, EmbeddedFile, EmbeddedVariant};
+pub use crate::file::{ChunkedReadFile, ChunkedReadFileBuilder};
+pub use crate::gzip::BodyWriter;
+pub use crate::inmem::{InMemoryEntity, InMemoryStorage};
+pub use crate::listing::DirListing;
+#[cfg(feature = "mmap")]
+pub use crate::mmap::{MmapBody, MmapChunk, MmapFile};
+pub use crate::precompressed::Precompressed;
+#[cfg(all(feature = "sendfile", target_os = "linux"))]
+pub use crate::sendfile::{serve_file, FileRegion};
+pub use crate::range::{RangePolicy, RangeViolation};
+pub use crate::serving::{serve, serve_with, ServeOptions};
+pub use crate::slice::Slice;
+pub use crate::static_dir::StaticDir;
+pub use crate::tar::TarEntity;
+#[cfg(feature = "tower")]
+pub use crate::tower::{ServeEntity, StreamingBodyLayer, StreamingBodyService};
+pub use crate::zip::ZipArchive;
 
 /// A reusable, read-only, byte-rangeable HTTP entity for GET and HEAD serving.
 /// Must return exactly the same data on every call.
 pub trait Entity: 'static + Send {
-    type Error: Send;
+    type Error: 'static + Send;
 
     /// The type of a data chunk.
     ///
-    /// Commonly `::hyper::Chunk` but may be something more exotic.
+    /// Commonly `bytes::Bytes` but may be something more exotic.
     type Data: 'static + Send + Buf + From<Vec<u8>> + From<&'static [u8]>;
 
     /// Returns the length of the entity's body in bytes.
@@ -126,10 +165,7 @@ pub trait Entity: 'static + Send {
     }
 
     /// Gets the body bytes indicated by `range`.
-    fn get_range(
-        &self,
-        range: Range<u64>,
-    ) -> Box<Stream<Item = Self::Data, Error = Self::Error> + Send>;
+    fn get_range(&self, range: Range<u64>) -> BoxStream<'static, Result<Self::Data, Self::Error>>;
 
     /// Adds entity headers such as `Content-Type` to the supplied `Headers` object.
     /// In particular, these headers are the "other representation header fields" described by [RFC
@@ -138,7 +174,7 @@ pub trait Entity: 'static + Send {
     ///
     /// This function will be called only when that section says that headers such as
     /// `Content-Type` should be included in the response.
-    fn add_headers(&self, &mut HeaderMap);
+    fn add_headers(&self, headers: &mut HeaderMap);
 
     /// Returns an etag for this entity, if available.
     /// Implementations are encouraged to provide a strong etag. [RFC 7232 section
@@ -153,185 +189,331 @@ pub trait Entity: 'static + Send {
     fn last_modified(&self) -> Option<SystemTime>;
 }
 
-/// Returns iff it's preferable to use `Content-Encoding: gzip` when responding to the given
-/// request, rather than no content coding.
-///
-/// Use via `should_gzip(req.headers().get())`.
-///
-/// Follows the rules of [RFC 7231 section
-/// 5.3.4](https://tools.ietf.org/html/rfc7231#section-5.3.4).
-pub fn should_gzip(headers: &HeaderMap) -> bool {
-    let v = match headers.get(header::ACCEPT_ENCODING) {
-        None => return false,
-        Some(v) => v,
-    };
-    let (mut gzip_q, mut identity_q, mut star_q) = (None, None, None);
-    let parts = match v.to_str() {
-        Ok(s) => s.split(','),
-        Err(_) => return false,
-    };
-    for qi in parts {
-        // Parse.
-        let qi = qi.trim();
-        let mut parts = qi.rsplitn(2, ';').map(|p| p.trim());
-        let last_part = parts
-            .next()
-            .expect("rsplitn should return at least one part");
-        let coding;
-        let quality;
-        match parts.next() {
-            None => {
-                coding = last_part;
-                quality = 1.0f32;
-            }
-            Some(c) => {
-                if !last_part.starts_with("q=") {
-                    return false; // unparseable.
-                }
-                let q = &last_part[2..];
-                match q.parse::<f32>() {
-                    Ok(q) if 0f32 <= q && q <= 1f32 => {
-                        coding = c;
-                        quality = q;
-                    }
-                    Ok(_q) => return false, // unparseable.
-                    Err(_) => return false, // unparseable.
-                };
-            }
-        }
-
-        if coding == "gzip" {
-            gzip_q = Some(quality);
-        } else if coding == "identity" {
-            identity_q = Some(quality);
-        } else if coding == "*" {
-            star_q = Some(quality);
-        }
-    }
-
-    let gzip_q = gzip_q.or(star_q).unwrap_or(0.0f32);
-
-    // "If the representation has no content-coding, then it is
-    // acceptable by default unless specifically excluded by the
-    // Accept-Encoding field stating either "identity;q=0" or "*;q=0"
-    // without a more specific entry for "identity"."
-    let identity_q = identity_q.or(star_q).unwrap_or(0.001f32);
-
-    gzip_q > 0.0f32 && gzip_q >= identity_q
-}
-
-pub struct StreamingBodyBuilder {
+pub struct StreamingBodyBuilder<'a> {
     chunk_size: usize,
     gzip_level: u32,
+    #[cfg(feature = "brotli")]
+    brotli_quality: u32,
+    #[cfg(feature = "zstd")]
+    zstd_level: i32,
     body_needed: bool,
+    req_hdrs: &'a HeaderMap,
+    etag: Option<HeaderValue>,
+    last_modified: Option<SystemTime>,
 }
 
 /// Adds a streaming body to the given request if a body is needed.
 ///
-/// Currently the body is added for non-HEAD requests. In the future, this may also follow
-/// conditional GET rules, omitting the body and stripping out entity headers from the response as
-/// desired.
-pub fn streaming_body<T>(req: &http::Request<T>) -> StreamingBodyBuilder {
+/// The body is added for non-HEAD requests, unless the response's etag or last modified time
+/// (supplied via `StreamingBodyBuilder::with_etag` and
+/// `StreamingBodyBuilder::with_last_modified`) cause conditional GET rules to omit it.
+pub fn streaming_body<T>(req: &http::Request<T>) -> StreamingBodyBuilder<'_> {
     StreamingBodyBuilder {
         chunk_size: 4096,
-        gzip_level: match should_gzip(req.headers()) {
-            true => 6,
-            false => 0,
-        },
+        gzip_level: 6,
+        #[cfg(feature = "brotli")]
+        brotli_quality: 5,
+        #[cfg(feature = "zstd")]
+        zstd_level: 3,
         body_needed: *req.method() != http::method::Method::HEAD,
+        req_hdrs: req.headers(),
+        etag: None,
+        last_modified: None,
     }
 }
 
-impl StreamingBodyBuilder {
+impl<'a> StreamingBodyBuilder<'a> {
     pub fn with_chunk_size(self, chunk_size: usize) -> Self {
         StreamingBodyBuilder { chunk_size, ..self }
     }
 
+    /// Sets the gzip compression level, from 1 (fastest) to 9 (best compression). A level of 0
+    /// disables gzip.
     pub fn with_gzip_level(self, level: u32) -> Self {
         StreamingBodyBuilder {
-            gzip_level: if self.gzip_level == 0 { 0 } else { level },
+            gzip_level: level,
+            ..self
+        }
+    }
+
+    /// Sets the brotli quality, from 0 (fastest) to 11 (best compression).
+    #[cfg(feature = "brotli")]
+    pub fn with_brotli_quality(self, quality: u32) -> Self {
+        assert!(quality <= 11);
+        StreamingBodyBuilder {
+            brotli_quality: quality,
             ..self
         }
     }
 
+    /// Sets the zstd compression level, as accepted by `zstd::stream::write::Encoder::new`.
+    #[cfg(feature = "zstd")]
+    pub fn with_zstd_level(self, level: i32) -> Self {
+        assert!(zstd::compression_level_range().contains(&level));
+        StreamingBodyBuilder {
+            zstd_level: level,
+            ..self
+        }
+    }
+
+    /// Sets the etag of the response, which will be used to evaluate `If-Match` and
+    /// `If-None-Match` headers and sent in the `ETag` header.
+    pub fn with_etag(self, etag: HeaderValue) -> Self {
+        StreamingBodyBuilder {
+            etag: Some(etag),
+            ..self
+        }
+    }
+
+    /// Sets the last modified time of the response, which will be used to evaluate
+    /// `If-Unmodified-Since` and `If-Modified-Since` headers and sent in the `Last-Modified`
+    /// header.
+    pub fn with_last_modified(self, last_modified: SystemTime) -> Self {
+        StreamingBodyBuilder {
+            last_modified: Some(last_modified),
+            ..self
+        }
+    }
+
+    /// Builds the response.
+    ///
+    /// If an etag or last modified time was supplied, the request's conditional headers are
+    /// evaluated as in `serve`. When they indicate the client's copy is current (`304 Not
+    /// Modified`) or a precondition failed (`412 Precondition Failed`), the returned response is
+    /// complete and no `BodyWriter` is returned.
     pub fn build<P, D, E>(self) -> (http::Response<P>, Option<BodyWriter<D, E>>)
     where
-        D: From<Vec<u8>> + Send,
-        E: Send,
-        P: From<Box<Stream<Item = D, Error = E> + Send>>,
+        D: From<Vec<u8>> + Send + 'static,
+        E: Send + 'static,
+        P: From<BoxStream<'static, Result<D, E>>>,
     {
-        let (w, stream) = chunker::BodyWriter::with_chunk_size(self.chunk_size);
-        let mut resp = http::Response::new(stream.into());
-        resp.headers_mut()
-            .append(header::VARY, HeaderValue::from_static("accept-encoding"));
+        let (precondition_failed, not_modified) =
+            if self.etag.is_none() && self.last_modified.is_none() {
+                (false, false)
+            } else {
+                match serving::parse_modified_hdrs(&self.etag, self.req_hdrs, self.last_modified) {
+                    Err(s) => {
+                        let resp = http::Response::builder()
+                            .status(http::StatusCode::BAD_REQUEST)
+                            .body(static_body::<D, E>(s).into())
+                            .unwrap();
+                        return (resp, None);
+                    }
+                    Ok(p) => p,
+                }
+            };
+
+        let mut res = http::Response::builder()
+            .header(header::VARY, HeaderValue::from_static("accept-encoding"));
+        if let Some(m) = self.last_modified {
+            // As in `serve`, set the Date now so that the Last-Modified can be clamped to it.
+            let d = SystemTime::now();
+            res = res.header(header::DATE, &*httpdate::fmt_http_date(d));
+            let clamped_m = ::std::cmp::min(m, d);
+            res = res.header(header::LAST_MODIFIED, &*httpdate::fmt_http_date(clamped_m));
+        }
+        if let Some(e) = self.etag {
+            res = res.header(header::ETAG, e);
+        }
+
+        if precondition_failed {
+            let resp = res
+                .status(http::StatusCode::PRECONDITION_FAILED)
+                .body(static_body::<D, E>("Precondition failed").into())
+                .unwrap();
+            return (resp, None);
+        }
 
+        if not_modified {
+            let resp = res
+                .status(http::StatusCode::NOT_MODIFIED)
+                .body(stream::empty().boxed().into())
+                .unwrap();
+            return (resp, None);
+        }
+
+        // Prefer the newer codings, which typically compress better than gzip. If nothing is
+        // acceptable, send the body without a content coding anyway as suggested by RFC 7231
+        // section 5.3.4.
+        let mut available = Vec::with_capacity(4);
+        #[cfg(feature = "brotli")]
+        available.push(Coding::Brotli);
+        #[cfg(feature = "zstd")]
+        available.push(Coding::Zstd);
         if self.gzip_level > 0 {
-            resp.headers_mut()
-                .append(header::CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+            available.push(Coding::Gzip);
+        }
+        available.push(Coding::Identity);
+        let coding = negotiate_coding(self.req_hdrs, &available).unwrap_or(Coding::Identity);
+        if coding != Coding::Identity {
+            res = res.header(header::CONTENT_ENCODING, HeaderValue::from_static(coding.as_str()));
         }
 
+        let (w, stream) = chunker::BodyWriter::with_chunk_size(self.chunk_size);
+        let resp = res.body(stream.into()).unwrap();
+
         if !self.body_needed {
             return (resp, None);
         }
 
-        let w = match self.gzip_level > 0 {
-            true => BodyWriter::gzipped(w, flate2::Compression::new(self.gzip_level)),
-            false => BodyWriter::raw(w),
+        let w = match coding {
+            Coding::Gzip => BodyWriter::gzipped(w, flate2::Compression::new(self.gzip_level)),
+            #[cfg(feature = "brotli")]
+            Coding::Brotli => BodyWriter::brotli(w, self.brotli_quality),
+            #[cfg(feature = "zstd")]
+            Coding::Zstd => BodyWriter::zstd(w, self.zstd_level),
+            _ => BodyWriter::raw(w),
         };
 
         (resp, Some(w))
     }
 }
 
+fn static_body<D, E>(s: &'static str) -> BoxStream<'static, Result<D, E>>
+where
+    D: From<Vec<u8>> + Send + 'static,
+    E: Send + 'static,
+{
+    vec_body(s.as_bytes().to_vec())
+}
+
+/// Returns a stream of the single chunk `v`.
+fn vec_body<D, E>(v: Vec<u8>) -> BoxStream<'static, Result<D, E>>
+where
+    D: From<Vec<u8>> + Send + 'static,
+    E: Send + 'static,
+{
+    stream::once(future::ready(Ok(v.into()))).boxed()
+}
+
+/// Collects the bytes of an entity or body stream, for tests.
+#[cfg(test)]
+pub(crate) async fn body_bytes<D: Buf, E>(
+    s: BoxStream<'static, Result<D, E>>,
+) -> Result<Vec<u8>, E> {
+    use futures_util::TryStreamExt;
+    s.try_fold(Vec::new(), |mut v, mut d| {
+        while d.has_remaining() {
+            let n = {
+                let c = d.chunk();
+                v.extend_from_slice(c);
+                c.len()
+            };
+            d.advance(n);
+        }
+        future::ready(Ok(v))
+    }).await
+}
+
 #[cfg(test)]
 mod tests {
+    use futures::executor::block_on;
+    use futures::stream::{BoxStream, TryStreamExt};
     use http::header::HeaderValue;
     use http::{self, header};
+    use std::io::Write;
 
-    fn ae_hdrs(value: &'static str) -> http::HeaderMap {
-        let mut h = http::HeaderMap::new();
-        h.insert(header::ACCEPT_ENCODING, HeaderValue::from_static(value));
-        h
+    type BodyStream = BoxStream<'static, Result<Vec<u8>, ()>>;
+
+    fn streaming_req(hdr: Option<(header::HeaderName, &'static str)>) -> http::Request<()> {
+        let mut req = http::Request::new(());
+        if let Some((k, v)) = hdr {
+            req.headers_mut().insert(k, HeaderValue::from_static(v));
+        }
+        req
+    }
+
+    #[test]
+    fn streaming_body_unconditional() {
+        let req = streaming_req(Some((header::IF_NONE_MATCH, "\"foo\"")));
+        let (resp, w) = super::streaming_body(&req).build();
+        let resp: http::Response<BodyStream> = resp;
+        assert_eq!(resp.status(), http::StatusCode::OK);
+        assert!(resp.headers().get(header::ETAG).is_none());
+        let mut w = w.unwrap();
+        w.write_all(b"asdf").unwrap();
+        drop(w);
+        assert_eq!(b"asdf", &block_on(resp.into_body().try_concat()).unwrap()[..]);
+    }
+
+    #[test]
+    fn streaming_body_not_modified() {
+        let req = streaming_req(Some((header::IF_NONE_MATCH, "\"foo\"")));
+        let (resp, w) = super::streaming_body(&req)
+            .with_etag(HeaderValue::from_static("\"foo\""))
+            .build::<BodyStream, _, _>();
+        assert!(w.is_none());
+        assert_eq!(resp.status(), http::StatusCode::NOT_MODIFIED);
+        assert_eq!(resp.headers().get(header::ETAG).unwrap(), "\"foo\"");
+        assert!(block_on(resp.into_body().try_concat()).unwrap().is_empty());
+
+        let req = streaming_req(Some((
+            header::IF_MODIFIED_SINCE,
+            "Sun, 06 Nov 1994 08:49:37 GMT",
+        )));
+        let last_modified = ::httpdate::parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT").unwrap();
+        let (resp, w) = super::streaming_body(&req)
+            .with_last_modified(last_modified)
+            .build::<BodyStream, _, _>();
+        assert!(w.is_none());
+        assert_eq!(resp.status(), http::StatusCode::NOT_MODIFIED);
+    }
+
+    #[test]
+    fn streaming_body_modified() {
+        let req = streaming_req(Some((header::IF_NONE_MATCH, "\"bar\"")));
+        let (resp, w) = super::streaming_body(&req)
+            .with_etag(HeaderValue::from_static("\"foo\""))
+            .build::<BodyStream, _, _>();
+        assert!(w.is_some());
+        assert_eq!(resp.status(), http::StatusCode::OK);
+        assert_eq!(resp.headers().get(header::ETAG).unwrap(), "\"foo\"");
+    }
+
+    #[test]
+    fn streaming_body_precondition_failed() {
+        let req = streaming_req(Some((header::IF_MATCH, "\"bar\"")));
+        let (resp, w) = super::streaming_body(&req)
+            .with_etag(HeaderValue::from_static("\"foo\""))
+            .build::<BodyStream, _, _>();
+        assert!(w.is_none());
+        assert_eq!(resp.status(), http::StatusCode::PRECONDITION_FAILED);
+    }
+
+    /// Writes `body` via a `streaming_body` for a request with the given `Accept-Encoding`,
+    /// returning the `Content-Encoding` and the encoded body.
+    #[cfg(any(feature = "brotli", feature = "zstd"))]
+    fn encoded_body(accept_encoding: &'static str, body: &[u8]) -> (String, Vec<u8>) {
+        let req = streaming_req(Some((header::ACCEPT_ENCODING, accept_encoding)));
+        let (resp, w) = super::streaming_body(&req).build::<BodyStream, _, _>();
+        let mut w = w.unwrap();
+        w.write_all(body).unwrap();
+        drop(w);
+        let coding = resp.headers()[header::CONTENT_ENCODING]
+            .to_str()
+            .unwrap()
+            .to_owned();
+        (coding, block_on(resp.into_body().try_concat()).unwrap())
+    }
+
+    #[cfg(feature = "brotli")]
+    #[test]
+    fn streaming_body_brotli() {
+        use std::io::Read;
+        let (coding, encoded) = encoded_body("gzip, br", b"hello world");
+        assert_eq!("br", coding);
+        let mut decoded = Vec::new();
+        ::brotli::Decompressor::new(&encoded[..], 4096)
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(b"hello world", &decoded[..]);
     }
 
+    #[cfg(feature = "zstd")]
     #[test]
-    fn should_gzip() {
-        // "A request without an Accept-Encoding header field implies that the
-        // user agent has no preferences regarding content-codings. Although
-        // this allows the server to use any content-coding in a response, it
-        // does not imply that the user agent will be able to correctly process
-        // all encodings." Identity seems safer; don't gzip.
-        assert!(!super::should_gzip(&header::HeaderMap::new()));
-
-        // "If the representation's content-coding is one of the
-        // content-codings listed in the Accept-Encoding field, then it is
-        // acceptable unless it is accompanied by a qvalue of 0.  (As
-        // defined in Section 5.3.1, a qvalue of 0 means "not acceptable".)"
-        assert!(super::should_gzip(&ae_hdrs("gzip")));
-        assert!(super::should_gzip(&ae_hdrs("gzip;q=0.001")));
-        assert!(!super::should_gzip(&ae_hdrs("gzip;q=0")));
-
-        // "An Accept-Encoding header field with a combined field-value that is
-        // empty implies that the user agent does not want any content-coding in
-        // response."
-        assert!(!super::should_gzip(&ae_hdrs("")));
-
-        // "The asterisk "*" symbol in an Accept-Encoding field
-        // matches any available content-coding not explicitly listed in the
-        // header field."
-        assert!(super::should_gzip(&ae_hdrs("*")));
-        assert!(!super::should_gzip(&ae_hdrs("gzip;q=0, *")));
-        assert!(super::should_gzip(&ae_hdrs("identity=q=0, *")));
-
-        // "If multiple content-codings are acceptable, then the acceptable
-        // content-coding with the highest non-zero qvalue is preferred."
-        assert!(super::should_gzip(&ae_hdrs("identity;q=0.5, gzip;q=1.0")));
-        assert!(!super::should_gzip(&ae_hdrs("identity;q=1.0, gzip;q=0.5")));
-
-        // "If an Accept-Encoding header field is present in a request
-        // and none of the available representations for the response have a
-        // content-coding that is listed as acceptable, the origin server SHOULD
-        // send a response without any content-coding."
-        assert!(!super::should_gzip(&ae_hdrs("*;q=0")));
+    fn streaming_body_zstd() {
+        let (coding, encoded) = encoded_body("gzip;q=0.5, zstd", b"hello world");
+        assert_eq!("zstd", coding);
+        assert_eq!(b"hello world", &::zstd::decode_all(&encoded[..]).unwrap()[..]);
     }
 }
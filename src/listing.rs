@@ -0,0 +1,471 @@
+// Copyright (c) 2018 Scott Lamb <slamb@slamb.org>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use crate::coding::parse_qvalue;
+use crate::content_etag;
+use crate::inmem::{InMemoryEntity, InMemoryStorage};
+use crate::static_dir::percent_decode;
+use crate::Entity;
+use bytes::Buf;
+use futures_util::stream::BoxStream;
+use http::header::{self, HeaderMap, HeaderValue};
+use http::Request;
+use httpdate::fmt_http_date;
+use std::cmp;
+use std::fmt::Write;
+use std::io;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The key by which listing entries are sorted.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum SortKey {
+    Name,
+    Size,
+    Modified,
+}
+
+impl SortKey {
+    fn as_str(self) -> &'static str {
+        match self {
+            SortKey::Name => "name",
+            SortKey::Size => "size",
+            SortKey::Modified => "modified",
+        }
+    }
+}
+
+struct ListEntry {
+    name: String,
+    is_dir: bool,
+    len: u64,
+    mtime: SystemTime,
+}
+
+/// A listing of a directory's entries, rendered in memory as HTML or JSON.
+///
+/// The format is chosen by the request's `Accept` header: JSON if it prefers `application/json`
+/// to `text/html`, HTML otherwise. Entries are sorted according to the query parameters `sort`
+/// (`name`, `size`, or `modified`) and `order` (`asc` or `desc`); the default is by name,
+/// ascending. Column headings in the HTML form link to the other orderings.
+///
+/// The listing's etag is derived from the rendered body, and its last modified time is the latest
+/// of the directory's and its entries', so conditional GET works when serving it via `serve`. As
+/// the representation depends on `Accept`, the response should carry `Vary: accept`; `StaticDir`
+/// adds this automatically.
+///
+/// The JSON form is an object such as:
+///
+/// ```text
+/// {"path":"/pub/","entries":[{"name":"a.txt","type":"file","size":3,"modified":1530000000}]}
+/// ```
+pub struct DirListing<D, E>
+where
+    D: 'static + Send + Buf + From<Vec<u8>> + From<&'static [u8]>,
+    E: 'static + Send + Into<Box<dyn ::std::error::Error + Send + Sync>> + From<Box<io::Error>>,
+{
+    inner: InMemoryEntity<Rendered, D, E>,
+}
+
+/// A rendered listing, as storage for an `InMemoryEntity`.
+///
+/// Unlike `Vec<u8>`, this works with `Data` types which only implement `From<Vec<u8>>`, at the
+/// cost of copying each requested range.
+struct Rendered(Vec<u8>);
+
+impl<D: From<Vec<u8>>> InMemoryStorage<D> for Rendered {
+    type Stored = Arc<[u8]>;
+
+    fn into_stored(self) -> Self::Stored {
+        Arc::from(self.0)
+    }
+
+    fn slice(stored: &Self::Stored, range: Range<usize>) -> D {
+        D::from(stored[range].to_vec())
+    }
+}
+
+impl<D, E> DirListing<D, E>
+where
+    D: 'static + Send + Buf + From<Vec<u8>> + From<&'static [u8]>,
+    E: 'static + Send + Into<Box<dyn ::std::error::Error + Send + Sync>> + From<Box<io::Error>>,
+{
+    /// Reads the directory `dir` and renders a listing of it for `req`.
+    ///
+    /// The request's path is used as the listing's title and as the base of relative links to
+    /// entries, so it should end in `/`. Entries which are symbolic links are described by their
+    /// targets; dangling links are omitted. This performs blocking filesystem operations.
+    pub fn new<PI>(dir: &Path, req: &Request<PI>) -> Result<Self, io::Error> {
+        let mut latest = dir.metadata()?.modified()?;
+        let mut entries = Vec::new();
+        for entry in dir.read_dir()? {
+            let entry = entry?;
+            let m = match entry.path().metadata() {
+                Ok(m) => m,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            let mtime = m.modified()?;
+            latest = cmp::max(latest, mtime);
+            entries.push(ListEntry {
+                name: entry.file_name().to_string_lossy().into_owned(),
+                is_dir: m.is_dir(),
+                len: m.len(),
+                mtime,
+            });
+        }
+
+        let (key, desc) = parse_sort(req.uri().query().unwrap_or(""));
+        entries.sort_by(|a, b| {
+            let o = match key {
+                SortKey::Name => a.name.cmp(&b.name),
+                SortKey::Size => a.len.cmp(&b.len),
+                SortKey::Modified => a.mtime.cmp(&b.mtime),
+            }.then_with(|| a.name.cmp(&b.name));
+            if desc {
+                o.reverse()
+            } else {
+                o
+            }
+        });
+
+        let path = req.uri().path();
+        let path = String::from_utf8_lossy(&percent_decode(path).unwrap_or_else(|| path.into()))
+            .into_owned();
+        let (body, content_type) = if wants_json(req.headers()) {
+            (render_json(&path, &entries), "application/json")
+        } else {
+            (
+                render_html(&path, &entries, key, desc),
+                "text/html; charset=utf-8",
+            )
+        };
+
+        let etag = content_etag::etag_for_bytes(&body);
+        Ok(DirListing {
+            inner: InMemoryEntity::new(Rendered(body))
+                .with_content_type(HeaderValue::from_static(content_type))
+                .with_etag(Some(etag))
+                .with_last_modified(latest),
+        })
+    }
+}
+
+impl<D, E> Entity for DirListing<D, E>
+where
+    D: 'static + Send + Buf + From<Vec<u8>> + From<&'static [u8]>,
+    E: 'static + Send + Into<Box<dyn ::std::error::Error + Send + Sync>> + From<Box<io::Error>>,
+{
+    type Data = D;
+    type Error = E;
+
+    fn len(&self) -> u64 {
+        self.inner.len()
+    }
+
+    fn get_range(&self, range: Range<u64>) -> BoxStream<'static, Result<D, E>> {
+        self.inner.get_range(range)
+    }
+
+    fn add_headers(&self, h: &mut HeaderMap) {
+        self.inner.add_headers(h)
+    }
+
+    fn etag(&self) -> Option<HeaderValue> {
+        self.inner.etag()
+    }
+
+    fn last_modified(&self) -> Option<SystemTime> {
+        self.inner.last_modified()
+    }
+}
+
+/// Returns the given time as (seconds, nanoseconds) since the epoch, or zero if it's before.
+fn unix_time(t: SystemTime) -> (u64, u32) {
+    t.duration_since(UNIX_EPOCH)
+        .map(|d| (d.as_secs(), d.subsec_nanos()))
+        .unwrap_or((0, 0))
+}
+
+/// Parses the `sort` and `order` query parameters, ignoring unknown values.
+fn parse_sort(query: &str) -> (SortKey, bool) {
+    let mut key = SortKey::Name;
+    let mut desc = false;
+    for param in query.split('&') {
+        let mut kv = param.splitn(2, '=');
+        match (kv.next(), kv.next()) {
+            (Some("sort"), Some("name")) => key = SortKey::Name,
+            (Some("sort"), Some("size")) => key = SortKey::Size,
+            (Some("sort"), Some("modified")) => key = SortKey::Modified,
+            (Some("order"), Some("asc")) => desc = false,
+            (Some("order"), Some("desc")) => desc = true,
+            _ => {}
+        }
+    }
+    (key, desc)
+}
+
+/// Returns true iff the `Accept` header prefers `application/json` to `text/html`.
+fn wants_json(req_hdrs: &HeaderMap) -> bool {
+    // The quality of each type, and the specificity of the range which determined it.
+    let mut json = (0, 0);
+    let mut html = (0, 0);
+    for v in req_hdrs.get_all(header::ACCEPT) {
+        let v = match v.to_str() {
+            Ok(v) => v,
+            Err(_) => continue,
+        };
+        for item in v.split(',') {
+            let mut params = item.split(';');
+            let range = params.next().unwrap().trim().to_ascii_lowercase();
+            let q = params
+                .filter_map(|p| {
+                    let p = p.trim();
+                    if p.starts_with("q=") || p.starts_with("Q=") {
+                        parse_qvalue(&p[2..])
+                    } else {
+                        None
+                    }
+                })
+                .next()
+                .unwrap_or(1000);
+            let s = specificity(&range, "application/json");
+            if s > json.1 {
+                json = (q, s);
+            }
+            let s = specificity(&range, "text/html");
+            if s > html.1 {
+                html = (q, s);
+            }
+        }
+    }
+    json.0 > html.0
+}
+
+/// Returns how specifically the media range `range` matches the type `t`: 0 for not at all, 1 for
+/// `*/*`, 2 for `type/*`, 3 for an exact match.
+fn specificity(range: &str, t: &str) -> u8 {
+    if range == t {
+        3
+    } else if range.ends_with("/*") && t.starts_with(&range[..range.len() - 1]) {
+        2
+    } else if range == "*/*" {
+        1
+    } else {
+        0
+    }
+}
+
+/// Appends `s` to `out`, escaped for HTML text or a quoted attribute value.
+fn escape_html(out: &mut String, s: &str) {
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+}
+
+/// Appends `s` to `out`, percent-encoding all but unreserved characters for use as a path segment.
+fn escape_path_segment(out: &mut String, s: &str) {
+    for &b in s.as_bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                out.push(b as char)
+            }
+            b => write!(out, "%{:02X}", b).unwrap(),
+        }
+    }
+}
+
+/// Appends `s` to `out` as a JSON string literal.
+fn escape_json(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+fn render_json(path: &str, entries: &[ListEntry]) -> Vec<u8> {
+    let mut out = String::from("{\"path\":");
+    escape_json(&mut out, path);
+    out.push_str(",\"entries\":[");
+    for (i, e) in entries.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        out.push_str("{\"name\":");
+        escape_json(&mut out, &e.name);
+        write!(
+            out,
+            ",\"type\":\"{}\",\"size\":{},\"modified\":{}}}",
+            if e.is_dir { "dir" } else { "file" },
+            e.len,
+            unix_time(e.mtime).0
+        ).unwrap();
+    }
+    out.push_str("]}");
+    out.into_bytes()
+}
+
+fn render_html(path: &str, entries: &[ListEntry], key: SortKey, desc: bool) -> Vec<u8> {
+    let mut out = String::from("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n");
+    out.push_str("<title>Index of ");
+    escape_html(&mut out, path);
+    out.push_str("</title>\n</head>\n<body>\n<h1>Index of ");
+    escape_html(&mut out, path);
+    out.push_str("</h1>\n<table>\n<thead><tr>");
+    for &(k, title) in &[
+        (SortKey::Name, "Name"),
+        (SortKey::Size, "Size"),
+        (SortKey::Modified, "Last modified"),
+    ] {
+        // Clicking the current sort column reverses the order.
+        let order = if k == key && !desc { "desc" } else { "asc" };
+        write!(
+            out,
+            "<th><a href=\"?sort={}&amp;order={}\">{}</a></th>",
+            k.as_str(),
+            order,
+            title
+        ).unwrap();
+    }
+    out.push_str("</tr></thead>\n<tbody>\n");
+    if path != "/" {
+        out.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+    }
+    for e in entries {
+        out.push_str("<tr><td><a href=\"./");
+        escape_path_segment(&mut out, &e.name);
+        if e.is_dir {
+            out.push('/');
+        }
+        out.push_str("\">");
+        escape_html(&mut out, &e.name);
+        if e.is_dir {
+            out.push_str("/</a></td><td>-</td>");
+        } else {
+            write!(out, "</a></td><td>{}</td>", e.len).unwrap();
+        }
+        writeln!(out, "<td>{}</td></tr>", fmt_http_date(e.mtime)).unwrap();
+    }
+    out.push_str("</tbody>\n</table>\n</body>\n</html>\n");
+    out.into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_sort, wants_json, DirListing, SortKey};
+    use crate::{BoxError, Entity};
+    use bytes::Bytes;
+    use futures::executor::block_on;
+    use http::header::{self, HeaderMap, HeaderValue};
+    use http::Request;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::time::{Duration, SystemTime};
+    use tempdir::TempDir;
+
+    type Listing = DirListing<Bytes, BoxError>;
+
+    fn accept(v: &'static str) -> HeaderMap {
+        let mut h = HeaderMap::new();
+        h.insert(header::ACCEPT, HeaderValue::from_static(v));
+        h
+    }
+
+    #[test]
+    fn negotiate() {
+        assert!(!wants_json(&HeaderMap::new()));
+        assert!(wants_json(&accept("application/json")));
+        assert!(!wants_json(&accept("text/html,application/xhtml+xml,*/*;q=0.8")));
+        assert!(!wants_json(&accept("*/*")));
+        assert!(wants_json(&accept("application/*, text/html;q=0.5")));
+        assert!(!wants_json(&accept("application/json;q=0.5, text/*")));
+    }
+
+    #[test]
+    fn sort_params() {
+        assert_eq!((SortKey::Name, false), parse_sort(""));
+        assert_eq!((SortKey::Size, true), parse_sort("sort=size&order=desc"));
+        assert_eq!((SortKey::Modified, false), parse_sort("order=bogus&sort=modified"));
+    }
+
+    fn body(l: &Listing) -> String {
+        let b = block_on(crate::body_bytes(l.get_range(0..l.len()))).unwrap();
+        String::from_utf8(b).unwrap()
+    }
+
+    #[test]
+    fn render() {
+        let tmp = TempDir::new("http-listing").unwrap();
+        fs::create_dir(tmp.path().join("sub")).unwrap();
+        File::create(tmp.path().join("b&c.txt"))
+            .unwrap()
+            .write_all(b"12345")
+            .unwrap();
+        File::create(tmp.path().join("a")).unwrap();
+
+        let req = Request::get("/x%20y/?sort=size&order=desc")
+            .body(())
+            .unwrap();
+        let l = Listing::new(tmp.path(), &req).unwrap();
+        let mut h = HeaderMap::new();
+        l.add_headers(&mut h);
+        assert_eq!(h.get(header::CONTENT_TYPE).unwrap(), "text/html; charset=utf-8");
+        let html = body(&l);
+        assert!(html.contains("<title>Index of /x y/</title>"));
+        assert!(html.contains("<a href=\"./b%26c.txt\">b&amp;c.txt</a></td><td>5</td>"));
+        assert!(html.contains("<a href=\"./sub/\">sub/</a></td><td>-</td>"));
+        assert!(html.contains("<a href=\"?sort=size&amp;order=asc\">Size</a>"));
+        let b = html.find("b&amp;c.txt").unwrap();
+        let a = html.find(">a</a>").unwrap();
+        assert!(b < a);
+
+        let req = Request::get("/")
+            .header(header::ACCEPT, "application/json")
+            .body(())
+            .unwrap();
+        let j = Listing::new(tmp.path(), &req).unwrap();
+        let json = body(&j);
+        assert!(json.starts_with(r#"{"path":"/","entries":[{"name":"a","type":"file","#));
+        assert!(json.contains(r#"{"name":"sub","type":"dir","#));
+        assert_ne!(l.etag(), j.etag());
+
+        // The etag is stable until the directory changes.
+        assert_eq!(j.etag(), Listing::new(tmp.path(), &req).unwrap().etag());
+        File::create(tmp.path().join("d")).unwrap();
+        assert_ne!(j.etag(), Listing::new(tmp.path(), &req).unwrap().etag());
+
+        // Modifying an entry in place doesn't touch the directory's mtime, but must still advance
+        // the listing's last modified time.
+        let t = SystemTime::now() + Duration::from_secs(3600);
+        File::options()
+            .write(true)
+            .open(tmp.path().join("a"))
+            .unwrap()
+            .set_modified(t)
+            .unwrap();
+        assert_eq!(Some(t), Listing::new(tmp.path(), &req).unwrap().last_modified());
+    }
+}
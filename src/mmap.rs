@@ -0,0 +1,300 @@
+// Copyright (c) 2018 Scott Lamb <slamb@slamb.org>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use crate::{Body, Entity};
+use bytes::Buf;
+use futures_util::stream::{self, BoxStream, StreamExt};
+use http::header::{HeaderMap, HeaderValue};
+use memmap2::{Mmap, MmapOptions};
+use std::io;
+use std::ops::Range;
+use std::os::unix::fs::MetadataExt;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+// Chunks don't copy, so they can be much larger than ChunkedReadFile's. This size is the
+// granularity of madvise/mlock calls.
+static CHUNK_SIZE: u64 = 1 << 20;
+
+/// A chunk of a memory-mapped file, used as the `Data` type of `MmapFile`.
+///
+/// A mapped chunk borrows from the mapping shared with its `MmapFile`; the file is unmapped when
+/// the entity and all its chunks have been dropped. Chunks can also be created from `Vec<u8>` or
+/// `&'static [u8]`, as required by `Entity`, for headers of `multipart/byteranges` responses and
+/// the like.
+pub struct MmapChunk {
+    inner: ChunkInner,
+    pos: usize,
+    end: usize,
+}
+
+enum ChunkInner {
+    Mapped(Arc<Mmap>),
+    Owned(Vec<u8>),
+    Static(&'static [u8]),
+}
+
+impl MmapChunk {
+    fn slice(&self) -> &[u8] {
+        match self.inner {
+            ChunkInner::Mapped(ref m) => &m[..],
+            ChunkInner::Owned(ref v) => &v[..],
+            ChunkInner::Static(s) => s,
+        }
+    }
+}
+
+impl Buf for MmapChunk {
+    fn remaining(&self) -> usize {
+        self.end - self.pos
+    }
+
+    fn chunk(&self) -> &[u8] {
+        &self.slice()[self.pos..self.end]
+    }
+
+    fn advance(&mut self, cnt: usize) {
+        assert!(cnt <= self.remaining());
+        self.pos += cnt;
+    }
+}
+
+impl AsRef<[u8]> for MmapChunk {
+    fn as_ref(&self) -> &[u8] {
+        self.chunk()
+    }
+}
+
+impl From<Vec<u8>> for MmapChunk {
+    fn from(v: Vec<u8>) -> Self {
+        let end = v.len();
+        MmapChunk {
+            inner: ChunkInner::Owned(v),
+            pos: 0,
+            end,
+        }
+    }
+}
+
+impl From<&'static [u8]> for MmapChunk {
+    fn from(s: &'static [u8]) -> Self {
+        MmapChunk {
+            inner: ChunkInner::Static(s),
+            pos: 0,
+            end: s.len(),
+        }
+    }
+}
+
+/// A `Body` of `MmapChunk`s, for use as the body type when serving an `MmapFile`:
+/// `http_serve::serve::<_, MmapBody<E>, _>(f, &req)`.
+pub type MmapBody<E> = Body<MmapChunk, E>;
+
+/// A HTTP entity created from a `std::fs::File` which is memory-mapped once and served without
+/// copying.
+///
+/// Each chunk is prepared via `tokio::task::spawn_blocking` before being handed to the tokio
+/// reactor: the pages are requested with `madvise(MADV_WILLNEED)` and, if enabled via
+/// `with_mlock`, faulted in and locked in memory with `mlock(2)`. Thus the body must be polled
+/// within a tokio runtime.
+///
+/// The file must not be truncated while mapped; accessing the missing pages will cause a `SIGBUS`.
+/// Replace files by renaming a new file over the old path instead.
+#[derive(Clone)]
+pub struct MmapFile<E>
+where
+    E: 'static + Send + Into<Box<dyn ::std::error::Error + Send + Sync>> + From<Box<io::Error>>,
+{
+    inner: Arc<MmapFileInner>,
+    mlock: bool,
+    phantom: ::std::marker::PhantomData<E>,
+}
+
+struct MmapFileInner {
+    len: u64,
+    inode: u64,
+    mtime: SystemTime,
+
+    /// The mapping, or `None` for an empty file (which can't be mapped).
+    mmap: Option<Arc<Mmap>>,
+    headers: HeaderMap,
+}
+
+impl<E> MmapFile<E>
+where
+    E: 'static + Send + Into<Box<dyn ::std::error::Error + Send + Sync>> + From<Box<io::Error>>,
+{
+    /// Creates a new MmapFile.
+    ///
+    /// As with `ChunkedReadFile::new`, this calls `fstat(2)` (and `mmap(2)`), which may block, so
+    /// it typically shouldn't be called on the tokio reactor.
+    pub fn new(file: ::std::fs::File, headers: HeaderMap) -> Result<Self, io::Error> {
+        let m = file.metadata()?;
+        let mmap = if m.len() == 0 {
+            None
+        } else {
+            Some(Arc::new(unsafe { MmapOptions::new().map(&file)? }))
+        };
+        Ok(MmapFile {
+            inner: Arc::new(MmapFileInner {
+                len: m.len(),
+                inode: m.ino(),
+                mtime: m.modified()?,
+                mmap,
+                headers,
+            }),
+            mlock: false,
+            phantom: ::std::marker::PhantomData,
+        })
+    }
+
+    /// Sets whether pages are locked into memory with `mlock(2)` as they're served.
+    ///
+    /// Locking is done along with the `madvise` call. Pages remain locked until the file is
+    /// unmapped. Note that locking is subject to `RLIMIT_MEMLOCK`; failures are
+    /// reported as stream errors.
+    pub fn with_mlock(self, mlock: bool) -> Self {
+        MmapFile { mlock, ..self }
+    }
+}
+
+/// Calls `madvise(MADV_WILLNEED)` and optionally `mlock` on the given range of `mmap`.
+fn prepare(mmap: &Mmap, range: &Range<usize>, mlock: bool) -> Result<(), io::Error> {
+    // madvise requires a page-aligned address; mlock rounds down itself.
+    let page_size = unsafe { ::libc::sysconf(::libc::_SC_PAGESIZE) } as usize;
+    let start = range.start - range.start % page_size;
+    let ptr = unsafe { mmap.as_ptr().add(start) } as *mut ::libc::c_void;
+    let len = range.end - start;
+    if unsafe { ::libc::madvise(ptr, len, ::libc::MADV_WILLNEED) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if mlock && unsafe { ::libc::mlock(ptr, len) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+impl<E> Entity for MmapFile<E>
+where
+    E: 'static + Send + Into<Box<dyn ::std::error::Error + Send + Sync>> + From<Box<io::Error>>,
+{
+    type Data = MmapChunk;
+    type Error = E;
+
+    fn len(&self) -> u64 {
+        self.inner.len
+    }
+
+    fn get_range(&self, range: Range<u64>) -> BoxStream<'static, Result<MmapChunk, E>> {
+        let mmap = match self.inner.mmap {
+            None => return stream::empty().boxed(),
+            Some(ref m) => Arc::clone(m),
+        };
+        let mlock = self.mlock;
+        stream::unfold(range, move |left| {
+            let mmap = Arc::clone(&mmap);
+            async move {
+                if left.start == left.end {
+                    return None;
+                }
+                let chunk_size = ::std::cmp::min(CHUNK_SIZE, left.end - left.start);
+                let chunk = left.start as usize..(left.start + chunk_size) as usize;
+                let (m, c) = (Arc::clone(&mmap), chunk.clone());
+                let r = match tokio::task::spawn_blocking(move || prepare(&m, &c, mlock)).await {
+                    Ok(r) => r,
+                    Err(e) => Err(io::Error::other(e)),
+                };
+                if let Err(e) = r {
+                    // End the stream after the error.
+                    return Some((Err(Box::new(e).into()), left.end..left.end));
+                }
+                Some((
+                    Ok(MmapChunk {
+                        inner: ChunkInner::Mapped(mmap),
+                        pos: chunk.start,
+                        end: chunk.end,
+                    }),
+                    left.start + chunk_size..left.end,
+                ))
+            }
+        }).boxed()
+    }
+
+    fn add_headers(&self, h: &mut HeaderMap) {
+        h.extend(
+            self.inner
+                .headers
+                .iter()
+                .map(|(k, v)| (k.clone(), v.clone())),
+        );
+    }
+
+    fn etag(&self) -> Option<HeaderValue> {
+        Some(crate::file::etag(self.inner.inode, self.inner.len, self.inner.mtime))
+    }
+
+    fn last_modified(&self) -> Option<SystemTime> {
+        Some(self.inner.mtime)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Entity;
+    use super::MmapFile;
+    use crate::BoxError;
+    use bytes::Buf;
+    use futures::stream::StreamExt;
+    use http::header::HeaderMap;
+    use std::fs::File;
+    use std::io::Write;
+    use tempdir::TempDir;
+
+    type MF = MmapFile<BoxError>;
+
+    async fn read(f: &MF, range: ::std::ops::Range<u64>) -> Vec<u8> {
+        crate::body_bytes(f.get_range(range)).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn basic() {
+        let tmp = TempDir::new("http-mmap").unwrap();
+        let p = tmp.path().join("f");
+        let mut f = File::create(&p).unwrap();
+
+        let empty = MF::new(File::open(&p).unwrap(), HeaderMap::new()).unwrap();
+        assert_eq!(0, empty.len());
+        assert_eq!(b"", &read(&empty, 0..0).await[..]);
+
+        f.write_all(b"asdf").unwrap();
+        let mf = MF::new(File::open(&p).unwrap(), HeaderMap::new())
+            .unwrap()
+            .with_mlock(true);
+        assert_eq!(4, mf.len());
+        assert_eq!(b"asdf", &read(&mf, 0..4).await[..]);
+        assert_eq!(b"sd", &read(&mf, 1..3).await[..]);
+        assert_ne!(empty.etag(), mf.etag());
+
+        // Chunks remain valid after the entity is dropped.
+        let mut chunk = mf.get_range(1..4).next().await.unwrap().unwrap();
+        drop(mf);
+        chunk.advance(1);
+        assert_eq!(b"df", chunk.chunk());
+    }
+
+    #[tokio::test]
+    async fn large() {
+        let tmp = TempDir::new("http-mmap").unwrap();
+        let p = tmp.path().join("f");
+        let data: Vec<u8> = (0..3 * super::CHUNK_SIZE + 17).map(|i| i as u8).collect();
+        File::create(&p).unwrap().write_all(&data).unwrap();
+        let mf = MF::new(File::open(&p).unwrap(), HeaderMap::new()).unwrap();
+        assert_eq!(&data[..], &read(&mf, 0..mf.len()).await[..]);
+        assert_eq!(&data[5..2_000_000], &read(&mf, 5..2_000_000).await[..]);
+    }
+}
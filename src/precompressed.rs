@@ -0,0 +1,198 @@
+// Copyright (c) 2018 Scott Lamb <slamb@slamb.org>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use crate::coding::{negotiate_coding, Coding};
+use crate::file::ChunkedReadFile;
+use crate::serving::serve;
+use bytes::Buf;
+use futures_util::stream::BoxStream;
+use http::header::{self, HeaderMap, HeaderValue};
+use http::{Request, Response};
+use std::ffi::OsString;
+use std::io;
+use std::path::PathBuf;
+
+/// Selects between a file and precompressed variants of it stored alongside it, such as
+/// `foo.js.gz` and `foo.js.br` for `foo.js`.
+///
+/// The variant is chosen according to the request's `Accept-Encoding` header. Each is served as a
+/// separate `ChunkedReadFile` with its own etag (derived from its own inode, length, and
+/// modification time), so byte ranges and conditional GET apply to the bytes actually sent.
+///
+/// Variants which don't exist on disk are skipped, so the same `Precompressed` can be used for
+/// files which have only been compressed in some of the listed codings.
+#[derive(Clone, Debug)]
+pub struct Precompressed {
+    path: PathBuf,
+
+    /// The available codings and the extension of the file holding each, in preference order.
+    siblings: Vec<(Coding, OsString)>,
+}
+
+impl Precompressed {
+    /// Creates a selector for the uncompressed file at `path` with no precompressed variants.
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Precompressed {
+            path: path.into(),
+            siblings: Vec::new(),
+        }
+    }
+
+    /// Adds a variant with the given coding, stored at the base path plus `.` plus `extension`.
+    ///
+    /// When the client finds several variants equally acceptable, the one added first is used;
+    /// the uncompressed file is used only when none are acceptable. Panics if `coding` is
+    /// `Coding::Identity`. E.g.:
+    ///
+    /// ```
+    /// # use http_serve::{Coding, Precompressed};
+    /// let p = Precompressed::new("static/app.js")
+    ///     .with_sibling(Coding::Brotli, "br")    // static/app.js.br
+    ///     .with_sibling(Coding::Gzip, "gz");     // static/app.js.gz
+    /// ```
+    pub fn with_sibling<X: Into<OsString>>(mut self, coding: Coding, extension: X) -> Self {
+        assert!(
+            coding != Coding::Identity,
+            "a sibling can't have the identity coding"
+        );
+        self.siblings.push((coding, extension.into()));
+        self
+    }
+
+    fn sibling_path(&self, extension: &OsString) -> PathBuf {
+        let mut p = self.path.clone().into_os_string();
+        p.push(".");
+        p.push(extension);
+        p.into()
+    }
+
+    /// Opens the variant best suited to a request with the given headers.
+    ///
+    /// `headers` is as in `ChunkedReadFile::new`; `headers` should describe the
+    /// uncompressed representation (e.g. its `Content-Type`). A `Content-Encoding` header is added
+    /// for compressed variants. The caller is responsible for adding `Vary: accept-encoding` to
+    /// the response; `Precompressed::serve` does so automatically.
+    ///
+    /// Like `ChunkedReadFile::new`, this performs blocking filesystem operations.
+    pub fn open<D, E>(
+        &self,
+        req_hdrs: &HeaderMap,
+        headers: HeaderMap,
+    ) -> Result<ChunkedReadFile<D, E>, io::Error>
+    where
+        D: 'static + Send + Buf + From<Vec<u8>> + From<&'static [u8]>,
+        E: 'static + Send + Into<Box<dyn ::std::error::Error + Send + Sync>> + From<Box<io::Error>>,
+    {
+        let mut available: Vec<Coding> = self.siblings.iter().map(|&(c, _)| c).collect();
+        available.push(Coding::Identity);
+        loop {
+            // If nothing is acceptable, RFC 7231 section 5.3.4 suggests sending the uncompressed
+            // representation anyway.
+            let coding = negotiate_coding(req_hdrs, &available).unwrap_or(Coding::Identity);
+            let ext = match self.siblings.iter().find(|&&(c, _)| c == coding) {
+                None => {
+                    let f = ::std::fs::File::open(&self.path)?;
+                    return ChunkedReadFile::new(f, headers);
+                }
+                Some((_, ext)) => ext,
+            };
+            match ::std::fs::File::open(self.sibling_path(ext)) {
+                Ok(f) => {
+                    let mut headers = headers;
+                    headers.insert(
+                        header::CONTENT_ENCODING,
+                        HeaderValue::from_static(coding.as_str()),
+                    );
+                    return ChunkedReadFile::new(f, headers);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                    available.retain(|&c| c != coding);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Opens the best variant as in `open`, then serves it via `serve`.
+    ///
+    /// Adds `Vary: accept-encoding` to the response if any precompressed variants are configured.
+    pub fn serve<D, E, P, PI>(
+        &self,
+        req: &Request<PI>,
+        headers: HeaderMap,
+    ) -> Result<Response<P>, io::Error>
+    where
+        D: 'static + Send + Buf + From<Vec<u8>> + From<&'static [u8]>,
+        E: 'static + Send + Into<Box<dyn ::std::error::Error + Send + Sync>> + From<Box<io::Error>>,
+        P: http_body::Body + From<BoxStream<'static, Result<D, E>>>,
+    {
+        let f: ChunkedReadFile<D, E> = self.open(req.headers(), headers)?;
+        let mut resp = serve(f, req);
+        if !self.siblings.is_empty() {
+            resp.headers_mut()
+                .append(header::VARY, HeaderValue::from_static("accept-encoding"));
+        }
+        Ok(resp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Precompressed;
+    use crate::coding::Coding;
+    use crate::file::ChunkedReadFile;
+    use crate::{BoxError, Entity};
+    use bytes::Bytes;
+    use http::header::{self, HeaderMap, HeaderValue};
+    use std::fs::File;
+    use std::io::Write;
+    use tempdir::TempDir;
+
+    type Crf = ChunkedReadFile<Bytes, BoxError>;
+
+    fn ae_hdrs(value: &'static str) -> HeaderMap {
+        let mut h = HeaderMap::new();
+        h.insert(header::ACCEPT_ENCODING, HeaderValue::from_static(value));
+        h
+    }
+
+    async fn body(f: &Crf) -> Vec<u8> {
+        crate::body_bytes(f.get_range(0..f.len())).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn select() {
+        let tmp = TempDir::new("http-precompressed").unwrap();
+        let p = tmp.path().join("f.txt");
+        File::create(&p).unwrap().write_all(b"raw").unwrap();
+        File::create(tmp.path().join("f.txt.gz"))
+            .unwrap()
+            .write_all(b"gzipped")
+            .unwrap();
+        let sel = Precompressed::new(&p)
+            .with_sibling(Coding::Brotli, "br") // doesn't exist.
+            .with_sibling(Coding::Gzip, "gz");
+
+        let raw: Crf = sel.open(&HeaderMap::new(), HeaderMap::new()).unwrap();
+        assert_eq!(b"raw", &body(&raw).await[..]);
+        let mut h = HeaderMap::new();
+        raw.add_headers(&mut h);
+        assert!(h.get(header::CONTENT_ENCODING).is_none());
+
+        let gz: Crf = sel.open(&ae_hdrs("br, gzip"), HeaderMap::new()).unwrap();
+        assert_eq!(b"gzipped", &body(&gz).await[..]);
+        let mut h = HeaderMap::new();
+        gz.add_headers(&mut h);
+        assert_eq!(h.get(header::CONTENT_ENCODING).unwrap(), "gzip");
+        assert_ne!(raw.etag(), gz.etag());
+
+        let raw: Crf = sel.open(&ae_hdrs("gzip;q=0.5, identity"), HeaderMap::new())
+            .unwrap();
+        assert_eq!(b"raw", &body(&raw).await[..]);
+    }
+}
@@ -29,12 +29,126 @@ pub(crate) enum ResolvedRanges {
     Satisfiable(SmallVec<[Range<u64>; 1]>),
 }
 
+/// What `RangePolicy` does with a `Range` header which violates it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RangeViolation {
+    /// Ignores the `Range` header, sending the full entity with `200 OK`.
+    Ignore,
+
+    /// Rejects the request with `416 Range Not Satisfiable`.
+    Reject,
+}
+
+/// Limits on the byte ranges `serve_with` will send for a single request.
+///
+/// [RFC 7233 section 6.1](https://tools.ietf.org/html/rfc7233#section-6.1) notes that many
+/// small, overlapping, or out-of-order ranges can be used to amplify the work a server does per
+/// request, and recommends ignoring, coalescing, or rejecting them. This policy can:
+///
+/// *   reject ranges which are requested in descending order of their first byte.
+/// *   coalesce overlapping and adjacent ranges into one, sorting them by their first byte.
+/// *   limit the number of ranges, counted after coalescing.
+///
+/// Unsatisfiable ranges are dropped before the policy is applied. A violation is handled as
+/// specified by `with_violation`. The default policy allows everything, as `serve` does.
+#[derive(Clone, Debug)]
+pub struct RangePolicy {
+    max_ranges: Option<usize>,
+    coalesce: bool,
+    reject_descending: bool,
+    violation: RangeViolation,
+}
+
+impl Default for RangePolicy {
+    fn default() -> Self {
+        RangePolicy {
+            max_ranges: None,
+            coalesce: false,
+            reject_descending: false,
+            violation: RangeViolation::Ignore,
+        }
+    }
+}
+
+impl RangePolicy {
+    /// Returns the default policy, which allows everything.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the maximum number of ranges to serve, counted after coalescing.
+    pub fn with_max_ranges(self, max_ranges: usize) -> Self {
+        RangePolicy {
+            max_ranges: Some(max_ranges),
+            ..self
+        }
+    }
+
+    /// Sets whether overlapping and adjacent ranges are coalesced.
+    pub fn with_coalesce(self, coalesce: bool) -> Self {
+        RangePolicy { coalesce, ..self }
+    }
+
+    /// Sets whether a range which starts before the preceding one is a violation.
+    pub fn with_reject_descending(self, reject_descending: bool) -> Self {
+        RangePolicy {
+            reject_descending,
+            ..self
+        }
+    }
+
+    /// Sets how violations are handled. The default is `RangeViolation::Ignore`.
+    pub fn with_violation(self, violation: RangeViolation) -> Self {
+        RangePolicy { violation, ..self }
+    }
+
+    /// Applies this policy to the result of `parse`.
+    pub(crate) fn apply(&self, resolved: ResolvedRanges) -> ResolvedRanges {
+        let mut ranges = match resolved {
+            ResolvedRanges::Satisfiable(r) => r,
+            r => return r,
+        };
+        if self.reject_descending && ranges.windows(2).any(|w| w[1].start < w[0].start) {
+            return self.violated();
+        }
+        if self.coalesce && ranges.len() > 1 {
+            ranges.sort_by_key(|r| r.start);
+            let mut coalesced: SmallVec<[Range<u64>; 1]> = SmallVec::new();
+            for r in ranges {
+                match coalesced.last_mut() {
+                    Some(ref mut last) if r.start <= last.end => {
+                        last.end = cmp::max(last.end, r.end);
+                    }
+                    _ => coalesced.push(r),
+                }
+            }
+            ranges = coalesced;
+        }
+        if let Some(max) = self.max_ranges {
+            if ranges.len() > max {
+                return self.violated();
+            }
+        }
+        ResolvedRanges::Satisfiable(ranges)
+    }
+
+    fn violated(&self) -> ResolvedRanges {
+        match self.violation {
+            RangeViolation::Ignore => ResolvedRanges::None,
+            RangeViolation::Reject => ResolvedRanges::NotSatisfiable,
+        }
+    }
+}
+
 /// Parses the byte-range-set in the range header as described in [RFC 7233 section
 /// 2.1](https://tools.ietf.org/html/rfc7233#section-2.1).
 pub(crate) fn parse(range: Option<&HeaderValue>, len: u64) -> ResolvedRanges {
     let range = match range {
         None => return ResolvedRanges::None,
-        Some(r) => r.to_str().unwrap(),
+        Some(r) => match r.to_str() {
+            Ok(r) => r,
+            Err(_) => return ResolvedRanges::None, // not visible ASCII; unparseable.
+        },
     };
 
     // byte-ranges-specifier = bytes-unit "=" byte-range-set
@@ -46,7 +160,7 @@ pub(crate) fn parse(range: Option<&HeaderValue>, len: u64) -> ResolvedRanges {
     let mut ranges: SmallVec<[Range<u64>; 1]> = SmallVec::new();
     for r in range[6..].split(',') {
         // Trim OWS = *( SP / HTAB )
-        let r = r.trim_left_matches(|c| c == ' ' || c == '\t');
+        let r = r.trim_start_matches([' ', '\t']);
 
         // Parse one of the following.
         // byte-range-spec = first-byte-pos "-" [ last-byte-pos ]
@@ -90,12 +204,23 @@ pub(crate) fn parse(range: Option<&HeaderValue>, len: u64) -> ResolvedRanges {
     if !ranges.is_empty() {
         return ResolvedRanges::Satisfiable(ranges);
     }
-    return ResolvedRanges::NotSatisfiable;
+    ResolvedRanges::NotSatisfiable
+}
+
+/// Returns the part of `range` within `region`, relative to the start of `region`, or `None` if
+/// they don't overlap.
+pub(crate) fn intersect(range: &Range<u64>, region: Range<u64>) -> Option<Range<u64>> {
+    let start = cmp::max(range.start, region.start);
+    let end = cmp::min(range.end, region.end);
+    if start >= end {
+        return None;
+    }
+    Some(start - region.start..end - region.start)
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{parse, ResolvedRanges};
+    use super::{parse, RangePolicy, RangeViolation, ResolvedRanges};
     use http::header::HeaderValue;
     use smallvec::SmallVec;
 
@@ -211,5 +336,48 @@ mod tests {
     #[test]
     fn test_resolve_ranges_absent_or_invalid() {
         assert_eq!(ResolvedRanges::None, parse(None, 10000));
+        assert_eq!(
+            ResolvedRanges::None,
+            parse(
+                Some(&HeaderValue::from_bytes(b"bytes=0-\xff").unwrap()),
+                10000
+            )
+        );
+    }
+
+    #[test]
+    fn policy() {
+        let p = |s| parse(Some(&HeaderValue::from_static(s)), 10000);
+        let sat = |v: &[(u64, u64)]| {
+            ResolvedRanges::Satisfiable(v.iter().map(|&(s, e)| s..e).collect())
+        };
+
+        // The default policy allows everything.
+        let default = RangePolicy::new();
+        assert_eq!(sat(&[(5, 6), (0, 3)]), default.apply(p("bytes=5-5,0-2")));
+        assert_eq!(ResolvedRanges::None, default.apply(ResolvedRanges::None));
+
+        // Coalescing overlapping and adjacent ranges, but not ones with a gap.
+        let coalesce = RangePolicy::new().with_coalesce(true);
+        assert_eq!(
+            sat(&[(0, 701), (900, 1000)]),
+            coalesce.apply(p("bytes=601-700,0-500,900-999,501-600,10-20"))
+        );
+        assert_eq!(sat(&[(0, 10000)]), coalesce.apply(p("bytes=0-,0-,0-")));
+
+        // Descending ranges.
+        let desc = RangePolicy::new().with_reject_descending(true);
+        assert_eq!(sat(&[(0, 1), (0, 1), (5, 6)]), desc.apply(p("bytes=0-0,0-0,5-5")));
+        assert_eq!(ResolvedRanges::None, desc.apply(p("bytes=5-5,0-0")));
+        let desc = desc.with_violation(RangeViolation::Reject);
+        assert_eq!(ResolvedRanges::NotSatisfiable, desc.apply(p("bytes=5-5,0-0")));
+
+        // Range count, after coalescing.
+        let max = RangePolicy::new().with_max_ranges(2);
+        assert_eq!(ResolvedRanges::None, max.apply(p("bytes=0-0,1-1,2-2")));
+        assert_eq!(sat(&[(0, 1), (2, 3)]), max.apply(p("bytes=0-0,2-2")));
+        let max = max.with_coalesce(true);
+        assert_eq!(sat(&[(0, 3)]), max.apply(p("bytes=0-0,1-1,2-2")));
+        assert_eq!(ResolvedRanges::None, max.apply(p("bytes=0-0,2-2,4-4")));
     }
 }
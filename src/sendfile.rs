@@ -0,0 +1,247 @@
+// Copyright (c) 2018 Scott Lamb <slamb@slamb.org>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Linux `sendfile(2)` fast path for `ChunkedReadFile`.
+
+use crate::file::{ChunkedReadFile, ChunkedReadFileInner};
+use crate::serving;
+use bytes::Buf;
+use futures_util::stream::BoxStream;
+use http::{Request, Response};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::Arc;
+
+/// The most bytes Linux will transfer in a single `sendfile(2)` call.
+const MAX_SENDFILE_LEN: u64 = 0x7fff_f000;
+
+/// The size of the buffer used when falling back to `pread(2)` and `write(2)`.
+const FALLBACK_BUF_LEN: usize = 65_536;
+
+/// A contiguous region of a file which makes up an entire response body, as returned by
+/// `serve_file`.
+///
+/// A connection writer which has direct access to the socket (such as one serving plain HTTP/1.1
+/// without TLS) can send the region with `send_to` rather than polling the response body, avoiding
+/// copying the file's contents through userspace.
+pub struct FileRegion {
+    file: Arc<ChunkedReadFileInner>,
+    offset: u64,
+    end: u64,
+    fallback: bool,
+}
+
+impl FileRegion {
+    /// Returns the file offset of the next byte to send.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Returns the number of bytes remaining to send.
+    pub fn len(&self) -> u64 {
+        self.end - self.offset
+    }
+
+    /// Returns true iff the entire region has been sent.
+    pub fn is_empty(&self) -> bool {
+        self.offset == self.end
+    }
+
+    /// Sends some of the remaining region to `out` with a single `sendfile(2)` call, returning
+    /// the number of bytes sent.
+    ///
+    /// If `out` is non-blocking, this may return an error of kind `WouldBlock`; the caller should
+    /// wait for `out` to become writable and try again. If the kernel doesn't support `sendfile`
+    /// for this pair of file descriptors, falls back to `pread(2)` and `write(2)` for this and all
+    /// subsequent calls.
+    ///
+    /// Returns an error of kind `UnexpectedEof` if the file is shorter than expected, as happens
+    /// when it is truncated after the response's `Content-Length` was computed. The caller
+    /// should then abort the connection.
+    pub fn send_to<W: AsRawFd>(&mut self, out: &W) -> Result<usize, io::Error> {
+        if self.is_empty() {
+            return Ok(0);
+        }
+        let out = out.as_raw_fd();
+        let n = if self.fallback {
+            self.copy_to(out)?
+        } else {
+            let mut off = self.offset as ::libc::off64_t;
+            let count = ::std::cmp::min(self.len(), MAX_SENDFILE_LEN) as usize;
+            let r = unsafe { ::libc::sendfile64(out, self.file.f.as_raw_fd(), &mut off, count) };
+            if r < 0 {
+                let e = io::Error::last_os_error();
+                match e.raw_os_error() {
+                    Some(::libc::EINVAL) | Some(::libc::ENOSYS) => {
+                        self.fallback = true;
+                        self.copy_to(out)?
+                    }
+                    _ => return Err(e),
+                }
+            } else {
+                r as usize
+            }
+        };
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "file is shorter than expected",
+            ));
+        }
+        self.offset += n as u64;
+        Ok(n)
+    }
+
+    /// Sends the entire remaining region to a blocking `out`.
+    pub fn send_all_to<W: AsRawFd>(&mut self, out: &W) -> Result<(), io::Error> {
+        while !self.is_empty() {
+            match self.send_to(out) {
+                Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Copies some of the remaining region to `out` via userspace.
+    fn copy_to(&self, out: RawFd) -> Result<usize, io::Error> {
+        let len = ::std::cmp::min(self.len(), FALLBACK_BUF_LEN as u64) as usize;
+        let mut buf = vec![0u8; len];
+        let read = self.file.f.read_at(&mut buf, self.offset)?;
+        if read == 0 {
+            return Ok(0);
+        }
+        let r = unsafe { ::libc::write(out, buf.as_ptr() as *const ::libc::c_void, read) };
+        if r < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(r as usize)
+    }
+}
+
+impl AsRawFd for FileRegion {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.f.as_raw_fd()
+    }
+}
+
+/// Serves a `ChunkedReadFile` as in `serve`, also returning a `FileRegion` if the response body
+/// is a single contiguous region of the file.
+///
+/// That's the case for `200 OK` and single-range `206 Partial Content` responses to `GET`
+/// requests. For other responses (`HEAD` requests, `multipart/byteranges`, errors, and
+/// conditional GET), there's no region to send.
+///
+/// The returned response is always complete; its body reads the same bytes via `read_at` on
+/// tokio's blocking threads. A connection writer which can use the region should write the
+/// response's status line and headers itself, drop the body, and send the region with
+/// `FileRegion::send_to`. Others can simply pass the response to hyper as usual.
+pub fn serve_file<D, E, P, PI>(
+    file: ChunkedReadFile<D, E>,
+    req: &Request<PI>,
+) -> (Response<P>, Option<FileRegion>)
+where
+    D: 'static + Send + Buf + From<Vec<u8>> + From<&'static [u8]>,
+    E: 'static + Send + Into<Box<dyn ::std::error::Error + Send + Sync>> + From<Box<io::Error>>,
+    P: http_body::Body + From<BoxStream<'static, Result<D, E>>>,
+{
+    let inner = Arc::clone(&file.inner);
+    let (resp, range) = serving::serve_inner(file, req, &serving::ServeOptions::default());
+    let region = range.map(|r| FileRegion {
+        file: inner,
+        offset: r.start,
+        end: r.end,
+        fallback: false,
+    });
+    (resp, region)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::serve_file;
+    use crate::file::ChunkedReadFile;
+    use crate::{Body, BoxError};
+    use bytes::Bytes;
+    use http::header::{self, HeaderMap};
+    use http::{Method, Request, StatusCode};
+    use std::fs::File;
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixStream;
+    use tempdir::TempDir;
+
+    type Crf = ChunkedReadFile<Bytes, BoxError>;
+
+    #[test]
+    fn regions() {
+        let tmp = TempDir::new("http-sendfile").unwrap();
+        let p = tmp.path().join("f");
+        let data = b"0123456789".repeat(100);
+        File::create(&p).unwrap().write_all(&data).unwrap();
+        let crf = || Crf::new(File::open(&p).unwrap(), HeaderMap::new()).unwrap();
+
+        // Whole file.
+        let req = Request::new(());
+        let (resp, region) = serve_file::<_, _, Body, _>(crf(), &req);
+        assert_eq!(StatusCode::OK, resp.status());
+        let mut region = region.unwrap();
+        assert_eq!((0, 1000), (region.offset(), region.len()));
+        let (a, mut b) = UnixStream::pair().unwrap();
+        region.send_all_to(&a).unwrap();
+        assert!(region.is_empty());
+        drop(a);
+        let mut buf = Vec::new();
+        b.read_to_end(&mut buf).unwrap();
+        assert_eq!(&data[..], &buf[..]);
+
+        // Single range.
+        let req = Request::builder()
+            .header(header::RANGE, "bytes=2-4")
+            .body(())
+            .unwrap();
+        let (resp, region) = serve_file::<_, _, Body, _>(crf(), &req);
+        assert_eq!(StatusCode::PARTIAL_CONTENT, resp.status());
+        let mut region = region.unwrap();
+        assert_eq!((2, 3), (region.offset(), region.len()));
+        let out_path = tmp.path().join("out");
+        let out = File::create(&out_path).unwrap();
+        region.send_all_to(&out).unwrap();
+        let mut buf = Vec::new();
+        File::open(&out_path)
+            .unwrap()
+            .read_to_end(&mut buf)
+            .unwrap();
+        assert_eq!(b"234", &buf[..]);
+
+        // No region for HEAD or multipart responses.
+        let req = Request::builder().method(Method::HEAD).body(()).unwrap();
+        assert!(serve_file::<_, _, Body, _>(crf(), &req).1.is_none());
+        let req = Request::builder()
+            .header(header::RANGE, "bytes=0-0,999-999")
+            .body(())
+            .unwrap();
+        let (resp, region) = serve_file::<_, _, Body, _>(crf(), &req);
+        assert_eq!(StatusCode::PARTIAL_CONTENT, resp.status());
+        assert!(region.is_none());
+    }
+
+    #[test]
+    fn truncated() {
+        let tmp = TempDir::new("http-sendfile").unwrap();
+        let p = tmp.path().join("f");
+        File::create(&p).unwrap().write_all(b"0123456789").unwrap();
+        let crf = Crf::new(File::open(&p).unwrap(), HeaderMap::new()).unwrap();
+        let (_resp, region) = serve_file::<_, _, Body, _>(crf, &Request::new(()));
+        let mut region = region.unwrap();
+        File::create(&p).unwrap(); // truncates.
+        let (a, _b) = UnixStream::pair().unwrap();
+        let e = region.send_all_to(&a).unwrap_err();
+        assert_eq!(::std::io::ErrorKind::UnexpectedEof, e.kind());
+    }
+}
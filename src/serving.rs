@@ -6,46 +6,46 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use super::Entity;
-use etag;
-use futures::future;
-use futures::stream;
-use futures::{self, Stream};
+use crate::etag;
+use crate::range::{self, RangePolicy};
+use crate::Entity;
+use futures_util::future;
+use futures_util::stream::{self, BoxStream, StreamExt};
 use http::header::{self, HeaderMap, HeaderValue};
 use http::{self, Method, Request, Response, StatusCode};
 use httpdate::{fmt_http_date, parse_http_date};
-use hyper::body::Payload;
-use range;
 use smallvec::SmallVec;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
 use std::io::Write;
 use std::ops::Range;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 const MAX_DECIMAL_U64_BYTES: usize = 20; // u64::max_value().to_string().len()
 
-fn parse_modified_hdrs(
+pub(crate) fn parse_modified_hdrs(
     etag: &Option<HeaderValue>,
     req_hdrs: &HeaderMap,
     last_modified: Option<SystemTime>,
 ) -> Result<(bool, bool), &'static str> {
     let precondition_failed = if !etag::any_match(etag, req_hdrs)? {
         true
-    } else if let (Some(ref m), Some(ref since)) =
+    } else if let (Some(m), Some(since)) =
         (last_modified, req_hdrs.get(header::IF_UNMODIFIED_SINCE))
     {
-        const ERR: &'static str = "Unparseable If-Unmodified-Since";
-        *m > parse_http_date(since.to_str().map_err(|_| ERR)?).map_err(|_| ERR)?
+        const ERR: &str = "Unparseable If-Unmodified-Since";
+        m > parse_http_date(since.to_str().map_err(|_| ERR)?).map_err(|_| ERR)?
     } else {
         false
     };
 
-    let not_modified = if !etag::none_match(&etag, req_hdrs).unwrap_or(true) {
+    let not_modified = if !etag::none_match(etag, req_hdrs).unwrap_or(true) {
         true
-    } else if let (Some(ref m), Some(ref since)) =
+    } else if let (Some(m), Some(since)) =
         (last_modified, req_hdrs.get(header::IF_MODIFIED_SINCE))
     {
-        const ERR: &'static str = "Unparseable If-Modified-Since";
-        *m <= parse_http_date(since.to_str().map_err(|_| ERR)?).map_err(|_| ERR)?
+        const ERR: &str = "Unparseable If-Modified-Since";
+        m <= parse_http_date(since.to_str().map_err(|_| ERR)?).map_err(|_| ERR)?
     } else {
         false
     };
@@ -53,46 +53,175 @@ fn parse_modified_hdrs(
     Ok((precondition_failed, not_modified))
 }
 
-fn static_body<E: Entity>(
-    s: &'static str,
-) -> Box<Stream<Item = E::Data, Error = E::Error> + Send> {
-    Box::new(stream::once(Ok(s.as_bytes().into())))
+/// Returns true iff the `If-Range` value `if_range` is a date which matches `last_modified`.
+///
+/// Uses the strong validation rules for an origin server described in [RFC 7232 section
+/// 2.2.2](https://tools.ietf.org/html/rfc7232#section-2.2.2): the last modified time is only
+/// considered strong if it is at least one second before `date`, the time sent in the `Date`
+/// header. Otherwise the resource could have changed twice within the supplied second, so it
+/// never matches. [RFC 7233 section 3.2](https://tools.ietf.org/html/rfc7233#section-3.2) requires
+/// ignoring a weak `If-Range` and an exact match for a strong one.
+fn date_if_range_matches(
+    if_range: &[u8],
+    last_modified: Option<SystemTime>,
+    date: SystemTime,
+) -> bool {
+    let m = match last_modified {
+        None => return false,
+        Some(m) => m,
+    };
+    let if_range = match ::std::str::from_utf8(if_range)
+        .ok()
+        .and_then(|s| parse_http_date(s).ok())
+    {
+        None => return false,
+        Some(d) => d,
+    };
+    if m + Duration::from_secs(1) > date {
+        return false;
+    }
+
+    // HTTP dates have one-second resolution, so compare to the Last-Modified as it was sent.
+    let m_secs = match m.duration_since(UNIX_EPOCH) {
+        Ok(d) => UNIX_EPOCH + Duration::from_secs(d.as_secs()),
+        Err(_) => return false,
+    };
+    if_range == m_secs
+}
+
+fn static_body<E: Entity>(s: &'static str) -> BoxStream<'static, Result<E::Data, E::Error>> {
+    once::<E>(s.as_bytes().into())
+}
+
+fn once<E: Entity>(d: E::Data) -> BoxStream<'static, Result<E::Data, E::Error>> {
+    stream::once(future::ready(Ok(d))).boxed()
+}
+
+fn empty_body<E: Entity>() -> BoxStream<'static, Result<E::Data, E::Error>> {
+    stream::empty().boxed()
+}
+
+/// Options for `serve_with`.
+#[derive(Clone, Debug, Default)]
+pub struct ServeOptions {
+    multipart_boundary: Option<String>,
+    range_policy: RangePolicy,
+}
+
+impl ServeOptions {
+    /// Returns the default options, as used by `serve`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Uses the given boundary for `multipart/byteranges` responses, rather than a random one.
+    ///
+    /// This is intended for deterministic tests. The boundary must not appear within the entity's
+    /// body; a random one makes that overwhelmingly likely for any entity. To avoid quoting in the
+    /// `Content-Type` header, it must be 1 to 70 characters, each an ASCII letter or digit or one
+    /// of `'+_-.`. Panics otherwise.
+    pub fn with_multipart_boundary(self, boundary: &str) -> Self {
+        assert!(
+            !boundary.is_empty()
+                && boundary.len() <= 70
+                && boundary
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b"'+_-.".contains(&b)),
+            "invalid multipart boundary {:?}",
+            boundary
+        );
+        ServeOptions {
+            multipart_boundary: Some(boundary.to_owned()),
+            ..self
+        }
+    }
+
+    /// Applies the given policy to `Range` requests. The default allows any satisfiable ranges.
+    pub fn with_range_policy(self, range_policy: RangePolicy) -> Self {
+        ServeOptions {
+            range_policy,
+            ..self
+        }
+    }
 }
 
-fn empty_body<E: Entity>() -> Box<Stream<Item = E::Data, Error = E::Error> + Send> {
-    Box::new(stream::empty())
+/// Returns a random boundary for a `multipart/byteranges` response.
+///
+/// This has 128 bits from std's randomly-keyed SipHash. That isn't a cryptographic generator,
+/// but the boundary can't be predicted by whoever wrote the entity's body, nor is it likely to
+/// appear there by chance.
+fn random_boundary() -> String {
+    let mut b = String::with_capacity(32);
+    for _ in 0..2 {
+        let h = RandomState::new().build_hasher().finish();
+        b.push_str(&format!("{:016x}", h));
+    }
+    b
 }
 
 /// Serves GET and HEAD requests for a given byte-ranged entity.
 /// Handles conditional & subrange requests.
 /// The caller is expected to have already determined the correct entity and appended
 /// `Expires`, `Cache-Control`, and `Vary` headers if desired.
+///
+/// The response body type `P` is typically `Body`, but may be any `http_body::Body` which can be
+/// constructed from the entity's stream.
 pub fn serve<
     E: Entity,
-    P: Payload + From<Box<Stream<Item = E::Data, Error = E::Error> + Send>>,
+    P: http_body::Body + From<BoxStream<'static, Result<E::Data, E::Error>>>,
+    PI,
+>(
+    e: E,
+    req: &Request<PI>,
+) -> Response<P> {
+    serve_inner(e, req, &ServeOptions::default()).0
+}
+
+/// Serves as in `serve`, with the given options.
+pub fn serve_with<
+    E: Entity,
+    P: http_body::Body + From<BoxStream<'static, Result<E::Data, E::Error>>>,
     PI,
 >(
     e: E,
     req: &Request<PI>,
+    options: &ServeOptions,
 ) -> Response<P> {
+    serve_inner(e, req, options).0
+}
+
+/// Serves as in `serve`, also returning the range of the entity sent as the body if it is a
+/// single contiguous range (rather than a static message or `multipart/byteranges`).
+pub(crate) fn serve_inner<
+    E: Entity,
+    P: http_body::Body + From<BoxStream<'static, Result<E::Data, E::Error>>>,
+    PI,
+>(
+    e: E,
+    req: &Request<PI>,
+    options: &ServeOptions,
+) -> (Response<P>, Option<Range<u64>>) {
     if *req.method() != Method::GET && *req.method() != Method::HEAD {
-        return Response::builder()
+        let res = Response::builder()
             .status(StatusCode::METHOD_NOT_ALLOWED)
             .header(header::ALLOW, HeaderValue::from_static("get, head"))
             .body(static_body::<E>("This resource only supports GET and HEAD.").into())
             .unwrap();
+        return (res, None);
     }
 
     let last_modified = e.last_modified();
     let etag = e.etag();
+    let now = SystemTime::now();
 
     let (precondition_failed, not_modified) =
         match parse_modified_hdrs(&etag, req.headers(), last_modified) {
             Err(s) => {
-                return Response::builder()
+                let res = Response::builder()
                     .status(StatusCode::BAD_REQUEST)
                     .body(static_body::<E>(s).into())
-                    .unwrap()
+                    .unwrap();
+                return (res, None);
             }
             Ok(p) => p,
         };
@@ -102,7 +231,7 @@ pub fn serve<
     // RFC 2616) iff the client didn't specify If-Range.
     let mut range_hdr = req.headers().get(header::RANGE);
     let include_entity_headers_on_range = match req.headers().get(header::IF_RANGE) {
-        Some(ref if_range) => {
+        Some(if_range) => {
             let if_range = if_range.as_bytes();
             if if_range.starts_with(b"W/\"") || if_range.starts_with(b"\"") {
                 // etag case.
@@ -117,11 +246,9 @@ pub fn serve<
                     range_hdr = None;
                     true
                 }
+            } else if date_if_range_matches(if_range, last_modified, now) {
+                false
             } else {
-                // Date case.
-                // Use the strong validation rules for an origin server:
-                // <https://tools.ietf.org/html/rfc7232#section-2.2.2>.
-                // The resource could have changed twice in the supplied second, so never match.
                 range_hdr = None;
                 true
             }
@@ -129,48 +256,56 @@ pub fn serve<
         None => true,
     };
 
-    let mut res = Response::builder();
-    res.header(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    let mut res =
+        Response::builder().header(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
     if let Some(m) = last_modified {
         // See RFC 7232 section 2.2.1 <https://tools.ietf.org/html/rfc7232#section-2.2.1>: the
         // Last-Modified must not exceed the Date. To guarantee this, set the Date now rather than
         // let hyper set it.
-        let d = SystemTime::now();
-        res.header(header::DATE, &*fmt_http_date(d));
-        let clamped_m = ::std::cmp::min(m, d);
-        res.header(header::LAST_MODIFIED, &*fmt_http_date(clamped_m));
+        res = res.header(header::DATE, &*fmt_http_date(now));
+        let clamped_m = ::std::cmp::min(m, now);
+        res = res.header(header::LAST_MODIFIED, &*fmt_http_date(clamped_m));
     }
     if let Some(e) = etag {
-        res.header(http::header::ETAG, e);
+        res = res.header(http::header::ETAG, e);
     }
 
     if precondition_failed {
-        res.status(StatusCode::PRECONDITION_FAILED);
-        return res.body(static_body::<E>("Precondition failed").into())
+        let res = res
+            .status(StatusCode::PRECONDITION_FAILED)
+            .body(static_body::<E>("Precondition failed").into())
             .unwrap();
+        return (res, None);
     }
 
     if not_modified {
-        res.status(StatusCode::NOT_MODIFIED);
-        return res.body(empty_body::<E>().into()).unwrap();
+        let res = res
+            .status(StatusCode::NOT_MODIFIED)
+            .body(empty_body::<E>().into())
+            .unwrap();
+        return (res, None);
     }
 
     let len = e.len();
-    let (range, include_entity_headers) = match range::parse(range_hdr, len) {
+    let (range, include_entity_headers) = match options
+        .range_policy
+        .apply(range::parse(range_hdr, len))
+    {
         range::ResolvedRanges::None => (0..len, true),
         range::ResolvedRanges::Satisfiable(rs) => {
             if rs.len() == 1 {
-                res.header(
-                    header::CONTENT_RANGE,
-                    fmt_ascii_val!(
-                        MAX_DECIMAL_U64_BYTES * 3 + "bytes -/".len(),
-                        "bytes {}-{}/{}",
-                        rs[0].start,
-                        rs[0].end - 1,
-                        len
-                    ),
-                );
-                res.status(StatusCode::PARTIAL_CONTENT);
+                res = res
+                    .header(
+                        header::CONTENT_RANGE,
+                        fmt_ascii_val!(
+                            MAX_DECIMAL_U64_BYTES * 3 + "bytes -/".len(),
+                            "bytes {}-{}/{}",
+                            rs[0].start,
+                            rs[0].end - 1,
+                            len
+                        ),
+                    )
+                    .status(StatusCode::PARTIAL_CONTENT);
                 (rs[0].clone(), include_entity_headers_on_range)
             } else {
                 // Before serving multiple ranges via multipart/byteranges, estimate the total
@@ -178,67 +313,65 @@ pub fn serve<
                 // more than simply serving the whole entity, do that instead.
                 let est_len: u64 = rs.iter().map(|r| 80 + r.end - r.start).sum();
                 if est_len < len {
-                    return send_multipart(e, req, res, rs, len, include_entity_headers_on_range);
+                    let res = send_multipart(
+                        e,
+                        req,
+                        res,
+                        rs,
+                        len,
+                        include_entity_headers_on_range,
+                        options,
+                    );
+                    return (res, None);
                 }
 
                 (0..len, true)
             }
         }
         range::ResolvedRanges::NotSatisfiable => {
-            res.header(
-                http::header::CONTENT_RANGE,
-                fmt_ascii_val!(MAX_DECIMAL_U64_BYTES + "bytes */".len(), "bytes */{}", len),
-            );
-            res.status(StatusCode::RANGE_NOT_SATISFIABLE);
-            return res.body(empty_body::<E>().into()).unwrap();
+            let res = res
+                .header(
+                    http::header::CONTENT_RANGE,
+                    fmt_ascii_val!(MAX_DECIMAL_U64_BYTES + "bytes */".len(), "bytes */{}", len),
+                )
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .body(empty_body::<E>().into())
+                .unwrap();
+            return (res, None);
         }
     };
-    res.header(
+    let res = res.header(
         header::CONTENT_LENGTH,
         fmt_ascii_val!(MAX_DECIMAL_U64_BYTES, "{}", range.end - range.start),
     );
-    let body = match *req.method() {
-        Method::HEAD => empty_body::<E>(),
-        _ => e.get_range(range),
+    let (body, body_range) = match *req.method() {
+        Method::HEAD => (empty_body::<E>(), None),
+        _ => (e.get_range(range.clone()), Some(range)),
     };
     let mut res = res.body(body.into()).unwrap();
     if include_entity_headers {
         e.add_headers(res.headers_mut());
     }
-    res
-}
-
-enum InnerBody<B, C> {
-    Once(Option<C>),
-    B(B),
-}
-
-impl<B, C> Stream for InnerBody<B, C>
-where
-    B: Stream<Item = C>,
-{
-    type Item = C;
-    type Error = B::Error;
-    fn poll(&mut self) -> ::futures::Poll<Option<C>, Self::Error> {
-        match *self {
-            InnerBody::Once(ref mut o) => Ok(futures::Async::Ready(o.take())),
-            InnerBody::B(ref mut b) => b.poll(),
-        }
-    }
+    (res, body_range)
 }
 
 fn send_multipart<
     E: Entity,
-    P: Payload + From<Box<Stream<Item = E::Data, Error = E::Error> + Send>>,
+    P: http_body::Body + From<BoxStream<'static, Result<E::Data, E::Error>>>,
     PI,
 >(
     e: E,
     req: &Request<PI>,
-    mut res: http::response::Builder,
+    res: http::response::Builder,
     rs: SmallVec<[Range<u64>; 1]>,
     len: u64,
     include_entity_headers: bool,
+    options: &ServeOptions,
 ) -> Response<P> {
+    let boundary = match options.multipart_boundary {
+        Some(ref b) => b.clone(),
+        None => random_boundary(),
+    };
     let mut body_len = 0;
     let mut each_part_headers = Vec::new();
     if include_entity_headers {
@@ -263,7 +396,8 @@ fn send_multipart<
         let mut buf = Vec::with_capacity(64 + each_part_headers.len());
         write!(
             &mut buf,
-            "\r\n--B\r\nContent-Range: bytes {}-{}/{}\r\n",
+            "\r\n--{}\r\nContent-Range: bytes {}-{}/{}\r\n",
+            boundary,
             r.start,
             r.end - 1,
             len
@@ -272,41 +406,172 @@ fn send_multipart<
         body_len += buf.len() as u64 + r.end - r.start;
         part_headers.push(buf);
     }
-    const TRAILER: &[u8] = b"\r\n--B--\r\n";
-    body_len += TRAILER.len() as u64;
+    let mut trailer = format!("\r\n--{}--\r\n", boundary).into_bytes();
+    body_len += trailer.len() as u64;
 
-    res.header(
-        header::CONTENT_LENGTH,
-        fmt_ascii_val!(MAX_DECIMAL_U64_BYTES, "{}", body_len),
-    );
-    res.header(
-        header::CONTENT_TYPE,
-        HeaderValue::from_static("multipart/byteranges; boundary=B"),
-    );
-    res.status(StatusCode::PARTIAL_CONTENT);
+    let res = res
+        .header(
+            header::CONTENT_LENGTH,
+            fmt_ascii_val!(MAX_DECIMAL_U64_BYTES, "{}", body_len),
+        )
+        .header(
+            header::CONTENT_TYPE,
+            HeaderValue::from_str(&format!("multipart/byteranges; boundary={}", boundary))
+                .expect("boundary is valid"),
+        )
+        .status(StatusCode::PARTIAL_CONTENT);
 
     if *req.method() == Method::HEAD {
         return res.body(empty_body::<E>().into()).unwrap();
     }
 
-    // Create bodies, a stream of E::Stream values as follows: each part's header and body
+    // Create bodies, a stream of streams as follows: each part's header and body
     // (the latter produced lazily), then the overall trailer.
-    let bodies = ::futures::stream::unfold(0, move |state| {
+    let n = rs.len();
+    let bodies = stream::iter((0..2 * n + 1).map(move |state| {
         let i = state >> 1;
-        let odd = (state & 1) == 1;
-        let body = if i == rs.len() && odd {
-            return None;
-        } else if i == rs.len() {
-            InnerBody::Once(Some(TRAILER.into()))
-        } else if odd {
-            InnerBody::B(e.get_range(rs[i].clone()))
+        if i == n {
+            once::<E>(::std::mem::take(&mut trailer).into())
+        } else if (state & 1) == 1 {
+            e.get_range(rs[i].clone())
         } else {
-            let v = ::std::mem::replace(&mut part_headers[i], Vec::new());
-            InnerBody::Once(Some(v.into()))
+            once::<E>(::std::mem::take(&mut part_headers[i]).into())
+        }
+    }));
+
+    res.body(bodies.flatten().boxed().into()).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{date_if_range_matches, serve_with, ServeOptions};
+    use crate::{Body, BoxError, InMemoryEntity, RangePolicy, RangeViolation};
+    use bytes::Bytes;
+    use futures::executor::block_on;
+    use http::{header, Request, Response, StatusCode};
+    use http_body_util::BodyExt;
+    use httpdate::parse_http_date;
+    use std::time::Duration;
+
+    /// Serves a multipart response, returning its boundary and body.
+    fn multipart(options: &ServeOptions) -> (String, Bytes) {
+        let e: InMemoryEntity<Vec<u8>, Bytes, BoxError> =
+            InMemoryEntity::new(b"0123456789".repeat(100));
+        let req = Request::get("/")
+            .header(header::RANGE, "bytes=0-0,999-999")
+            .body(())
+            .unwrap();
+        let resp: Response<Body> = serve_with(e, &req, options);
+        assert_eq!(StatusCode::PARTIAL_CONTENT, resp.status());
+        let ct = resp.headers()[header::CONTENT_TYPE].to_str().unwrap();
+        let boundary = ct
+            .strip_prefix("multipart/byteranges; boundary=")
+            .unwrap()
+            .to_owned();
+        let len: usize = resp.headers()[header::CONTENT_LENGTH]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        let body = block_on(resp.into_body().collect()).unwrap().to_bytes();
+        assert_eq!(len, body.len());
+        (boundary, body)
+    }
+
+    #[test]
+    fn multipart_boundary() {
+        let (b1, body) = multipart(&ServeOptions::new());
+        let (b2, _) = multipart(&ServeOptions::new());
+        assert_eq!(32, b1.len());
+        assert_ne!(b1, b2);
+        let expected = format!(
+            "\r\n--{b}\r\nContent-Range: bytes 0-0/1000\r\n\r\n0\
+             \r\n--{b}\r\nContent-Range: bytes 999-999/1000\r\n\r\n9\
+             \r\n--{b}--\r\n",
+            b = b1
+        );
+        assert_eq!(expected.as_bytes(), &body[..]);
+
+        let (b, body) = multipart(&ServeOptions::new().with_multipart_boundary("x'+_-.Y"));
+        assert_eq!("x'+_-.Y", b);
+        assert!(body.starts_with(b"\r\n--x'+_-.Y\r\n"));
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid multipart boundary")]
+    fn invalid_multipart_boundary() {
+        ServeOptions::new().with_multipart_boundary("a b");
+    }
+
+    #[test]
+    fn range_policy() {
+        let serve = |range: &str, policy: RangePolicy| -> Response<Body> {
+            let e: InMemoryEntity<Vec<u8>, Bytes, BoxError> =
+                InMemoryEntity::new(b"0123456789".repeat(100));
+            let req = Request::get("/")
+                .header(header::RANGE, range)
+                .body(())
+                .unwrap();
+            serve_with(e, &req, &ServeOptions::new().with_range_policy(policy))
         };
-        Some(future::ok::<_, E::Error>((body, state + 1)))
-    });
+        let policy = RangePolicy::new()
+            .with_max_ranges(2)
+            .with_coalesce(true)
+            .with_reject_descending(true);
+
+        // Coalesced down to a single range.
+        let resp = serve("bytes=0-9,5-19,20-29", policy.clone());
+        assert_eq!(StatusCode::PARTIAL_CONTENT, resp.status());
+        assert_eq!("bytes 0-29/1000", resp.headers()[header::CONTENT_RANGE]);
 
-    let body: Box<Stream<Item = E::Data, Error = E::Error> + Send> = Box::new(bodies.flatten());
-    res.body(body.into()).unwrap()
+        // Too many ranges.
+        let resp = serve("bytes=0-0,2-2,4-4", policy.clone());
+        assert_eq!(StatusCode::OK, resp.status());
+        assert!(resp.headers().get(header::CONTENT_RANGE).is_none());
+        assert_eq!("1000", resp.headers()[header::CONTENT_LENGTH]);
+
+        // Non-ASCII bytes, ignored.
+        let resp = serve("bytes=0-9\u{e9}", policy.clone());
+        assert_eq!(StatusCode::OK, resp.status());
+
+        // Descending ranges, rejected.
+        let resp = serve("bytes=4-4,0-0", policy.with_violation(RangeViolation::Reject));
+        assert_eq!(StatusCode::RANGE_NOT_SATISFIABLE, resp.status());
+        assert_eq!("bytes */1000", resp.headers()[header::CONTENT_RANGE]);
+    }
+
+    #[test]
+    fn date_if_range() {
+        let if_range = b"Sun, 06 Nov 1994 08:49:37 GMT";
+        let m = parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT").unwrap();
+        let later = m + Duration::from_secs(60);
+
+        // Exact match of a strong validator.
+        assert!(date_if_range_matches(if_range, Some(m), later));
+        assert!(date_if_range_matches(if_range, Some(m), m + Duration::from_secs(1)));
+
+        // A sub-second last modified time matches the date as it would have been sent.
+        assert!(date_if_range_matches(
+            if_range,
+            Some(m + Duration::from_millis(500)),
+            later
+        ));
+
+        // Weak validator: modified within a second of the date.
+        assert!(!date_if_range_matches(if_range, Some(m), m));
+        assert!(!date_if_range_matches(
+            if_range,
+            Some(m),
+            m + Duration::from_millis(999)
+        ));
+
+        // Mismatches.
+        assert!(!date_if_range_matches(if_range, None, later));
+        assert!(!date_if_range_matches(
+            if_range,
+            Some(m + Duration::from_secs(1)),
+            later
+        ));
+        assert!(!date_if_range_matches(b"garbage", Some(m), later));
+    }
 }
@@ -0,0 +1,189 @@
+// Copyright (c) 2018 Scott Lamb <slamb@slamb.org>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use crate::content_etag;
+use crate::Entity;
+use futures_util::stream::BoxStream;
+use http::header::{HeaderMap, HeaderValue};
+use sha2::{Digest, Sha256};
+use std::ops::Range;
+use std::time::SystemTime;
+
+/// A region of another entity, served as an entity of its own, such as a member stored
+/// uncompressed within a bundle file.
+///
+/// The slice's etag is derived from the parent's etag and the range, so it differs from the
+/// parent's and other slices'. It's weak if the parent's is weak and absent if the parent's is
+/// absent. The last modified time is the parent's. The headers are the parent's unless replaced
+/// via `with_headers`.
+///
+/// ```
+/// # use bytes::Bytes;
+/// # use http::header::{self, HeaderMap, HeaderValue};
+/// # use http_serve::{BoxError, InMemoryEntity, Slice};
+/// # fn main() {
+/// let bundle: InMemoryEntity<_, Bytes, BoxError> = InMemoryEntity::new(&b"aaaabbbb"[..]);
+/// let mut headers = HeaderMap::new();
+/// headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+/// let member = Slice::new(bundle, 4..8).with_headers(headers);
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct Slice<E: Entity> {
+    inner: E,
+    range: Range<u64>,
+    headers: Option<HeaderMap>,
+}
+
+impl<E: Entity> Slice<E> {
+    /// Creates a new slice of `inner`. Panics if `range` isn't within `inner`.
+    pub fn new(inner: E, range: Range<u64>) -> Self {
+        assert!(
+            range.start <= range.end && range.end <= inner.len(),
+            "slice {:?} out of range for entity of length {}",
+            range,
+            inner.len()
+        );
+        Slice {
+            inner,
+            range,
+            headers: None,
+        }
+    }
+
+    /// Sends the given headers rather than the parent's.
+    pub fn with_headers(self, headers: HeaderMap) -> Self {
+        Slice {
+            headers: Some(headers),
+            ..self
+        }
+    }
+
+    /// Returns the parent entity.
+    pub fn get_ref(&self) -> &E {
+        &self.inner
+    }
+
+    /// Returns the range of the parent entity.
+    pub fn range(&self) -> Range<u64> {
+        self.range.clone()
+    }
+}
+
+impl<E: Entity> Entity for Slice<E> {
+    type Data = E::Data;
+    type Error = E::Error;
+
+    fn len(&self) -> u64 {
+        self.range.end - self.range.start
+    }
+
+    fn get_range(&self, range: Range<u64>) -> BoxStream<'static, Result<E::Data, E::Error>> {
+        self.inner
+            .get_range(self.range.start + range.start..self.range.start + range.end)
+    }
+
+    fn add_headers(&self, h: &mut HeaderMap) {
+        match self.headers {
+            Some(ref headers) => h.extend(headers.iter().map(|(k, v)| (k.clone(), v.clone()))),
+            None => self.inner.add_headers(h),
+        }
+    }
+
+    fn etag(&self) -> Option<HeaderValue> {
+        let parent = self.inner.etag()?;
+        let mut h = Sha256::new();
+        h.update(parent.as_bytes());
+        h.update(self.range.start.to_be_bytes());
+        h.update(self.range.end.to_be_bytes());
+        let weak = parent.as_bytes().starts_with(b"W/");
+        Some(content_etag::format_derived_etag(&h.finalize(), weak))
+    }
+
+    fn last_modified(&self) -> Option<SystemTime> {
+        self.inner.last_modified()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Slice;
+    use crate::{Body, BoxError as E, Entity, InMemoryEntity};
+    use bytes::Bytes;
+    use futures::executor::block_on;
+    use http::header::{self, HeaderMap, HeaderValue};
+    use http::{Request, Response, StatusCode};
+
+    type Mem = InMemoryEntity<&'static [u8], Bytes, E>;
+
+    fn parent() -> Mem {
+        Mem::new(b"0123456789").with_content_type(HeaderValue::from_static("text/plain"))
+    }
+
+    #[test]
+    fn get_range() {
+        let s = Slice::new(parent(), 2..8);
+        assert_eq!(6, s.len());
+        assert_eq!(2..8, s.range());
+        let body = |r| block_on(crate::body_bytes(s.get_range(r))).unwrap();
+        assert_eq!(b"234567", &body(0..6)[..]);
+        assert_eq!(b"34", &body(1..3)[..]);
+        assert_eq!(b"", &body(6..6)[..]);
+    }
+
+    #[test]
+    #[should_panic(expected = "out of range")]
+    fn out_of_range() {
+        Slice::new(parent(), 5..11);
+    }
+
+    #[test]
+    fn etag() {
+        let p = parent();
+        let s = Slice::new(p.clone(), 2..8);
+        let e = s.etag().unwrap();
+        assert!(e.as_bytes().starts_with(b"\""));
+        assert_ne!(p.etag(), Some(e.clone()));
+        assert_ne!(Slice::new(p.clone(), 2..7).etag(), Some(e.clone()));
+        assert_eq!(Slice::new(p.clone(), 2..8).etag(), Some(e));
+
+        let weak = p.clone().with_etag(Some(HeaderValue::from_static("W/\"x\"")));
+        assert!(Slice::new(weak, 0..1).etag().unwrap().as_bytes().starts_with(b"W/\""));
+        assert_eq!(None, Slice::new(p.with_etag(None), 0..1).etag());
+    }
+
+    #[test]
+    fn headers() {
+        let mut h = HeaderMap::new();
+        Slice::new(parent(), 0..1).add_headers(&mut h);
+        assert_eq!("text/plain", h[header::CONTENT_TYPE]);
+
+        let mut replacement = HeaderMap::new();
+        replacement.insert(header::CONTENT_TYPE, HeaderValue::from_static("image/png"));
+        let mut h = HeaderMap::new();
+        Slice::new(parent(), 0..1)
+            .with_headers(replacement)
+            .add_headers(&mut h);
+        assert_eq!("image/png", h[header::CONTENT_TYPE]);
+    }
+
+    #[test]
+    fn serve() {
+        let s = Slice::new(parent(), 2..8);
+        let req = Request::get("/")
+            .header(header::RANGE, "bytes=1-2")
+            .header(header::IF_MATCH, s.etag().unwrap())
+            .body(())
+            .unwrap();
+        let resp: Response<Body> = crate::serve(s, &req);
+        assert_eq!(StatusCode::PARTIAL_CONTENT, resp.status());
+        assert_eq!("bytes 1-2/6", resp.headers()[header::CONTENT_RANGE]);
+        let body = block_on(crate::body_bytes(resp.into_body().into_stream())).unwrap();
+        assert_eq!(b"34", &body[..]);
+    }
+}
@@ -0,0 +1,396 @@
+// Copyright (c) 2018 Scott Lamb <slamb@slamb.org>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use crate::content_etag::ContentEtagCache;
+use crate::content_type::ContentTypes;
+use crate::file::ChunkedReadFile;
+use crate::listing::DirListing;
+use crate::serving::serve;
+use crate::{Body, BoxError};
+use bytes::{Buf, Bytes};
+use futures_util::stream::BoxStream;
+use http::header::{self, HeaderMap, HeaderValue};
+use http::{Request, Response, StatusCode};
+use hyper::service::Service;
+use std::ffi::OsStr;
+use std::future::Future;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+
+/// The file served for a request naming a directory.
+const INDEX_FILE: &str = "index.html";
+
+/// Serves the files under a root directory, mapping request paths to file paths.
+///
+/// Request paths are percent-decoded and split into segments, each of which must name a single
+/// file or directory: `..`, `NUL`, and segments which would be absolute paths are rejected with
+/// `400 Bad Request`. Missing files produce `404 Not Found`. A directory is served via its
+/// `index.html` or, if enabled with `with_listing`, a `DirListing`. If the request path lacks a
+/// trailing slash, a `301 Moved Permanently` to the path with one is returned instead, so that
+/// relative links within the index resolve correctly.
+///
+/// Each file's `Content-Type` is derived from its extension via a `ContentTypes` table. Files are
+/// served as `ChunkedReadFile`s via `serve`, so byte ranges and conditional GET are
+/// supported. `StaticDir` implements `hyper::service::Service`, performing all filesystem
+/// operations via `tokio::task::spawn_blocking`:
+///
+/// ```no_run
+/// # use http_serve::StaticDir;
+/// # use hyper_util::rt::TokioIo;
+/// # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+/// let dir = StaticDir::new("/var/www");
+/// let listener = tokio::net::TcpListener::bind("127.0.0.1:1337").await?;
+/// loop {
+///     let (tcp, _) = listener.accept().await?;
+///     let conn = hyper::server::conn::http1::Builder::new()
+///         .serve_connection(TokioIo::new(tcp), dir.clone());
+///     tokio::spawn(conn);
+/// }
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct StaticDir {
+    inner: Arc<StaticDirInner>,
+}
+
+#[derive(Clone)]
+struct StaticDirInner {
+    root: PathBuf,
+    content_types: ContentTypes,
+    content_etag: Option<ContentEtagCache>,
+    listing: bool,
+}
+
+impl StaticDir {
+    /// Creates a new StaticDir serving the files under `root`.
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        StaticDir {
+            inner: Arc::new(StaticDirInner {
+                root: root.into(),
+                content_types: ContentTypes::new(),
+                content_etag: None,
+                listing: false,
+            }),
+        }
+    }
+
+    /// Sets the table used to derive each file's `Content-Type` header.
+    ///
+    /// The default is `ContentTypes::new()`.
+    pub fn with_content_types(mut self, content_types: ContentTypes) -> Self {
+        Arc::make_mut(&mut self.inner).content_types = content_types;
+        self
+    }
+
+    /// Uses strong etags derived from file contents, cached in `cache`, as described in
+    /// `ChunkedReadFileBuilder::with_content_etag`.
+    pub fn with_content_etag(mut self, cache: &ContentEtagCache) -> Self {
+        Arc::make_mut(&mut self.inner).content_etag = Some(cache.clone());
+        self
+    }
+
+    /// Sets whether directories without an `index.html` are served as a `DirListing`.
+    ///
+    /// The default is false, which produces `404 Not Found` for such directories.
+    pub fn with_listing(mut self, listing: bool) -> Self {
+        Arc::make_mut(&mut self.inner).listing = listing;
+        self
+    }
+
+    /// Serves the given request.
+    ///
+    /// This performs blocking filesystem operations, so it typically shouldn't be called on the
+    /// tokio reactor thread. The `Service` implementation calls it via `spawn_blocking`. Errors
+    /// other than missing files are returned to the caller.
+    pub fn serve<D, E, P, PI>(&self, req: &Request<PI>) -> Result<Response<P>, io::Error>
+    where
+        D: 'static + Send + Buf + From<Vec<u8>> + From<&'static [u8]>,
+        E: 'static
+            + Send
+            + Into<Box<dyn ::std::error::Error + Send + Sync>>
+            + From<Box<::std::io::Error>>,
+        P: http_body::Body + From<BoxStream<'static, Result<D, E>>>,
+    {
+        let uri_path = req.uri().path();
+        let mut path = match resolve(&self.inner.root, uri_path) {
+            Some(p) => p,
+            None => return Ok(error(StatusCode::BAD_REQUEST, "Invalid path")),
+        };
+        let f = match ::std::fs::File::open(&path) {
+            Ok(f) => f,
+            Err(e) => return not_found_or(e),
+        };
+        let f = if f.metadata()?.is_dir() {
+            if !uri_path.ends_with('/') {
+                // Build the location from the path's non-empty segments, so that a request for
+                // `//host/dir` doesn't redirect to the protocol-relative URL `//host/dir/`.
+                let mut loc = String::with_capacity(uri_path.len() + 1);
+                for seg in uri_path.split('/').filter(|s| !s.is_empty()) {
+                    loc.push('/');
+                    loc.push_str(seg);
+                }
+                loc.push('/');
+                if let Some(q) = req.uri().query() {
+                    loc.push('?');
+                    loc.push_str(q);
+                }
+                let loc = HeaderValue::from_str(&loc)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+                return Ok(Response::builder()
+                    .status(StatusCode::MOVED_PERMANENTLY)
+                    .header(header::LOCATION, loc)
+                    .body(crate::static_body::<D, E>("Moved permanently").into())
+                    .unwrap());
+            }
+            path.push(INDEX_FILE);
+            match ::std::fs::File::open(&path) {
+                Ok(f) => f,
+                Err(ref e) if self.inner.listing && e.kind() == io::ErrorKind::NotFound => {
+                    path.pop();
+                    let l: DirListing<D, E> = DirListing::new(&path, req)?;
+                    let mut resp = serve(l, req);
+                    resp.headers_mut()
+                        .append(header::VARY, HeaderValue::from_static("accept"));
+                    return Ok(resp);
+                }
+                Err(e) => return not_found_or(e),
+            }
+        } else if uri_path.ends_with('/') {
+            return Ok(error(StatusCode::NOT_FOUND, "Not found"));
+        } else {
+            f
+        };
+        let mut headers = HeaderMap::new();
+        self.inner.content_types.add_header(&path, &f, &mut headers)?;
+        let mut b = ChunkedReadFile::<D, E>::builder(f, headers);
+        if let Some(ref c) = self.inner.content_etag {
+            b = b.with_content_etag(c);
+        }
+        let f: ChunkedReadFile<D, E> = b.build()?;
+        Ok(serve(f, req))
+    }
+}
+
+impl<B> Service<Request<B>> for StaticDir {
+    type Response = Response<Body>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response<Body>, io::Error>> + Send>>;
+
+    fn call(&self, req: Request<B>) -> Self::Future {
+        let (parts, _) = req.into_parts();
+        let req = Request::from_parts(parts, ());
+        let dir = self.clone();
+        let f = tokio::task::spawn_blocking(move || {
+            match dir.serve::<Bytes, BoxError, Body, _>(&req) {
+                Ok(r) => r,
+                Err(e) => {
+                    // The error may describe the filesystem, so it's logged rather than sent.
+                    log::error!("error serving {}: {}", req.uri().path(), e);
+                    error(StatusCode::INTERNAL_SERVER_ERROR, "Internal server error")
+                }
+            }
+        });
+        Box::pin(async move { f.await.map_err(io::Error::other) })
+    }
+}
+
+/// Returns a `404 Not Found` response if `e` indicates a missing file, or `e` otherwise.
+fn not_found_or<D, E, P>(e: io::Error) -> Result<Response<P>, io::Error>
+where
+    D: 'static + Send + From<Vec<u8>>,
+    E: 'static + Send,
+    P: From<BoxStream<'static, Result<D, E>>>,
+{
+    // ENOTDIR means a path component which should be a directory is a file instead.
+    if e.kind() == io::ErrorKind::NotFound || e.raw_os_error() == Some(::libc::ENOTDIR) {
+        return Ok(error(StatusCode::NOT_FOUND, "Not found"));
+    }
+    Err(e)
+}
+
+fn error<D, E, P>(status: StatusCode, msg: &'static str) -> Response<P>
+where
+    D: 'static + Send + From<Vec<u8>>,
+    E: 'static + Send,
+    P: From<BoxStream<'static, Result<D, E>>>,
+{
+    Response::builder()
+        .status(status)
+        .body(crate::static_body::<D, E>(msg).into())
+        .unwrap()
+}
+
+/// Returns the value of the given hex digit, or `None` if it isn't one.
+fn hex_digit(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// Percent-decodes `s`, returning `None` if it contains an invalid escape.
+pub(crate) fn percent_decode(s: &str) -> Option<Vec<u8>> {
+    let s = s.as_bytes();
+    let mut out = Vec::with_capacity(s.len());
+    let mut i = 0;
+    while i < s.len() {
+        if s[i] == b'%' {
+            let hi = hex_digit(*s.get(i + 1)?)?;
+            let lo = hex_digit(*s.get(i + 2)?)?;
+            out.push(hi << 4 | lo);
+            i += 3;
+        } else {
+            out.push(s[i]);
+            i += 1;
+        }
+    }
+    Some(out)
+}
+
+/// Maps the request path `uri_path` to a filesystem path under `root`.
+///
+/// Returns `None` if any segment is invalid: not properly percent-encoded, or not a single normal
+/// path component once decoded. Empty segments (as in `a//b` or a trailing slash) are ignored.
+pub(crate) fn resolve(root: &Path, uri_path: &str) -> Option<PathBuf> {
+    let mut path = root.to_path_buf();
+    for seg in uri_path.split('/') {
+        if seg.is_empty() {
+            continue;
+        }
+        let seg = percent_decode(seg)?;
+        if seg.iter().any(|&b| b == b'\0' || b == b'/' || b == b'\\') {
+            return None;
+        }
+        let seg = Path::new(OsStr::from_bytes(&seg));
+        let mut components = seg.components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(_)), None) => path.push(seg),
+            _ => return None,
+        }
+    }
+    Some(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{resolve, StaticDir};
+    use crate::{Body, BoxError};
+    use bytes::Bytes;
+    use http::header;
+    use http::{Request, Response, StatusCode};
+    use http_body_util::BodyExt;
+    use hyper::service::Service;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::path::Path;
+    use tempdir::TempDir;
+
+    #[test]
+    fn resolve_paths() {
+        let root = Path::new("/srv");
+        assert_eq!(Some(root.to_path_buf()), resolve(root, "/"));
+        assert_eq!(Some(root.join("a/b.txt")), resolve(root, "/a//b.txt"));
+        assert_eq!(Some(root.join("a b")), resolve(root, "/a%20b"));
+        assert_eq!(Some(root.join("a")), resolve(root, "/a/"));
+        assert_eq!(None, resolve(root, "/../etc/passwd"));
+        assert_eq!(None, resolve(root, "/a/%2e%2e/b"));
+        assert_eq!(None, resolve(root, "/a/./b"));
+        assert_eq!(None, resolve(root, "/a%2fb"));
+        assert_eq!(None, resolve(root, "/a%00"));
+        assert_eq!(None, resolve(root, "/a%5c..%5cb"));
+        assert_eq!(None, resolve(root, "/a%2"));
+        assert_eq!(None, resolve(root, "/a%zz"));
+    }
+
+    fn get(dir: &StaticDir, uri: &str) -> Response<Body> {
+        let req = Request::get(uri).body(()).unwrap();
+        dir.serve::<Bytes, BoxError, Body, _>(&req).unwrap()
+    }
+
+    async fn body(resp: Response<Body>) -> Vec<u8> {
+        resp.into_body().collect().await.unwrap().to_bytes().to_vec()
+    }
+
+    #[tokio::test]
+    async fn serve() {
+        let tmp = TempDir::new("http-static-dir").unwrap();
+        fs::create_dir(tmp.path().join("sub")).unwrap();
+        fs::create_dir(tmp.path().join("empty")).unwrap();
+        File::create(tmp.path().join("sub/index.html"))
+            .unwrap()
+            .write_all(b"index")
+            .unwrap();
+        File::create(tmp.path().join("f.txt"))
+            .unwrap()
+            .write_all(b"file")
+            .unwrap();
+        let dir = StaticDir::new(tmp.path());
+
+        let resp = get(&dir, "/f.txt");
+        assert_eq!(StatusCode::OK, resp.status());
+        assert_eq!(
+            resp.headers().get(header::CONTENT_TYPE).unwrap(),
+            "text/plain; charset=utf-8"
+        );
+        assert_eq!(b"file", &body(resp).await[..]);
+
+        let resp = get(&dir, "/sub/");
+        assert_eq!(StatusCode::OK, resp.status());
+        assert_eq!(
+            resp.headers().get(header::CONTENT_TYPE).unwrap(),
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(b"index", &body(resp).await[..]);
+
+        let resp = get(&dir, "/sub?x=y");
+        assert_eq!(StatusCode::MOVED_PERMANENTLY, resp.status());
+        assert_eq!(resp.headers().get(header::LOCATION).unwrap(), "/sub/?x=y");
+        let resp = get(&dir, "//sub");
+        assert_eq!(StatusCode::MOVED_PERMANENTLY, resp.status());
+        assert_eq!(resp.headers().get(header::LOCATION).unwrap(), "/sub/");
+
+        assert_eq!(StatusCode::NOT_FOUND, get(&dir, "/missing").status());
+        assert_eq!(StatusCode::NOT_FOUND, get(&dir, "/empty/").status());
+        let listing = dir.clone().with_listing(true);
+        let resp = get(&listing, "/empty/");
+        assert_eq!(StatusCode::OK, resp.status());
+        assert_eq!(resp.headers().get(header::VARY).unwrap(), "accept");
+        assert!(resp.headers().get(header::ETAG).is_some());
+        let resp = get(&listing, "/sub/");
+        assert_eq!(b"index", &body(resp).await[..]);
+        assert_eq!(StatusCode::NOT_FOUND, get(&dir, "/f.txt/").status());
+        assert_eq!(StatusCode::NOT_FOUND, get(&dir, "/f.txt/x").status());
+        assert_eq!(StatusCode::BAD_REQUEST, get(&dir, "/../f.txt").status());
+
+        let req = Request::get("/f.txt")
+            .header(header::RANGE, "bytes=1-2")
+            .body(())
+            .unwrap();
+        let resp = dir.serve::<Bytes, BoxError, Body, _>(&req).unwrap();
+        assert_eq!(StatusCode::PARTIAL_CONTENT, resp.status());
+        assert_eq!(b"il", &body(resp).await[..]);
+    }
+
+    #[tokio::test]
+    async fn service() {
+        let tmp = TempDir::new("http-static-dir").unwrap();
+        File::create(tmp.path().join("f.txt"))
+            .unwrap()
+            .write_all(b"file")
+            .unwrap();
+        let dir = StaticDir::new(tmp.path());
+        let resp = dir.call(Request::get("/f.txt").body(()).unwrap()).await.unwrap();
+        assert_eq!(StatusCode::OK, resp.status());
+        assert_eq!(b"file", &body(resp).await[..]);
+    }
+}
@@ -0,0 +1,549 @@
+// Copyright (c) 2018 Scott Lamb <slamb@slamb.org>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Streaming generation of tar archives of directory trees.
+//!
+//! Each member is a 512-byte ustar header, preceded by a PAX extended header when some field
+//! (a long path, a large size, etc.) doesn't fit, followed by the member's data padded to a
+//! multiple of 512 bytes. The archive ends with two zero blocks.
+
+use crate::content_etag;
+use crate::file::ChunkedReadFile;
+use crate::range::intersect;
+use crate::Entity;
+use bytes::Buf;
+use futures_util::future;
+use futures_util::stream::{self, BoxStream, StreamExt};
+use http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use sha2::{Digest, Sha256};
+use std::cmp;
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+const BLOCK: u64 = 512;
+
+struct Member {
+    /// The member's headers: an optional PAX extended header (with its data) and a ustar header.
+    header: Vec<u8>,
+
+    /// The path of a regular file's contents on disk, or `None` for other types.
+    path: Option<PathBuf>,
+    len: u64,
+    mtime: SystemTime,
+    ino: u64,
+
+    /// The offset of `header` within the archive.
+    offset: u64,
+}
+
+impl Member {
+    fn data_offset(&self) -> u64 {
+        self.offset + self.header.len() as u64
+    }
+
+    fn end(&self) -> u64 {
+        self.data_offset() + padded(self.len)
+    }
+}
+
+/// A tar archive of a directory tree, generated on the fly.
+///
+/// `new` walks the tree once to index it, recording each member's headers. The archive's length
+/// and layout are then known, so it supports byte ranges (and thus resumable downloads) without
+/// ever being written out. Regular files' contents are read only when requested, via
+/// `ChunkedReadFile`; thus the body must be polled within a tokio runtime. If a file's length,
+/// modification time, or inode number has changed since it was indexed, the body stream ends in
+/// an error.
+///
+/// Members are directories, regular files, and symbolic links (which aren't followed), named
+/// relative to the root and sorted by name within each directory. Other file types are skipped.
+/// Each has the mode, owner ids, and modification time of the original. The etag is derived from
+/// the headers and the files' full modification times, device numbers, and inode numbers, and
+/// the last modified time is the latest of the members'.
+///
+/// ```no_run
+/// # use bytes::Bytes;
+/// # use http::header::{self, HeaderValue};
+/// # use http_serve::{BoxError, TarEntity};
+/// # fn main() -> Result<(), std::io::Error> {
+/// let e: TarEntity<Bytes, BoxError> = TarEntity::new("artifacts/build-1234")?.with_header(
+///     header::CONTENT_DISPOSITION,
+///     HeaderValue::from_static("attachment; filename=\"build-1234.tar\""),
+/// );
+/// # Ok(())
+/// # }
+/// ```
+pub struct TarEntity<D, E> {
+    members: Vec<Member>,
+    headers: HeaderMap,
+    etag: HeaderValue,
+    phantom: ::std::marker::PhantomData<fn() -> (D, E)>,
+}
+
+impl<D, E> TarEntity<D, E>
+where
+    D: 'static + Send + Buf + From<Vec<u8>> + From<&'static [u8]>,
+    E: 'static + Send + Into<Box<dyn ::std::error::Error + Send + Sync>> + From<Box<io::Error>>,
+{
+    /// Indexes the directory tree at `root`, with a `Content-Type` of `application/x-tar`.
+    ///
+    /// This performs blocking filesystem operations, so it typically shouldn't be called on the
+    /// tokio reactor. Fails on non-UTF-8 names.
+    pub fn new<P: AsRef<Path>>(root: P) -> Result<Self, io::Error> {
+        let mut entries = Vec::new();
+        walk(root.as_ref(), "", &mut entries)?;
+        let mut members = Vec::with_capacity(entries.len());
+        let mut etag = Sha256::new();
+        let mut offset = 0;
+        for (name, path, m) in entries {
+            let mtime = m.modified()?;
+            let ft = m.file_type();
+            let (typeflag, len, link) = if ft.is_dir() {
+                (b'5', 0, None)
+            } else if ft.is_symlink() {
+                let target = fs::read_link(&path)?;
+                let target = target.to_str().ok_or_else(|| non_utf8(&target))?.to_owned();
+                (b'2', 0, Some(target))
+            } else {
+                (b'0', m.len(), None)
+            };
+            let header = headers(&name, typeflag, link.as_deref(), len, &m);
+            // The header holds the modification time only to the second, so hash it in full. The
+            // device and inode identify the file whose contents are served.
+            etag.update(&header);
+            etag.update(m.mtime().to_be_bytes());
+            etag.update(m.mtime_nsec().to_be_bytes());
+            etag.update(m.dev().to_be_bytes());
+            etag.update(m.ino().to_be_bytes());
+            let member = Member {
+                header,
+                path: if typeflag == b'0' { Some(path) } else { None },
+                len,
+                mtime,
+                ino: m.ino(),
+                offset,
+            };
+            offset = member.end();
+            members.push(member);
+        }
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/x-tar"),
+        );
+        Ok(TarEntity {
+            members,
+            headers,
+            etag: content_etag::format_derived_etag(&etag.finalize(), false),
+            phantom: ::std::marker::PhantomData,
+        })
+    }
+
+    /// Adds a header to be sent with the entity, such as `Content-Disposition`.
+    pub fn with_header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.headers.append(name, value);
+        self
+    }
+}
+
+impl<D, E> Entity for TarEntity<D, E>
+where
+    D: 'static + Send + Buf + From<Vec<u8>> + From<&'static [u8]>,
+    E: 'static + Send + Into<Box<dyn ::std::error::Error + Send + Sync>> + From<Box<io::Error>>,
+{
+    type Data = D;
+    type Error = E;
+
+    fn len(&self) -> u64 {
+        self.members.last().map(|m| m.end()).unwrap_or(0) + 2 * BLOCK
+    }
+
+    fn get_range(&self, range: Range<u64>) -> BoxStream<'static, Result<D, E>> {
+        if range.start == range.end {
+            return stream::empty().boxed();
+        }
+        let mut streams = Vec::new();
+        let first = self.members.partition_point(|m| m.end() <= range.start);
+        for m in &self.members[first..] {
+            if m.offset >= range.end {
+                break;
+            }
+            let data_offset = m.data_offset();
+            let data_end = data_offset + m.len;
+            if let Some(r) = intersect(&range, m.offset..data_offset) {
+                streams.push(crate::vec_body(m.header[r.start as usize..r.end as usize].to_vec()));
+            }
+            if let Some(r) = intersect(&range, data_offset..data_end) {
+                let path = m.path.clone().expect("only regular files have data");
+                streams.push(file_range(path, m.len, m.mtime, m.ino, r));
+            }
+            if let Some(r) = intersect(&range, data_end..m.end()) {
+                streams.push(crate::vec_body(vec![0; (r.end - r.start) as usize]));
+            }
+        }
+
+        // The end-of-archive marker.
+        let len = self.len();
+        if let Some(r) = intersect(&range, len - 2 * BLOCK..len) {
+            streams.push(crate::vec_body(vec![0; (r.end - r.start) as usize]));
+        }
+        stream::iter(streams).flatten().boxed()
+    }
+
+    fn add_headers(&self, h: &mut HeaderMap) {
+        h.extend(self.headers.iter().map(|(k, v)| (k.clone(), v.clone())));
+    }
+
+    fn etag(&self) -> Option<HeaderValue> {
+        Some(self.etag.clone())
+    }
+
+    fn last_modified(&self) -> Option<SystemTime> {
+        self.members.iter().map(|m| m.mtime).max()
+    }
+}
+
+/// Appends `(name, path, metadata)` for everything under `dir`, in order, without following
+/// symbolic links. Names are relative to the root; directories' have a trailing `/`.
+fn walk(
+    dir: &Path,
+    prefix: &str,
+    out: &mut Vec<(String, PathBuf, fs::Metadata)>,
+) -> Result<(), io::Error> {
+    let mut children = Vec::new();
+    for e in fs::read_dir(dir)? {
+        let e = e?;
+        let name = e.file_name();
+        let name = name.to_str().ok_or_else(|| non_utf8(&e.path()))?.to_owned();
+        children.push((name, e.path()));
+    }
+    children.sort();
+    for (name, path) in children {
+        let m = fs::symlink_metadata(&path)?;
+        let ft = m.file_type();
+        if ft.is_dir() {
+            let name = format!("{}{}/", prefix, name);
+            out.push((name.clone(), path.clone(), m));
+            walk(&path, &name, out)?;
+        } else if ft.is_file() || ft.is_symlink() {
+            out.push((format!("{}{}", prefix, name), path, m));
+        }
+    }
+    Ok(())
+}
+
+fn non_utf8(p: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("non-UTF-8 path {}", p.display()),
+    )
+}
+
+/// Returns `len` rounded up to a multiple of the block size.
+fn padded(len: u64) -> u64 {
+    len.div_ceil(BLOCK) * BLOCK
+}
+
+/// Returns the headers for a member, including a PAX extended header if needed.
+fn headers(name: &str, typeflag: u8, link: Option<&str>, len: u64, m: &fs::Metadata) -> Vec<u8> {
+    let mtime = cmp::max(m.mtime(), 0) as u64;
+    let mut pax = Vec::new();
+    let mut h = [0u8; BLOCK as usize];
+    if !split_name(name, &mut h) {
+        pax_record(&mut pax, "path", name);
+        put_str(&mut h[0..100], name);
+    }
+    octal(&mut h[100..108], u64::from(m.permissions().mode() & 0o7777));
+    if !octal(&mut h[108..116], u64::from(m.uid())) {
+        pax_record(&mut pax, "uid", &m.uid().to_string());
+    }
+    if !octal(&mut h[116..124], u64::from(m.gid())) {
+        pax_record(&mut pax, "gid", &m.gid().to_string());
+    }
+    if !octal(&mut h[124..136], len) {
+        pax_record(&mut pax, "size", &len.to_string());
+    }
+    if !octal(&mut h[136..148], mtime) {
+        pax_record(&mut pax, "mtime", &mtime.to_string());
+    }
+    h[156] = typeflag;
+    if let Some(link) = link {
+        if link.len() > 100 {
+            pax_record(&mut pax, "linkpath", link);
+        }
+        put_str(&mut h[157..257], link);
+    }
+    h[257..263].copy_from_slice(b"ustar\0");
+    h[263..265].copy_from_slice(b"00");
+    checksum(&mut h);
+
+    let mut out = Vec::with_capacity(3 * BLOCK as usize + pax.len());
+    if !pax.is_empty() {
+        let mut x = [0u8; BLOCK as usize];
+        put_str(&mut x[0..100], "././@PaxHeader");
+        octal(&mut x[100..108], 0o644);
+        octal(&mut x[108..116], 0);
+        octal(&mut x[116..124], 0);
+        octal(&mut x[124..136], pax.len() as u64);
+        octal(&mut x[136..148], cmp::min(mtime, 0o777_7777_7777));
+        x[156] = b'x';
+        x[257..263].copy_from_slice(b"ustar\0");
+        x[263..265].copy_from_slice(b"00");
+        checksum(&mut x);
+        out.extend_from_slice(&x);
+        let pax_len = pax.len() as u64;
+        out.extend_from_slice(&pax);
+        out.resize(out.len() + (padded(pax_len) - pax_len) as usize, 0);
+    }
+    out.extend_from_slice(&h);
+    out
+}
+
+/// Stores `name` in the ustar `name` and `prefix` fields, returning false if it doesn't fit.
+fn split_name(name: &str, h: &mut [u8; BLOCK as usize]) -> bool {
+    if name.len() <= 100 {
+        put_str(&mut h[0..100], name);
+        return true;
+    }
+
+    // Split at a `/` such that the prefix fits in 155 bytes and the rest in 100. A directory's
+    // trailing `/` can't be the split point.
+    let trimmed = name.trim_end_matches('/');
+    for (i, _) in trimmed.match_indices('/') {
+        if i <= 155 && name.len() - i - 1 <= 100 {
+            put_str(&mut h[345..500], &name[..i]);
+            put_str(&mut h[0..100], &name[i + 1..]);
+            return true;
+        }
+    }
+    false
+}
+
+/// Copies as much of `s` as fits into `field`.
+fn put_str(field: &mut [u8], s: &str) {
+    let n = cmp::min(field.len(), s.len());
+    field[..n].copy_from_slice(&s.as_bytes()[..n]);
+}
+
+/// Writes `v` as NUL-terminated octal, returning false (and writing zero) if it doesn't fit.
+fn octal(field: &mut [u8], v: u64) -> bool {
+    let s = format!("{:0width$o}", v, width = field.len() - 1);
+    let fits = s.len() < field.len();
+    if !fits {
+        return !octal(field, 0);
+    }
+    field[..s.len()].copy_from_slice(s.as_bytes());
+    field[s.len()] = 0;
+    true
+}
+
+fn checksum(h: &mut [u8; BLOCK as usize]) {
+    h[148..156].copy_from_slice(b"        ");
+    let sum: u32 = h.iter().map(|&b| u32::from(b)).sum();
+    let s = format!("{:06o}\0 ", sum);
+    h[148..156].copy_from_slice(s.as_bytes());
+}
+
+/// Appends a PAX extended header record, `<length> <key>=<value>\n`, where the length includes
+/// its own digits.
+fn pax_record(pax: &mut Vec<u8>, key: &str, value: &str) {
+    let base = key.len() + value.len() + 3;
+    let mut len = base + base.to_string().len();
+    if len.to_string().len() != base.to_string().len() {
+        len += 1;
+    }
+    pax.extend_from_slice(format!("{} {}={}\n", len, key, value).as_bytes());
+}
+
+/// Returns `range` of the file at `path`, opening it on first poll and failing if it has
+/// changed or been replaced since being indexed.
+fn file_range<D, E>(
+    path: PathBuf,
+    len: u64,
+    mtime: SystemTime,
+    ino: u64,
+    range: Range<u64>,
+) -> BoxStream<'static, Result<D, E>>
+where
+    D: 'static + Send + Buf + From<Vec<u8>> + From<&'static [u8]>,
+    E: 'static + Send + Into<Box<dyn ::std::error::Error + Send + Sync>> + From<Box<io::Error>>,
+{
+    stream::once(async move {
+        let f = tokio::task::spawn_blocking(move || {
+            let f = fs::File::open(&path)?;
+            let replaced = f.metadata()?.ino() != ino;
+            let f: ChunkedReadFile<D, E> = ChunkedReadFile::new(f, HeaderMap::new())?;
+            if replaced || f.len() != len || f.last_modified() != Some(mtime) {
+                return Err(io::Error::other(format!(
+                    "{} was modified since being indexed",
+                    path.display()
+                )));
+            }
+            Ok(f)
+        })
+        .await
+        .unwrap_or_else(|e| Err(io::Error::other(e)));
+        match f {
+            Ok(f) => f.get_range(range),
+            Err(e) => stream::once(future::ready(Err(Box::new(e).into()))).boxed(),
+        }
+    })
+    .flatten()
+    .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{pax_record, TarEntity};
+    use crate::{BoxError, Entity};
+    use bytes::Bytes;
+    use std::fs::{self, File};
+    use std::os::unix::fs::symlink;
+    use std::path::Path;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    use tempdir::TempDir;
+
+    type Tar = TarEntity<Bytes, BoxError>;
+
+    /// Returns the `(name, typeflag, size, data offset)` of each member, following PAX paths.
+    fn members(b: &[u8]) -> Vec<(String, u8, u64, usize)> {
+        let mut out = Vec::new();
+        let mut pos = 0;
+        let mut pax_path = None;
+        while b[pos..pos + 512].iter().any(|&b| b != 0) {
+            let h = &b[pos..pos + 512];
+            let field = |r: ::std::ops::Range<usize>| {
+                let f = &h[r];
+                let n = f.iter().position(|&b| b == 0).unwrap_or(f.len());
+                String::from_utf8(f[..n].to_vec()).unwrap()
+            };
+            let size = u64::from_str_radix(&field(124..135), 8).unwrap();
+            let data = pos + 512;
+            pos = data + (size as usize).div_ceil(512) * 512;
+            if h[156] == b'x' {
+                let pax = ::std::str::from_utf8(&b[data..data + size as usize]).unwrap();
+                let value = pax.split_once(" path=").unwrap().1;
+                pax_path = Some(value.trim_end_matches('\n').to_owned());
+                continue;
+            }
+            let name = pax_path.take().unwrap_or_else(|| {
+                let prefix = field(345..500);
+                if prefix.is_empty() {
+                    field(0..100)
+                } else {
+                    format!("{}/{}", prefix, field(0..100))
+                }
+            });
+            out.push((name, h[156], size, data));
+        }
+        out
+    }
+
+    #[tokio::test]
+    async fn archive() {
+        let tmp = TempDir::new("http-tar").unwrap();
+        let root = tmp.path();
+        fs::write(root.join("a.txt"), b"hello\n").unwrap();
+        fs::create_dir(root.join("sub")).unwrap();
+        fs::write(root.join("sub/b.bin"), vec![7u8; 1000]).unwrap();
+        symlink("../a.txt", root.join("sub/link")).unwrap();
+        let medium = format!("{}/{}", "d".repeat(90), "f".repeat(60));
+        let long = "l".repeat(200);
+        fs::create_dir(root.join(&medium[..90])).unwrap();
+        fs::write(root.join(&medium), b"medium").unwrap();
+        fs::write(root.join(&long), b"long").unwrap();
+
+        let e = Tar::new(root).unwrap();
+        assert_eq!(0, e.len() % 512);
+        assert!(e.etag().is_some());
+        let full = crate::body_bytes(e.get_range(0..e.len())).await.unwrap();
+        assert_eq!(e.len(), full.len() as u64);
+        let m = members(&full);
+        let names: Vec<&str> = m.iter().map(|m| &m.0[..]).collect();
+        let dir = format!("{}/", &medium[..90]);
+        assert_eq!(
+            vec!["a.txt", &dir, &medium, &long, "sub/", "sub/b.bin", "sub/link"],
+            names
+        );
+        let types: Vec<u8> = m.iter().map(|m| m.1).collect();
+        assert_eq!(b"0500502", &types[..]);
+        let data = |i: usize| &full[m[i].3..m[i].3 + m[i].2 as usize];
+        assert_eq!(b"hello\n", data(0));
+        assert_eq!(b"medium", data(2));
+        assert_eq!(b"long", data(3));
+        assert_eq!(&[7u8; 1000][..], data(5));
+
+        // Any range matches the full body.
+        for &(start, end) in &[(0, 1), (500, 1100), (m[5].3 as u64 - 3, m[5].3 as u64 + 1500)] {
+            let part = crate::body_bytes(e.get_range(start..end)).await.unwrap();
+            assert_eq!(&full[start as usize..end as usize], &part[..]);
+        }
+
+        // Changing a file after indexing causes an error.
+        fs::write(root.join("a.txt"), b"HELLO\n!").unwrap();
+        assert!(crate::body_bytes(e.get_range(0..e.len())).await.is_err());
+    }
+
+    /// Writes `data` to `path` with the given modification time.
+    fn write_with_mtime(path: &Path, data: &[u8], mtime: SystemTime) {
+        fs::write(path, data).unwrap();
+        File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+    }
+
+    #[test]
+    fn etag_subsecond_change() {
+        let tmp = TempDir::new("http-tar").unwrap();
+        let p = tmp.path().join("a.txt");
+        let t = UNIX_EPOCH + Duration::new(1_500_000_000, 100);
+        write_with_mtime(&p, b"hello", t);
+        let before = Tar::new(tmp.path()).unwrap().etag();
+
+        // Same length and second; only the nanoseconds differ.
+        write_with_mtime(&p, b"world", t + Duration::from_nanos(100));
+        assert_ne!(before, Tar::new(tmp.path()).unwrap().etag());
+    }
+
+    #[tokio::test]
+    async fn replaced() {
+        let tmp = TempDir::new("http-tar").unwrap();
+        let root = tmp.path().join("root");
+        fs::create_dir(&root).unwrap();
+        let t = UNIX_EPOCH + Duration::new(1_500_000_000, 100);
+        write_with_mtime(&root.join("a.txt"), b"hello", t);
+        let e = Tar::new(&root).unwrap();
+
+        // A file with the same length and modification time renamed over the original.
+        let tmp_file = tmp.path().join("a.tmp");
+        write_with_mtime(&tmp_file, b"world", t);
+        fs::rename(&tmp_file, root.join("a.txt")).unwrap();
+        let err = crate::body_bytes(e.get_range(0..e.len())).await.unwrap_err();
+        assert!(err.to_string().contains("modified"), "{}", err);
+    }
+
+    #[test]
+    fn pax_records() {
+        let mut pax = Vec::new();
+        pax_record(&mut pax, "path", "x");
+        assert_eq!(&b"9 path=x\n"[..], &pax[..]);
+
+        // The length's own digits push it from 100 to 101 bytes.
+        let mut pax = Vec::new();
+        pax_record(&mut pax, "path", &"x".repeat(91));
+        assert_eq!(101, pax.len());
+        assert!(pax.starts_with(b"101 path="));
+    }
+}
@@ -0,0 +1,427 @@
+// Copyright (c) 2018 Scott Lamb <slamb@slamb.org>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! `tower` services and layers, available with the `tower` feature.
+
+use crate::{serve, streaming_body, Body, BoxError, Entity};
+use bytes::{Buf, Bytes};
+use futures_util::future;
+use futures_util::stream::{self, BoxStream, StreamExt};
+use http::header::{self, HeaderMap};
+use http::{Request, Response, StatusCode};
+use std::convert::Infallible;
+use std::future::Future;
+use std::io::Write;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tower_layer::Layer;
+use tower_service::Service;
+
+/// A `tower_service::Service` which serves entities via `serve`.
+///
+/// Each request is passed to a closure which returns a future resolving to the entity to serve,
+/// or `None` to send `404 Not Found`. The closure receives only a reference to the request, so
+/// its future must copy any parts of the request it needs.
+///
+/// ```
+/// # use http::header::HeaderValue;
+/// # use http_serve::{BoxError, InMemoryEntity, ServeEntity};
+/// type E = InMemoryEntity<&'static [u8], bytes::Bytes, BoxError>;
+/// let svc = ServeEntity::new(|req: &http::Request<()>| {
+///     let e: Option<E> = match req.uri().path() {
+///         "/hello.txt" => Some(
+///             InMemoryEntity::new(&b"hello"[..])
+///                 .with_content_type(HeaderValue::from_static("text/plain")),
+///         ),
+///         _ => None,
+///     };
+///     std::future::ready(e)
+/// });
+/// ```
+#[derive(Clone)]
+pub struct ServeEntity<F> {
+    resolve: F,
+}
+
+impl<F> ServeEntity<F> {
+    /// Creates a new `ServeEntity` which resolves entities with `resolve`.
+    pub fn new(resolve: F) -> Self {
+        ServeEntity { resolve }
+    }
+}
+
+impl<F, Fut, Ent, B> Service<Request<B>> for ServeEntity<F>
+where
+    F: FnMut(&Request<B>) -> Fut,
+    Fut: Future<Output = Option<Ent>> + Send + 'static,
+    Ent: Entity,
+    Ent::Error: Into<BoxError> + From<Box<::std::io::Error>>,
+{
+    type Response = Response<Body<Ent::Data, Ent::Error>>;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Infallible>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let fut = (self.resolve)(&req);
+        let (parts, _) = req.into_parts();
+        let req = Request::from_parts(parts, ());
+        Box::pin(async move {
+            Ok(match fut.await {
+                Some(e) => serve(e, &req),
+                None => Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body(crate::static_body::<Ent::Data, Ent::Error>("Not found").into())
+                    .unwrap(),
+            })
+        })
+    }
+}
+
+/// A `tower_layer::Layer` which passes the bodies of an inner service's responses through
+/// `streaming_body`.
+///
+/// `200 OK` responses to `GET` and `HEAD` requests which don't already have a `Content-Encoding`
+/// or `Content-Range` are rebuilt as in `streaming_body`: the body is compressed according to the
+/// request's `Accept-Encoding`, dropped for `HEAD` requests, and conditional GET rules are
+/// evaluated using the inner response's `ETag` and `Last-Modified` headers. Its other headers are
+/// kept, except `Content-Length`. All other responses are passed through unchanged.
+///
+/// Bodies are copied to the `BodyWriter` on a task spawned with `tokio::spawn`, so the service
+/// must be called within a tokio runtime.
+#[derive(Clone)]
+pub struct StreamingBodyLayer {
+    chunk_size: usize,
+    gzip_level: u32,
+    #[cfg(feature = "brotli")]
+    brotli_quality: u32,
+    #[cfg(feature = "zstd")]
+    zstd_level: i32,
+}
+
+impl Default for StreamingBodyLayer {
+    fn default() -> Self {
+        StreamingBodyLayer {
+            chunk_size: 4096,
+            gzip_level: 6,
+            #[cfg(feature = "brotli")]
+            brotli_quality: 5,
+            #[cfg(feature = "zstd")]
+            zstd_level: 3,
+        }
+    }
+}
+
+impl StreamingBodyLayer {
+    /// Creates a new layer with the same defaults as `streaming_body`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the chunk size, as in `StreamingBodyBuilder::with_chunk_size`.
+    pub fn with_chunk_size(self, chunk_size: usize) -> Self {
+        StreamingBodyLayer { chunk_size, ..self }
+    }
+
+    /// Sets the gzip compression level, as in `StreamingBodyBuilder::with_gzip_level`.
+    pub fn with_gzip_level(self, level: u32) -> Self {
+        StreamingBodyLayer {
+            gzip_level: level,
+            ..self
+        }
+    }
+
+    /// Sets the brotli quality, as in `StreamingBodyBuilder::with_brotli_quality`.
+    #[cfg(feature = "brotli")]
+    pub fn with_brotli_quality(self, quality: u32) -> Self {
+        assert!(quality <= 11);
+        StreamingBodyLayer {
+            brotli_quality: quality,
+            ..self
+        }
+    }
+
+    /// Sets the zstd compression level, as in `StreamingBodyBuilder::with_zstd_level`.
+    #[cfg(feature = "zstd")]
+    pub fn with_zstd_level(self, level: i32) -> Self {
+        assert!(zstd::compression_level_range().contains(&level));
+        StreamingBodyLayer {
+            zstd_level: level,
+            ..self
+        }
+    }
+}
+
+impl<S> Layer<S> for StreamingBodyLayer {
+    type Service = StreamingBodyService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        StreamingBodyService {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+/// The service produced by `StreamingBodyLayer`.
+#[derive(Clone)]
+pub struct StreamingBodyService<S> {
+    inner: S,
+    layer: StreamingBodyLayer,
+}
+
+impl<S, ReqB, ResB> Service<Request<ReqB>> for StreamingBodyService<S>
+where
+    S: Service<Request<ReqB>, Response = Response<ResB>>,
+    S::Future: Send + 'static,
+    S::Error: 'static,
+    ResB: http_body::Body + Send + 'static,
+    ResB::Data: Send,
+    ResB::Error: Into<BoxError>,
+{
+    type Response = Response<Body>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response<Body>, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqB>) -> Self::Future {
+        let mut hdrs_req = Request::new(());
+        *hdrs_req.method_mut() = req.method().clone();
+        *hdrs_req.headers_mut() = req.headers().clone();
+        let layer = self.layer.clone();
+        let fut = self.inner.call(req);
+        Box::pin(async move {
+            let resp = fut.await?;
+            Ok(layer.rebuild(&hdrs_req, resp))
+        })
+    }
+}
+
+impl StreamingBodyLayer {
+    /// Rebuilds `resp` with a streaming body if applicable, as described above.
+    fn rebuild<B>(&self, req: &Request<()>, resp: Response<B>) -> Response<Body>
+    where
+        B: http_body::Body + Send + 'static,
+        B::Data: Send,
+        B::Error: Into<BoxError>,
+    {
+        let (parts, inner_body) = resp.into_parts();
+        let eligible = parts.status == StatusCode::OK
+            && (req.method() == http::Method::GET || req.method() == http::Method::HEAD)
+            && !parts.headers.contains_key(header::CONTENT_ENCODING)
+            && !parts.headers.contains_key(header::CONTENT_RANGE);
+        if !eligible {
+            return Response::from_parts(parts, body_stream(inner_body).into());
+        }
+
+        let mut b = streaming_body(req)
+            .with_chunk_size(self.chunk_size)
+            .with_gzip_level(self.gzip_level);
+        #[cfg(feature = "brotli")]
+        {
+            b = b.with_brotli_quality(self.brotli_quality);
+        }
+        #[cfg(feature = "zstd")]
+        {
+            b = b.with_zstd_level(self.zstd_level);
+        }
+        if let Some(etag) = parts.headers.get(header::ETAG) {
+            b = b.with_etag(etag.clone());
+        }
+        if let Some(m) = parts.headers.get(header::LAST_MODIFIED) {
+            if let Some(m) = m.to_str().ok().and_then(|m| httpdate::parse_http_date(m).ok()) {
+                b = b.with_last_modified(m);
+            }
+        }
+        let (mut resp, w) = b.build::<Body, Bytes, BoxError>();
+        merge_headers(resp.headers_mut(), parts.headers);
+        if let Some(mut w) = w {
+            let mut s = body_stream(inner_body);
+            tokio::spawn(async move {
+                while let Some(r) = s.next().await {
+                    match r {
+                        Ok(d) => {
+                            if w.write_all(&d).is_err() {
+                                return; // the client went away.
+                            }
+                        }
+                        Err(e) => return w.abort(e),
+                    }
+                }
+            });
+        }
+        resp
+    }
+}
+
+/// Adds the inner response's headers `from` to those of the rebuilt response `to`.
+///
+/// Headers set by `streaming_body` take precedence, except that `Vary` values are combined.
+/// `Content-Length` is dropped, as the length of the rebuilt body may differ.
+fn merge_headers(to: &mut HeaderMap, from: HeaderMap) {
+    let mut last = None;
+    for (k, v) in from {
+        let k = match k {
+            Some(k) => k,
+            None => match last {
+                Some(ref k) => Clone::clone(k),
+                None => continue,
+            },
+        };
+        last = Some(k.clone());
+        if k == header::CONTENT_LENGTH || (k != header::VARY && to.contains_key(&k)) {
+            continue;
+        }
+        to.append(k, v);
+    }
+}
+
+/// Returns a stream of the data frames of `body`, skipping any trailers.
+fn body_stream<B>(body: B) -> BoxStream<'static, Result<Bytes, BoxError>>
+where
+    B: http_body::Body + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    stream::unfold(Box::pin(body), |mut body| async move {
+        loop {
+            let frame = future::poll_fn(|cx| body.as_mut().poll_frame(cx)).await?;
+            match frame {
+                Ok(f) => match f.into_data() {
+                    Ok(mut d) => return Some((Ok(d.copy_to_bytes(d.remaining())), body)),
+                    Err(_) => continue, // trailers.
+                },
+                Err(e) => return Some((Err(e.into()), body)),
+            }
+        }
+    }).boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ServeEntity, StreamingBodyLayer};
+    use crate::{Body, BoxError, InMemoryEntity};
+    use bytes::Bytes;
+    use futures::future::{self, poll_fn};
+    use http::header::{self, HeaderValue};
+    use http::{Request, Response, StatusCode};
+    use http_body_util::BodyExt;
+    use std::convert::Infallible;
+    use std::task::{Context, Poll};
+    use tower_layer::Layer;
+    use tower_service::Service;
+
+    type E = InMemoryEntity<&'static [u8], Bytes, BoxError>;
+
+    async fn call<S, B>(svc: &mut S, req: Request<()>) -> Response<B>
+    where
+        S: Service<Request<()>, Response = Response<B>>,
+        S::Error: ::std::fmt::Debug,
+    {
+        poll_fn(|cx| svc.poll_ready(cx)).await.unwrap();
+        svc.call(req).await.unwrap()
+    }
+
+    async fn body(resp: Response<Body>) -> Bytes {
+        resp.into_body().collect().await.unwrap().to_bytes()
+    }
+
+    #[tokio::test]
+    async fn serve_entity() {
+        let mut svc = ServeEntity::new(|req: &Request<()>| {
+            let e = match req.uri().path() {
+                "/a" => Some(E::new(&b"hello"[..])),
+                _ => None,
+            };
+            future::ready(e)
+        });
+        let req = Request::get("/a")
+            .header(header::RANGE, "bytes=1-2")
+            .body(())
+            .unwrap();
+        let resp = call(&mut svc, req).await;
+        assert_eq!(StatusCode::PARTIAL_CONTENT, resp.status());
+        assert_eq!(&b"el"[..], &body(resp).await[..]);
+
+        let resp = call(&mut svc, Request::get("/b").body(()).unwrap()).await;
+        assert_eq!(StatusCode::NOT_FOUND, resp.status());
+    }
+
+    #[tokio::test]
+    async fn streaming_body_layer() {
+        let mut svc = StreamingBodyLayer::new().layer(Hello);
+
+        // Compressed.
+        let req = Request::get("/")
+            .header(header::ACCEPT_ENCODING, "gzip")
+            .body(())
+            .unwrap();
+        let resp = call(&mut svc, req).await;
+        assert_eq!(StatusCode::OK, resp.status());
+        assert_eq!(resp.headers().get(header::CONTENT_ENCODING).unwrap(), "gzip");
+        assert_eq!(resp.headers().get(header::CONTENT_TYPE).unwrap(), "text/plain");
+        assert_eq!(resp.headers().get(header::ETAG).unwrap(), "\"foo\"");
+        assert!(resp.headers().get(header::CONTENT_LENGTH).is_none());
+        let vary: Vec<&HeaderValue> = resp.headers().get_all(header::VARY).iter().collect();
+        assert_eq!(vary, vec!["accept-encoding", "cookie"]);
+        assert_eq!(&b"\x1f\x8b"[..], &body(resp).await[..2]); // gzip magic number.
+
+        // Conditional GET via the inner response's etag.
+        let req = Request::get("/")
+            .header(header::IF_NONE_MATCH, "\"foo\"")
+            .body(())
+            .unwrap();
+        let resp = call(&mut svc, req).await;
+        assert_eq!(StatusCode::NOT_MODIFIED, resp.status());
+        assert!(body(resp).await.is_empty());
+
+        // Other statuses are passed through.
+        let req = Request::get("/missing")
+            .header(header::ACCEPT_ENCODING, "gzip")
+            .body(())
+            .unwrap();
+        let resp = call(&mut svc, req).await;
+        assert_eq!(StatusCode::NOT_FOUND, resp.status());
+        assert!(resp.headers().get(header::CONTENT_ENCODING).is_none());
+        assert_eq!(&b"hello"[..], &body(resp).await[..]);
+    }
+
+    /// An inner service which says hello, or `404 Not Found` for `/missing`.
+    struct Hello;
+
+    impl Service<Request<()>> for Hello {
+        type Response = Response<Body>;
+        type Error = Infallible;
+        type Future = future::Ready<Result<Response<Body>, Infallible>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, req: Request<()>) -> Self::Future {
+            let status = match req.uri().path() {
+                "/missing" => StatusCode::NOT_FOUND,
+                _ => StatusCode::OK,
+            };
+            future::ready(Ok(Response::builder()
+                .status(status)
+                .header(header::CONTENT_TYPE, "text/plain")
+                .header(header::CONTENT_LENGTH, "5")
+                .header(header::ETAG, "\"foo\"")
+                .header(header::VARY, "cookie")
+                .body(Body::from("hello"))
+                .unwrap()))
+        }
+    }
+}
@@ -0,0 +1,630 @@
+// Copyright (c) 2018 Scott Lamb <slamb@slamb.org>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Streaming generation of stored (uncompressed) ZIP64 archives.
+//!
+//! The layout of each member is:
+//!
+//! *   a local file header, with the data descriptor flag set and a zero CRC.
+//! *   the member's data.
+//! *   a ZIP64 data descriptor holding the CRC and sizes.
+//!
+//! followed by the central directory, the ZIP64 end of central directory record and locator, and
+//! the classic end of central directory record. Everything but the CRCs depends only on the
+//! members' names, lengths, and last modified times, so the archive's length is known up front.
+//! The CRCs are needed only for the data descriptors and the central directory.
+
+use crate::content_etag;
+use crate::range::intersect;
+use crate::Entity;
+use bytes::Buf;
+use flate2::Crc;
+use futures_util::stream::{self, BoxStream, StreamExt};
+use http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use sha2::{Digest, Sha256};
+use std::cmp;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const VERSION: u16 = 45; // 4.5: ZIP64.
+const FLAGS: u16 = 0x0808; // bit 3: data descriptor; bit 11: UTF-8 names.
+const LOCAL_HEADER_LEN: u64 = 30 + 20;
+const DESCRIPTOR_LEN: u64 = 24;
+const CENTRAL_HEADER_LEN: u64 = 46 + 28;
+const END_LEN: u64 = 56 + 20 + 22;
+
+/// A CRC-32 which is supplied or computed on first use, shared between `get_range` calls.
+type CrcSlot = Arc<Mutex<Option<u32>>>;
+
+struct Member<D, E> {
+    name: String,
+    entity: Box<dyn Entity<Data = D, Error = E>>,
+    len: u64,
+
+    /// The offset of this member's local file header within the archive.
+    offset: u64,
+
+    /// The MS-DOS `(time, date)` of the last modification.
+    dos_time: (u16, u16),
+
+    crc: CrcSlot,
+    supplied_crc: bool,
+}
+
+impl<D, E> Member<D, E> {
+    fn data_offset(&self) -> u64 {
+        self.offset + LOCAL_HEADER_LEN + self.name.len() as u64
+    }
+
+    fn end(&self) -> u64 {
+        self.data_offset() + self.len + DESCRIPTOR_LEN
+    }
+}
+
+/// A stored (uncompressed) ZIP64 archive of other entities, generated on the fly.
+///
+/// The archive's layout depends only on the members' names, lengths, and last modified times,
+/// so it supports byte ranges (and thus resumable downloads) without ever being written out.
+///
+/// Each member's CRC-32 may be supplied via `with_member_crc`; otherwise it's computed on first
+/// use. A response which sends a member's data in full computes its CRC along the way; a range
+/// which needs a CRC but not all the data reads the member in full before sending the bytes
+/// holding the CRC. Computed CRCs are kept for the lifetime of the archive, so it's worth
+/// reusing an archive across requests when possible.
+///
+/// The etag is derived from the members' names and etags, and is weak if any of them is weak
+/// or absent if any of them is absent. The last modified time is the latest of the members', or
+/// absent if any of them is absent.
+///
+/// ```
+/// # use bytes::Bytes;
+/// # use http::header::{self, HeaderValue};
+/// # use http_serve::{BoxError, InMemoryEntity, ZipArchive};
+/// # fn main() {
+/// let readme: InMemoryEntity<_, Bytes, BoxError> = InMemoryEntity::new(&b"hello\n"[..]);
+/// let data: InMemoryEntity<_, Bytes, BoxError> = InMemoryEntity::new(vec![0u8; 1024]);
+/// let zip = ZipArchive::new()
+///     .with_member("README", readme)
+///     .with_member_crc("data/zeros", data, 0xefb5af2e)
+///     .with_header(
+///         header::CONTENT_DISPOSITION,
+///         HeaderValue::from_static("attachment; filename=\"bundle.zip\""),
+///     );
+/// # }
+/// ```
+pub struct ZipArchive<D, E> {
+    members: Vec<Member<D, E>>,
+    headers: HeaderMap,
+}
+
+impl<D, E> Default for ZipArchive<D, E> {
+    fn default() -> Self {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/zip"),
+        );
+        ZipArchive {
+            members: Vec::new(),
+            headers,
+        }
+    }
+}
+
+impl<D, E> ZipArchive<D, E>
+where
+    D: 'static + Send + Buf + From<Vec<u8>> + From<&'static [u8]>,
+    E: 'static + Send,
+{
+    /// Creates a new archive with no members and a `Content-Type` of `application/zip`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends a member whose CRC-32 is computed on first use.
+    ///
+    /// `name` is the member's path within the archive, with `/` separators. Panics if it's empty
+    /// or longer than 65,535 bytes.
+    pub fn with_member<M: Entity<Data = D, Error = E>>(self, name: &str, entity: M) -> Self {
+        self.push(name, entity, None)
+    }
+
+    /// Appends a member with the given CRC-32 of its data, as in `with_member`.
+    pub fn with_member_crc<M: Entity<Data = D, Error = E>>(
+        self,
+        name: &str,
+        entity: M,
+        crc: u32,
+    ) -> Self {
+        self.push(name, entity, Some(crc))
+    }
+
+    /// Adds a header to be sent with the entity, such as `Content-Disposition`.
+    pub fn with_header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.headers.append(name, value);
+        self
+    }
+
+    fn push<M: Entity<Data = D, Error = E>>(
+        mut self,
+        name: &str,
+        entity: M,
+        crc: Option<u32>,
+    ) -> Self {
+        assert!(
+            !name.is_empty() && name.len() <= usize::from(u16::MAX),
+            "invalid zip member name {:?}",
+            name
+        );
+        let offset = self.members.last().map(|m| m.end()).unwrap_or(0);
+        self.members.push(Member {
+            name: name.to_owned(),
+            len: entity.len(),
+            dos_time: dos_time(entity.last_modified()),
+            entity: Box::new(entity),
+            offset,
+            crc: Arc::new(Mutex::new(crc)),
+            supplied_crc: crc.is_some(),
+        });
+        self
+    }
+
+    fn central_offset(&self) -> u64 {
+        self.members.last().map(|m| m.end()).unwrap_or(0)
+    }
+
+    fn local_header(&self, m: &Member<D, E>) -> Vec<u8> {
+        let mut h = Vec::with_capacity(LOCAL_HEADER_LEN as usize + m.name.len());
+        put32(&mut h, 0x0403_4b50);
+        put16(&mut h, VERSION);
+        put16(&mut h, FLAGS);
+        put16(&mut h, 0); // method: stored.
+        put16(&mut h, m.dos_time.0);
+        put16(&mut h, m.dos_time.1);
+        put32(&mut h, 0); // CRC-32: in the data descriptor.
+        put32(&mut h, 0xffff_ffff); // compressed size: in the ZIP64 extra field.
+        put32(&mut h, 0xffff_ffff); // uncompressed size: likewise.
+        put16(&mut h, m.name.len() as u16);
+        put16(&mut h, 20); // extra field length.
+        h.extend_from_slice(m.name.as_bytes());
+        put16(&mut h, 0x0001); // ZIP64 extended information.
+        put16(&mut h, 16);
+        put64(&mut h, 0); // uncompressed size: in the data descriptor.
+        put64(&mut h, 0); // compressed size: likewise.
+        h
+    }
+
+    /// Returns the data descriptor, with a zero CRC-32 at offset 4.
+    fn descriptor(&self, m: &Member<D, E>) -> Vec<u8> {
+        let mut h = Vec::with_capacity(DESCRIPTOR_LEN as usize);
+        put32(&mut h, 0x0807_4b50);
+        put32(&mut h, 0);
+        put64(&mut h, m.len);
+        put64(&mut h, m.len);
+        h
+    }
+
+    /// Returns the central directory and end records, with zero CRC-32s at offset 16 within each
+    /// member's central directory header.
+    fn central(&self) -> Vec<u8> {
+        let central_offset = self.central_offset();
+        let mut h = Vec::with_capacity((self.len() - central_offset) as usize);
+        for m in &self.members {
+            put32(&mut h, 0x0201_4b50);
+            put16(&mut h, VERSION); // version made by: MS-DOS.
+            put16(&mut h, VERSION);
+            put16(&mut h, FLAGS);
+            put16(&mut h, 0); // method: stored.
+            put16(&mut h, m.dos_time.0);
+            put16(&mut h, m.dos_time.1);
+            put32(&mut h, 0); // CRC-32: patched in.
+            put32(&mut h, 0xffff_ffff); // compressed size: in the ZIP64 extra field.
+            put32(&mut h, 0xffff_ffff); // uncompressed size: likewise.
+            put16(&mut h, m.name.len() as u16);
+            put16(&mut h, 28); // extra field length.
+            put16(&mut h, 0); // comment length.
+            put16(&mut h, 0); // disk number start.
+            put16(&mut h, 0); // internal attributes.
+            put32(&mut h, 0); // external attributes.
+            put32(&mut h, 0xffff_ffff); // local header offset: in the ZIP64 extra field.
+            h.extend_from_slice(m.name.as_bytes());
+            put16(&mut h, 0x0001); // ZIP64 extended information.
+            put16(&mut h, 24);
+            put64(&mut h, m.len);
+            put64(&mut h, m.len);
+            put64(&mut h, m.offset);
+        }
+        let central_len = h.len() as u64;
+        let entries = self.members.len() as u64;
+
+        // ZIP64 end of central directory record.
+        put32(&mut h, 0x0606_4b50);
+        put64(&mut h, 44); // size of the remainder of this record.
+        put16(&mut h, VERSION);
+        put16(&mut h, VERSION);
+        put32(&mut h, 0); // this disk.
+        put32(&mut h, 0); // disk with the central directory.
+        put64(&mut h, entries);
+        put64(&mut h, entries);
+        put64(&mut h, central_len);
+        put64(&mut h, central_offset);
+
+        // ZIP64 end of central directory locator.
+        put32(&mut h, 0x0706_4b50);
+        put32(&mut h, 0); // disk with the ZIP64 end of central directory record.
+        put64(&mut h, central_offset + central_len);
+        put32(&mut h, 1); // total disks.
+
+        // End of central directory record, deferring to the ZIP64 record where needed.
+        put32(&mut h, 0x0605_4b50);
+        put16(&mut h, 0); // this disk.
+        put16(&mut h, 0); // disk with the central directory.
+        put16(&mut h, cmp::min(entries, 0xffff) as u16);
+        put16(&mut h, cmp::min(entries, 0xffff) as u16);
+        put32(&mut h, cmp::min(central_len, 0xffff_ffff) as u32);
+        put32(&mut h, cmp::min(central_offset, 0xffff_ffff) as u32);
+        put16(&mut h, 0); // comment length.
+        h
+    }
+
+    /// Returns a lazily computed CRC-32 of the given member.
+    fn lazy_crc(&self, m: &Member<D, E>) -> LazyCrc<D, E> {
+        let known = m.crc.lock().unwrap().is_some();
+        LazyCrc {
+            slot: m.crc.clone(),
+            fallback: if known {
+                None
+            } else {
+                Some(m.entity.get_range(0..m.len))
+            },
+        }
+    }
+}
+
+impl<D, E> Entity for ZipArchive<D, E>
+where
+    D: 'static + Send + Buf + From<Vec<u8>> + From<&'static [u8]>,
+    E: 'static + Send,
+{
+    type Data = D;
+    type Error = E;
+
+    fn len(&self) -> u64 {
+        self.central_offset()
+            + (CENTRAL_HEADER_LEN * self.members.len() as u64)
+            + self.members.iter().map(|m| m.name.len() as u64).sum::<u64>()
+            + END_LEN
+    }
+
+    fn get_range(&self, range: Range<u64>) -> BoxStream<'static, Result<D, E>> {
+        if range.start == range.end {
+            return stream::empty().boxed();
+        }
+        let mut streams = Vec::new();
+        let first = self.members.partition_point(|m| m.end() <= range.start);
+        for m in &self.members[first..] {
+            if m.offset >= range.end {
+                break;
+            }
+            let data_offset = m.data_offset();
+            let descriptor_offset = data_offset + m.len;
+
+            // Local file header.
+            if let Some(r) = intersect(&range, m.offset..data_offset) {
+                let h = self.local_header(m);
+                streams.push(crate::vec_body(h[r.start as usize..r.end as usize].to_vec()));
+            }
+
+            // Data, computing the CRC-32 along the way if it's sent in full.
+            if let Some(r) = intersect(&range, data_offset..descriptor_offset) {
+                let whole = r.start == 0 && r.end == m.len;
+                let s = m.entity.get_range(r);
+                if whole && m.crc.lock().unwrap().is_none() {
+                    streams.push(with_crc(s, m.crc.clone()));
+                } else {
+                    streams.push(s);
+                }
+            }
+
+            // Data descriptor.
+            if let Some(r) = intersect(&range, descriptor_offset..m.end()) {
+                let crcs = if r.start < 8 && r.end > 4 {
+                    vec![(4, self.lazy_crc(m))]
+                } else {
+                    Vec::new()
+                };
+                streams.push(patched(self.descriptor(m), r, crcs));
+            }
+        }
+
+        // Central directory and end records.
+        let central_offset = self.central_offset();
+        if let Some(r) = intersect(&range, central_offset..self.len()) {
+            let mut crcs = Vec::new();
+            let mut pos = 0;
+            for m in &self.members {
+                let crc_pos = pos + 16;
+                if r.start < crc_pos + 4 && r.end > crc_pos {
+                    crcs.push((crc_pos as usize, self.lazy_crc(m)));
+                }
+                pos += CENTRAL_HEADER_LEN + m.name.len() as u64;
+            }
+            streams.push(patched(self.central(), r, crcs));
+        }
+        stream::iter(streams).flatten().boxed()
+    }
+
+    fn add_headers(&self, h: &mut HeaderMap) {
+        h.extend(self.headers.iter().map(|(k, v)| (k.clone(), v.clone())));
+    }
+
+    fn etag(&self) -> Option<HeaderValue> {
+        let mut h = Sha256::new();
+        let mut weak = false;
+        for m in &self.members {
+            let e = m.entity.etag()?;
+            let e = e.as_bytes();
+            weak |= e.starts_with(b"W/");
+            h.update((m.name.len() as u64).to_be_bytes());
+            h.update(m.name.as_bytes());
+            h.update((e.len() as u64).to_be_bytes());
+            h.update(e);
+            if m.supplied_crc {
+                h.update(m.crc.lock().unwrap().unwrap().to_be_bytes());
+            }
+        }
+        Some(content_etag::format_derived_etag(&h.finalize(), weak))
+    }
+
+    fn last_modified(&self) -> Option<SystemTime> {
+        let mut latest = None;
+        for m in &self.members {
+            latest = cmp::max(latest, Some(m.entity.last_modified()?));
+        }
+        latest
+    }
+}
+
+/// A CRC-32 which may need to be computed from the member's data.
+struct LazyCrc<D, E> {
+    slot: CrcSlot,
+
+    /// The member's full data, if the CRC-32 wasn't known when the stream was created.
+    fallback: Option<BoxStream<'static, Result<D, E>>>,
+}
+
+impl<D: Buf, E> LazyCrc<D, E> {
+    async fn get(self) -> Result<u32, E> {
+        // The CRC-32 may have been computed since, e.g. by sending the data earlier in the same
+        // response.
+        if let Some(crc) = *self.slot.lock().unwrap() {
+            return Ok(crc);
+        }
+        let mut s = self.fallback.expect("fallback stream for unknown CRC");
+        let mut crc = Crc::new();
+        while let Some(d) = s.next().await {
+            let mut d = d?;
+            while d.has_remaining() {
+                let n = {
+                    let c = d.chunk();
+                    crc.update(c);
+                    c.len()
+                };
+                d.advance(n);
+            }
+        }
+        let crc = crc.sum();
+        *self.slot.lock().unwrap() = Some(crc);
+        Ok(crc)
+    }
+}
+
+/// Returns `range` of `template`, after writing each of `crcs` (little-endian) at its position.
+fn patched<D, E>(
+    mut template: Vec<u8>,
+    range: Range<u64>,
+    crcs: Vec<(usize, LazyCrc<D, E>)>,
+) -> BoxStream<'static, Result<D, E>>
+where
+    D: 'static + Send + Buf + From<Vec<u8>>,
+    E: 'static + Send,
+{
+    let range = range.start as usize..range.end as usize;
+    if crcs.is_empty() {
+        return crate::vec_body(template[range].to_vec());
+    }
+    stream::once(async move {
+        for (pos, crc) in crcs {
+            let crc = crc.get().await?;
+            template[pos..pos + 4].copy_from_slice(&crc.to_le_bytes());
+        }
+        Ok(template[range].to_vec().into())
+    })
+    .boxed()
+}
+
+/// Passes through a member's full data, storing its CRC-32 in `slot` at the end.
+fn with_crc<D, E>(
+    s: BoxStream<'static, Result<D, E>>,
+    slot: CrcSlot,
+) -> BoxStream<'static, Result<D, E>>
+where
+    D: 'static + Send + Buf + From<Vec<u8>>,
+    E: 'static + Send,
+{
+    stream::unfold(Some((s, Crc::new(), slot)), |state| async move {
+        let (mut s, mut crc, slot) = state?;
+        match s.next().await {
+            Some(Ok(mut d)) => {
+                if d.chunk().len() != d.remaining() {
+                    // Hash a contiguous copy rather than consuming a discontiguous buffer.
+                    d = d.copy_to_bytes(d.remaining()).to_vec().into();
+                }
+                crc.update(d.chunk());
+                Some((Ok(d), Some((s, crc, slot))))
+            }
+            Some(Err(e)) => Some((Err(e), None)),
+            None => {
+                *slot.lock().unwrap() = Some(crc.sum());
+                None
+            }
+        }
+    })
+    .boxed()
+}
+
+/// Converts a last modified time to the MS-DOS `(time, date)` format, in UTC.
+///
+/// Times before 1980 (the earliest representable) or unknown become 1980-01-01 00:00:00; times
+/// after 2107 become 2107-12-31 23:59:58.
+fn dos_time(t: Option<SystemTime>) -> (u16, u16) {
+    const MIN: (u16, u16) = (0, 0x21);
+    let secs = match t.and_then(|t| t.duration_since(UNIX_EPOCH).ok()) {
+        Some(d) => d.as_secs(),
+        None => return MIN,
+    };
+    let (days, secs) = (secs / 86_400, secs % 86_400);
+
+    // Howard Hinnant's civil_from_days.
+    let z = days as i64 + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    if year < 1980 {
+        return MIN;
+    }
+    if year > 2107 {
+        return (0xbf7d, 0xff9f);
+    }
+    let time = (secs / 3600) << 11 | (secs % 3600 / 60) << 5 | (secs % 60 / 2);
+    let date = (year - 1980) << 9 | month << 5 | day;
+    (time as u16, date as u16)
+}
+
+fn put16(v: &mut Vec<u8>, x: u16) {
+    v.extend_from_slice(&x.to_le_bytes());
+}
+
+fn put32(v: &mut Vec<u8>, x: u32) {
+    v.extend_from_slice(&x.to_le_bytes());
+}
+
+fn put64(v: &mut Vec<u8>, x: u64) {
+    v.extend_from_slice(&x.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{dos_time, ZipArchive};
+    use crate::{BoxError as E, Entity, InMemoryEntity};
+    use bytes::Bytes;
+    use futures::executor::block_on;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    type Mem = InMemoryEntity<&'static [u8], Bytes, E>;
+
+    fn archive() -> ZipArchive<Bytes, E> {
+        ZipArchive::new()
+            .with_member("a.txt", Mem::new(b"hello\n"))
+            .with_member("empty", Mem::new(b""))
+            .with_member_crc("dir/b.txt", Mem::new(b"world\n"), 0x1234_5678)
+    }
+
+    fn body(e: &ZipArchive<Bytes, E>, range: ::std::ops::Range<u64>) -> Vec<u8> {
+        block_on(crate::body_bytes(e.get_range(range))).unwrap()
+    }
+
+    fn u32_at(b: &[u8], pos: usize) -> u32 {
+        let mut a = [0u8; 4];
+        a.copy_from_slice(&b[pos..pos + 4]);
+        u32::from_le_bytes(a)
+    }
+
+    #[test]
+    fn layout() {
+        let z = archive();
+        let full = body(&z, 0..z.len());
+        assert_eq!(z.len(), full.len() as u64);
+
+        // Local file header of "a.txt", then its data.
+        assert_eq!(0x0403_4b50, u32_at(&full, 0));
+        assert_eq!(b"a.txt", &full[30..35]);
+        assert_eq!(b"hello\n", &full[55..61]);
+
+        // The computed and supplied CRC-32s appear in the descriptors and central directory.
+        let a_crc = 0x363a_3020; // crc32("hello\n").
+        assert_eq!(0x0807_4b50, u32_at(&full, 61));
+        assert_eq!(a_crc, u32_at(&full, 65));
+        let central = z.central_offset() as usize;
+        assert_eq!(0x0201_4b50, u32_at(&full, central));
+        assert_eq!(a_crc, u32_at(&full, central + 16));
+        let empty_central = central + 74 + 5;
+        assert_eq!(0, u32_at(&full, empty_central + 16));
+        let b_central = empty_central + 74 + 5;
+        assert_eq!(0x1234_5678, u32_at(&full, b_central + 16));
+
+        // End of central directory record.
+        let end = full.len() - 22;
+        assert_eq!(0x0605_4b50, u32_at(&full, end));
+        assert_eq!(central as u32, u32_at(&full, end + 16));
+    }
+
+    #[test]
+    fn ranges() {
+        let full = {
+            let z = archive();
+            body(&z, 0..z.len())
+        };
+
+        // A fresh archive computes CRC-32s as needed for any range, without first sending the
+        // member's data.
+        for &(start, end) in &[(0, 10), (50, 70), (64, 66), (70, 200), (200, 350)] {
+            let z = archive();
+            let end = ::std::cmp::min(end, z.len());
+            assert_eq!(
+                &full[start as usize..end as usize],
+                &body(&z, start..end)[..],
+                "range {}..{}",
+                start,
+                end
+            );
+        }
+    }
+
+    #[test]
+    fn etag() {
+        let e = archive().etag().unwrap();
+        assert_eq!(Some(e.clone()), archive().etag());
+        let renamed = ZipArchive::new()
+            .with_member("b.txt", Mem::new(b"hello\n"))
+            .with_member("empty", Mem::new(b""))
+            .with_member_crc("dir/b.txt", Mem::new(b"world\n"), 0x1234_5678);
+        assert_ne!(Some(e), renamed.etag());
+        let none = archive().with_member("c", Mem::new(b"").with_etag(None));
+        assert_eq!(None, none.etag());
+    }
+
+    #[test]
+    fn dos_times() {
+        assert_eq!((0, 0x21), dos_time(None));
+        assert_eq!((0, 0x21), dos_time(Some(UNIX_EPOCH)));
+
+        // 2018-06-15 12:34:56 UTC.
+        let t = UNIX_EPOCH + Duration::from_secs(1_529_066_096);
+        assert_eq!(
+            (12 << 11 | 34 << 5 | 28, (2018 - 1980) << 9 | 6 << 5 | 15),
+            dos_time(Some(t))
+        );
+    }
+}
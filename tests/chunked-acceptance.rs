@@ -6,34 +6,31 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-extern crate env_logger;
-extern crate futures;
-extern crate http;
-extern crate http_serve;
-extern crate hyper;
-#[macro_use]
-extern crate lazy_static;
-extern crate reqwest;
-extern crate tokio;
-
-use futures::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
-use futures::{Future, Stream};
+use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use futures::StreamExt;
+use http_serve::{Body, BoxError};
+use hyper::body::Incoming;
+use hyper_util::rt::TokioIo;
+use lazy_static::lazy_static;
+use reqwest::header;
 use std::collections::HashMap;
+use std::convert::Infallible;
 use std::io::{self, Read, Write};
 use std::sync::Mutex;
 
-fn serve(req: http::Request<hyper::Body>) -> http::Response<hyper::Body> {
-    let cmds = CMDS.lock().unwrap().remove(req.uri().path()).unwrap();
+fn serve(req: http::Request<Incoming>) -> http::Response<Body> {
+    let mut cmds = CMDS.lock().unwrap().remove(req.uri().path()).unwrap();
     let (resp, w) = http_serve::streaming_body(&req).build();
     let mut w = w.unwrap();
-    tokio::spawn(cmds.for_each(move |cmd| {
-        match cmd {
-            Cmd::WriteAll(s) => w.write_all(s).unwrap(),
-            Cmd::Abort(e) => w.abort(e),
-            Cmd::Flush => w.flush().unwrap(),
+    tokio::spawn(async move {
+        while let Some(cmd) = cmds.next().await {
+            match cmd {
+                Cmd::WriteAll(s) => w.write_all(s).unwrap(),
+                Cmd::Abort(e) => w.abort(e),
+                Cmd::Flush => w.flush().unwrap(),
+            }
         }
-        futures::future::ok(())
-    }));
+    });
     resp
 }
 
@@ -41,7 +38,7 @@ fn serve(req: http::Request<hyper::Body>) -> http::Response<hyper::Body> {
 enum Cmd {
     WriteAll(&'static [u8]),
     Flush,
-    Abort(Box<::std::error::Error + Send + Sync>),
+    Abort(BoxError),
 }
 
 struct Server {
@@ -51,39 +48,55 @@ struct Server {
 fn new_server() -> Server {
     let (server_tx, server_rx) = ::std::sync::mpsc::channel();
     ::std::thread::spawn(move || {
-        let addr = "127.0.0.1:0".parse().unwrap();
-        let srv = hyper::server::Server::bind(&addr).serve(|| hyper::service::service_fn_ok(serve));
-        let addr = srv.local_addr();
-        server_tx
-            .send(Server {
-                addr: format!("http://{}:{}", addr.ip(), addr.port()),
-            })
-            .unwrap();
-        tokio::run(srv.map_err(|e| eprintln!("server error: {}", e)))
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            server_tx
+                .send(Server {
+                    addr: format!("http://{}:{}", addr.ip(), addr.port()),
+                })
+                .unwrap();
+            loop {
+                let (tcp, _) = listener.accept().await.unwrap();
+                let svc =
+                    hyper::service::service_fn(|req| async { Ok::<_, Infallible>(serve(req)) });
+                tokio::spawn(async move {
+                    let conn = hyper::server::conn::http1::Builder::new()
+                        .serve_connection(TokioIo::new(tcp), svc);
+                    if let Err(e) = conn.await {
+                        eprintln!("server error: {}", e);
+                    }
+                });
+            }
+        })
     });
     server_rx.recv().unwrap()
 }
 
 lazy_static! {
     static ref CMDS: Mutex<HashMap<&'static str, UnboundedReceiver<Cmd>>> =
-        { Mutex::new(HashMap::new()) };
-    static ref SERVER: Server = { new_server() };
+        Mutex::new(HashMap::new());
+    static ref SERVER: Server = new_server();
 }
 
 fn setup_req(
     path: &'static str,
     auto_gzip: bool,
-) -> (UnboundedSender<Cmd>, reqwest::RequestBuilder) {
+) -> (UnboundedSender<Cmd>, reqwest::blocking::RequestBuilder) {
     let (tx, rx) = mpsc::unbounded();
     CMDS.lock().unwrap().insert(path, rx);
-    let client = reqwest::Client::builder().gzip(auto_gzip).build().unwrap();
-    let req = client.get(&format!("{}{}", SERVER.addr, path));
+    let client = reqwest::blocking::Client::builder()
+        .gzip(auto_gzip)
+        .build()
+        .unwrap();
+    let req = client.get(format!("{}{}", SERVER.addr, path));
     (tx, req)
 }
 
 fn basic(path: &'static str, auto_gzip: bool) {
     let _ = env_logger::try_init();
-    let (cmds, mut req) = setup_req(path, auto_gzip);
+    let (cmds, req) = setup_req(path, auto_gzip);
     let mut resp = req.send().unwrap();
 
     cmds.unbounded_send(Cmd::WriteAll(b"1234")).unwrap();
@@ -92,10 +105,7 @@ fn basic(path: &'static str, auto_gzip: bool) {
     let mut buf = Vec::new();
     resp.read_to_end(&mut buf).unwrap();
     assert_eq!(b"12345678", &buf[..]);
-    assert_eq!(
-        None,
-        resp.headers().get::<reqwest::header::ContentEncoding>()
-    );
+    assert_eq!(None, resp.headers().get(header::CONTENT_ENCODING));
 }
 
 #[test]
@@ -110,7 +120,7 @@ fn auto_gzip() {
 
 fn abort(path: &'static str, auto_gzip: bool) {
     let _ = env_logger::try_init();
-    let (cmds, mut req) = setup_req(path, auto_gzip);
+    let (cmds, req) = setup_req(path, auto_gzip);
     let mut resp = req.send().unwrap();
 
     cmds.unbounded_send(Cmd::WriteAll(b"1234")).unwrap();
@@ -120,14 +130,28 @@ fn abort(path: &'static str, auto_gzip: bool) {
     resp.read_exact(&mut buf).unwrap();
     assert_eq!(b"1234", &buf);
 
-    cmds.unbounded_send(Cmd::Abort(Box::new(io::Error::new(
-        io::ErrorKind::Other,
-        "foo",
-    )))).unwrap();
-    assert_eq!(
-        io::ErrorKind::UnexpectedEof,
-        resp.read(&mut buf).unwrap_err().kind()
-    );
+    cmds.unbounded_send(Cmd::Abort(Box::new(io::Error::other("foo"))))
+        .unwrap();
+    let e = resp.read(&mut buf).unwrap_err();
+    assert!(is_unexpected_eof(&e), "{:?}", e);
+}
+
+/// Returns true iff `e` was caused by an `UnexpectedEof` error, which reqwest wraps.
+fn is_unexpected_eof(e: &io::Error) -> bool {
+    let mut cur: Option<&(dyn ::std::error::Error + 'static)> = Some(e);
+    while let Some(e) = cur {
+        if let Some(e) = e.downcast_ref::<io::Error>() {
+            if e.kind() == io::ErrorKind::UnexpectedEof {
+                return true;
+            }
+            if let Some(inner) = e.get_ref() {
+                cur = Some(inner);
+                continue;
+            }
+        }
+        cur = e.source();
+    }
+    false
 }
 
 #[test]
@@ -142,21 +166,14 @@ fn auto_gzip_abort() {
 
 #[test]
 fn manual_gzip() {
-    use reqwest::header;
     let _ = env_logger::try_init();
-    let (cmds, mut req) = setup_req("/manual_gzip", false);
-    let mut resp = req.header(header::AcceptEncoding(vec![
-        header::qitem(header::Encoding::Gzip),
-    ])).send()
-        .unwrap();
+    let (cmds, req) = setup_req("/manual_gzip", false);
+    let mut resp = req.header(header::ACCEPT_ENCODING, "gzip").send().unwrap();
 
     cmds.unbounded_send(Cmd::WriteAll(b"1234")).unwrap();
     drop(cmds);
     let mut buf = Vec::new();
     resp.read_to_end(&mut buf).unwrap();
     assert_eq!(b"\x1f\x8b", &buf[..2]); // gzip magic number.
-    assert_eq!(
-        Some(&header::ContentEncoding(vec![header::Encoding::Gzip])),
-        resp.headers().get()
-    );
+    assert_eq!(resp.headers().get(header::CONTENT_ENCODING).unwrap(), "gzip");
 }